@@ -0,0 +1,123 @@
+//! Throughput of the local cache, without any networking.
+
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use disca::{DiskCache, FileNotifier};
+use tokio::io::AsyncReadExt;
+
+const SIZES: [usize; 3] = [4 * 1024, 256 * 1024, 4 * 1024 * 1024];
+
+/// Keeps announcements out of the measurements.
+struct NoNotifier;
+
+#[async_trait]
+impl FileNotifier for NoNotifier {
+    async fn added(&self, _: String) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn removed(&self, _: String) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+fn cache_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("disca-bench-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    root
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("building the runtime")
+}
+
+fn insert(c: &mut Criterion) {
+    let rt = runtime();
+    let root = cache_root("insert");
+    let cache = DiskCache::new(&root, 10, u64::MAX, NoNotifier).unwrap();
+    let mut group = c.benchmark_group("insert");
+    for size in SIZES {
+        let content = vec![7; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &content, |b, content| {
+            b.to_async(&rt).iter_custom(|iters| {
+                let cache = &cache;
+                async move {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let start = Instant::now();
+                        cache.insert("key", content).await.unwrap();
+                        elapsed += start.elapsed();
+                        cache.remove("key").await.unwrap();
+                    }
+                    elapsed
+                }
+            });
+        });
+    }
+    group.finish();
+    let _ = std::fs::remove_dir_all(root);
+}
+
+fn get(c: &mut Criterion) {
+    let rt = runtime();
+    let root = cache_root("get");
+    let cache = DiskCache::new(&root, 10, u64::MAX, NoNotifier).unwrap();
+    let mut group = c.benchmark_group("get");
+    for size in SIZES {
+        let key = size.to_string();
+        rt.block_on(cache.insert(&key, &vec![7; size])).unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &key, |b, key| {
+            b.to_async(&rt).iter(|| async {
+                let mut content = Vec::with_capacity(size);
+                let mut file = cache.get(key).await.unwrap().unwrap();
+                file.read_to_end(&mut content).await.unwrap();
+                content
+            });
+        });
+    }
+    group.finish();
+    let _ = std::fs::remove_dir_all(root);
+}
+
+/// Inserts into a full cache of 100 files, so that every `files_to_evict`
+/// inserts pay for an eviction. The time per insert includes its share.
+fn evict(c: &mut Criterion) {
+    const FILE_SIZE: usize = 4 * 1024;
+    let rt = runtime();
+    let content = vec![7; FILE_SIZE];
+    let mut group = c.benchmark_group("insert_into_full_cache");
+    for files_to_evict in [2, 10, 50] {
+        let root = cache_root("evict");
+        let cache =
+            DiskCache::new(&root, files_to_evict, 100 * FILE_SIZE as u64, NoNotifier).unwrap();
+        let next_key = AtomicU64::new(0);
+        rt.block_on(async {
+            for _ in 0..100 {
+                let key = next_key.fetch_add(1, Ordering::Relaxed).to_string();
+                cache.insert(key, &content).await.unwrap();
+            }
+        });
+        group.bench_function(BenchmarkId::from_parameter(files_to_evict), |b| {
+            b.to_async(&rt).iter(|| async {
+                let key = next_key.fetch_add(1, Ordering::Relaxed).to_string();
+                cache.insert(key, &content).await.unwrap();
+            });
+        });
+        let _ = std::fs::remove_dir_all(root);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert, get, evict);
+criterion_main!(benches);
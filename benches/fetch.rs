@@ -0,0 +1,72 @@
+//! End-to-end latency of fetching a file from another node.
+//!
+//! Nodes only speak QUIC, so both run in this process and talk over the
+//! loopback interface. Each measured fetch goes through the whole path: the
+//! provider lookup in the DHT, the request to the provider and caching the
+//! response.
+
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use disca::{testing::Dataset, Disca};
+use tokio::{io::AsyncReadExt, sync::Mutex};
+
+const FILES: usize = 20;
+
+async fn node(name: &str) -> Disca {
+    let root = std::env::temp_dir().join(format!("disca-bench-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    Disca::builder(root)
+        .listen_addr("/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap())
+        .build()
+        .await
+        .unwrap()
+}
+
+fn fetch(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("building the runtime");
+    let dataset = Dataset::generate(FILES, 0);
+    let (provider, fetcher) = rt.block_on(async {
+        let provider = node("provider").await;
+        dataset.warm_up(&provider).await.unwrap();
+        let mut fetcher = node("fetcher").await;
+        fetcher.add_peer(provider.addr().clone()).await.unwrap();
+        fetcher
+            .wait_ready(1, Duration::from_secs(30))
+            .await
+            .unwrap();
+        (provider, Mutex::new(fetcher))
+    });
+
+    let mut group = c.benchmark_group("fetch");
+    group.sample_size(20);
+    for (key, content) in dataset.files().iter().step_by(FILES / 4) {
+        group.throughput(Throughput::Bytes(content.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(content.len()), key, |b, key| {
+            b.to_async(&rt).iter_custom(|iters| {
+                let fetcher = &fetcher;
+                async move {
+                    let mut fetcher = fetcher.lock().await;
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let start = Instant::now();
+                        let mut file = fetcher.get(key).await.unwrap().expect("file not fetched");
+                        let mut content = Vec::new();
+                        file.read_to_end(&mut content).await.unwrap();
+                        elapsed += start.elapsed();
+                        fetcher.delete(key).await.unwrap();
+                    }
+                    elapsed
+                }
+            });
+        });
+    }
+    group.finish();
+    drop(provider);
+}
+
+criterion_group!(benches, fetch);
+criterion_main!(benches);
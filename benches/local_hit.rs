@@ -0,0 +1,58 @@
+//! Latency of [`disca::Disca::get`] for keys cached locally, the common case
+//! on a warm cache. The node has no peers, and the files are small, so that
+//! the time is that of finding and opening the file rather than reading it.
+
+use std::{path::PathBuf, time::Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use disca::Disca;
+
+const FILES: usize = 1000;
+const FILE_SIZE: usize = 1024;
+
+fn cache_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("disca-bench-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    root
+}
+
+fn local_hit(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("building the runtime");
+    let root = cache_root("local-hit");
+    let keys = (0..FILES)
+        .map(|i| format!("file-{}", i))
+        .collect::<Vec<_>>();
+    // `get` takes the node mutably, which the benchmark routine cannot hold.
+    let node = tokio::sync::Mutex::new(rt.block_on(async {
+        let node = Disca::builder(&root)
+            .listen_addr("/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap())
+            .build()
+            .await
+            .unwrap();
+        for key in &keys {
+            node.add(key, &[7; FILE_SIZE]).await.unwrap();
+        }
+        node
+    }));
+
+    c.bench_function("local_hit", |b| {
+        b.to_async(&rt).iter_custom(|iters| {
+            let (node, keys) = (&node, &keys);
+            async move {
+                let mut node = node.lock().await;
+                let start = Instant::now();
+                for key in keys.iter().cycle().take(iters as usize) {
+                    node.get(key).await.unwrap().unwrap();
+                }
+                start.elapsed()
+            }
+        });
+    });
+    let _ = std::fs::remove_dir_all(root);
+}
+
+criterion_group!(benches, local_hit);
+criterion_main!(benches);
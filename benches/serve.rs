@@ -0,0 +1,100 @@
+//! Throughput of a node serving many requests at once from a slow disk, with
+//! files read on the event loop or off it, see
+//! [`disca::NetworkConfig::offload`].
+//!
+//! The provider answers from a script after blocking for a while, as reads
+//! from a slow or busy disk would. The fetcher asks it for every file at
+//! once, directly, so that no DHT lookup adds to the measurements.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use disca::{
+    testing::{ScriptedFileProvider, ScriptedResponse},
+    FileSharingP2P, MemoryFileProvider, NetworkConfig,
+};
+use futures::future::try_join_all;
+
+const FILES: usize = 32;
+const FILE_SIZE: usize = 64 * 1024;
+const READ_DELAY: Duration = Duration::from_millis(5);
+
+fn config(offload: bool) -> NetworkConfig {
+    NetworkConfig {
+        listen_addrs: vec!["/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap()],
+        offload,
+        ..Default::default()
+    }
+}
+
+fn serve(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("building the runtime");
+    let keys = (0..FILES)
+        .map(|i| format!("file-{}", i))
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("serve");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes((FILES * FILE_SIZE) as u64));
+    for offload in [false, true] {
+        let (provider, fetcher) = rt.block_on(async {
+            let files = ScriptedFileProvider::new();
+            for key in &keys {
+                files.respond(
+                    key.clone(),
+                    ScriptedResponse::Delayed(READ_DELAY, vec![0; FILE_SIZE]),
+                );
+            }
+            let provider = FileSharingP2P::with_config(config(offload), files)
+                .await
+                .unwrap();
+            let mut fetcher =
+                FileSharingP2P::with_config(config(offload), MemoryFileProvider::new())
+                    .await
+                    .unwrap();
+            fetcher.add_peer(provider.addr().clone()).await.unwrap();
+            fetcher
+                .wait_ready(1, Duration::from_secs(30))
+                .await
+                .unwrap();
+            (provider, fetcher)
+        });
+        let id = if offload {
+            "offloaded"
+        } else {
+            "on_event_loop"
+        };
+        group.bench_with_input(BenchmarkId::from_parameter(id), &keys, |b, keys| {
+            b.to_async(&rt).iter_custom(|iters| {
+                let (provider, fetcher) = (&provider, &fetcher);
+                async move {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let start = Instant::now();
+                        try_join_all(keys.iter().map(|key| {
+                            let mut fetcher = fetcher.clone();
+                            async move {
+                                let outcome = fetcher
+                                    .fetch_file_from(*provider.peer_id(), key.clone())
+                                    .await?;
+                                outcome.into_option().context("file not fetched")
+                            }
+                        }))
+                        .await
+                        .unwrap();
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serve);
+criterion_main!(benches);
@@ -0,0 +1,23 @@
+use std::sync::Arc;
+
+#[tokio::main]
+async fn main() {
+    // usage: cargo run --example control --features control-socket root_dir port socket_path
+    // then e.g.: echo '{"op": "stats"}' | nc -U socket_path
+    let args = std::env::args().collect::<Vec<_>>();
+    let root_dir = args.get(1).expect("root_dir not specified").to_string();
+    let port = args.get(2).expect("port not specified").parse().unwrap();
+    let socket_path = args.get(3).expect("socket_path not specified");
+    let addr = disca::listen_addr::quic_ipv6(port);
+    let disca = disca::Disca::builder(root_dir)
+        .listen_addr(addr)
+        .build()
+        .await
+        .unwrap();
+
+    println!("addr: {}", disca.addr());
+    println!("peer_id: {}", disca.peer_id());
+
+    let disca = Arc::new(tokio::sync::Mutex::new(disca));
+    disca::control::serve(disca, socket_path).await.unwrap();
+}
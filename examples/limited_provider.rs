@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use disca::{
+    AccessLog, FileSharingP2P, LimitedFileProvider, MemoryFileProvider, NetworkConfig, ServedFile,
+};
+
+#[tokio::main]
+async fn main() {
+    // usage: cargo run --example limited_provider
+    // serves a small and a large file through a provider refusing files over
+    // 1 KiB, fetches both from a second node and prints the access log
+    let config = || NetworkConfig {
+        listen_addrs: vec!["/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let files = MemoryFileProvider::new();
+    files.insert("small", b"hello".to_vec());
+    files.insert("large", vec![0; 64 * 1024]);
+    let provider = LimitedFileProvider::new(files)
+        .with_max_size(Some(1024))
+        .with_access_log(Some(AccessLog::new(|served: &ServedFile| {
+            let verdict = if served.refused { "refused" } else { "served" };
+            println!(
+                "{} {} ({} bytes) to {:?}",
+                verdict, served.path, served.size, served.peer
+            );
+        })));
+    let server = FileSharingP2P::with_config(config(), provider)
+        .await
+        .unwrap();
+
+    let mut client = FileSharingP2P::with_config(config(), MemoryFileProvider::new())
+        .await
+        .unwrap();
+    client.add_peer(server.addr().clone()).await.unwrap();
+    client.wait_ready(1, Duration::from_secs(30)).await.unwrap();
+
+    for key in ["small", "large"] {
+        let outcome = client
+            .fetch_file_from(*server.peer_id(), key)
+            .await
+            .unwrap();
+        match outcome.into_option() {
+            Some(content) => println!("fetched {} ({} bytes)", key, content.len()),
+            None => println!("{} not fetched", key),
+        }
+    }
+}
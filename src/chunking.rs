@@ -0,0 +1,222 @@
+//! Content-defined chunking, so that fetching a file sharing most of its
+//! content with files already cached only transfers the rest.
+//!
+//! Files are cut where a rolling hash of the bytes just read hits a pattern,
+//! so boundaries move along with bytes inserted or removed instead of every
+//! later chunk shifting, as fixed-size chunks would. The manifest of a file
+//! lists the hash and length of each of its chunks. A node fetching a file
+//! asks the provider for its manifest first, reads the chunks it already has
+//! from its own cached files and asks for the others only, as ranges of the
+//! file. The file put back together is then checked like one sent whole.
+//!
+//! Chunks are not stored apart from their files: the index points into the
+//! cached files, so chunks shared by several files are stored once per file,
+//! unless [`crate::CacheConfig::dedup`] links identical files together.
+
+use std::{ops::Range, sync::Arc};
+
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{ChunkingConfig, HashAlgorithm};
+
+/// Files are asked for their manifest under their key with this prefix. Keys
+/// cannot contain a `/`, nor namespaces start with a `.`, so it never names
+/// a file.
+const MANIFEST_PREFIX: &str = ".manifest/";
+
+/// Bytes a node asks for at once when fetching missing chunks, so that
+/// responses stay well below the largest one accepted.
+pub(crate) const MAX_CHUNK_REQUEST: u64 = 4 * 1024 * 1024;
+
+/// Multipliers of the rolling hash, one per byte value, scattered by
+/// SplitMix64 from a fixed seed, as every node has to cut files alike.
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x6469_7363_6163_6463;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// The path a manifest of `key` is asked for under.
+pub(crate) fn manifest_path(key: &str) -> String {
+    format!("{}{}", MANIFEST_PREFIX, key)
+}
+
+/// The key whose manifest `path` asks for, if it asks for one.
+pub(crate) fn manifest_key(path: &str) -> Option<&str> {
+    path.strip_prefix(MANIFEST_PREFIX)
+}
+
+/// Where the chunks of `content` end, the last one at the end of it. Chunks
+/// are between the minimum and maximum size, apart from the last one, which
+/// may be shorter.
+pub(crate) fn boundaries(content: &[u8], config: &ChunkingConfig) -> Vec<Range<usize>> {
+    // Cut when as many of the hash's top bits are clear as it takes for that
+    // to happen every `avg_size` bytes on average.
+    let bits = config.avg_size.max(2).next_power_of_two().trailing_zeros();
+    let mask = !(u64::MAX >> bits);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < content.len() {
+        let rest = &content[start..];
+        let max = rest.len().min(config.max_size);
+        let mut end = max;
+        let mut hash = 0u64;
+        for (i, byte) in rest[..max].iter().enumerate().skip(config.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & mask == 0 {
+                end = i + 1;
+                break;
+            }
+        }
+        chunks.push(start..start + end);
+        start += end;
+    }
+    chunks
+}
+
+/// One chunk of a file, as listed in its [`Manifest`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ChunkRef {
+    pub(crate) hash: Vec<u8>,
+    pub(crate) len: u64,
+}
+
+/// The chunks a file is made of, in order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// Fetchers hash the chunks with whatever algorithm made the manifest.
+    pub(crate) algorithm: HashAlgorithm,
+    pub(crate) chunks: Vec<ChunkRef>,
+}
+
+impl Manifest {
+    pub(crate) fn new(content: &[u8], config: &ChunkingConfig, algorithm: HashAlgorithm) -> Self {
+        let chunks = boundaries(content, config)
+            .into_iter()
+            .map(|range| ChunkRef {
+                len: range.len() as u64,
+                hash: algorithm.hash(&content[range]),
+            })
+            .collect();
+        Self { algorithm, chunks }
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        cbor4ii::serde::to_vec(Vec::new(), self).expect("manifests always encode")
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> Result<Self> {
+        cbor4ii::serde::from_slice(buf).context("decoding manifest")
+    }
+
+    /// Where each chunk starts in the file.
+    pub(crate) fn offsets(&self) -> Vec<u64> {
+        self.chunks
+            .iter()
+            .scan(0, |offset, chunk| {
+                let start = *offset;
+                *offset += chunk.len;
+                Some(start)
+            })
+            .collect()
+    }
+}
+
+/// Where a chunk can be read from locally.
+#[derive(Debug, Clone)]
+struct ChunkLocation {
+    key: String,
+    range: Range<u64>,
+}
+
+/// The manifests of the cached files and where each of their chunks is,
+/// shared by the node and the provider serving its files.
+#[derive(Debug, Clone)]
+pub(crate) struct ChunkIndex {
+    config: ChunkingConfig,
+    algorithm: HashAlgorithm,
+    /// Encoded, as that is how they are served.
+    manifests: Arc<DashMap<String, (Manifest, Vec<u8>)>>,
+    chunks: Arc<DashMap<Vec<u8>, ChunkLocation>>,
+}
+
+impl ChunkIndex {
+    pub(crate) fn new(config: ChunkingConfig, algorithm: HashAlgorithm) -> Self {
+        Self {
+            config,
+            algorithm,
+            manifests: Default::default(),
+            chunks: Default::default(),
+        }
+    }
+
+    /// Records the chunks of `content`, cached as `key`. Files of a single
+    /// chunk are left out, as fetching them in chunks gains nothing.
+    pub(crate) fn index(&self, key: &str, content: &[u8]) {
+        self.forget(key);
+        let manifest = Manifest::new(content, &self.config, self.algorithm);
+        if manifest.chunks.len() < 2 {
+            return;
+        }
+        for (chunk, offset) in manifest.chunks.iter().zip(manifest.offsets()) {
+            self.chunks.insert(
+                chunk.hash.clone(),
+                ChunkLocation {
+                    key: key.to_owned(),
+                    range: offset..offset + chunk.len,
+                },
+            );
+        }
+        let encoded = manifest.encode();
+        self.manifests.insert(key.to_owned(), (manifest, encoded));
+    }
+
+    /// Forgets the chunks of `key`, e.g. once it is evicted. Chunks it
+    /// shares with other files stay known if another file was indexed last.
+    pub(crate) fn forget(&self, key: &str) {
+        let Some((_, (manifest, _))) = self.manifests.remove(key) else {
+            return;
+        };
+        for chunk in &manifest.chunks {
+            self.chunks
+                .remove_if(&chunk.hash, |_, location| location.key == key);
+        }
+    }
+
+    /// Forgets the chunks of every key matching `removed`.
+    pub(crate) fn forget_where(&self, removed: impl Fn(&str) -> bool) {
+        let keys = self
+            .manifests
+            .iter()
+            .filter(|entry| removed(entry.key()))
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.forget(&key);
+        }
+    }
+
+    /// The encoded manifest of `key`, if it has more than one chunk.
+    pub(crate) fn manifest(&self, key: &str) -> Option<Vec<u8>> {
+        Some(self.manifests.get(key)?.1.clone())
+    }
+
+    /// The key and byte range of a cached file holding the chunk with
+    /// `hash`. The file may have changed since, so the bytes read need
+    /// checking against the hash.
+    pub(crate) fn location(&self, hash: &[u8]) -> Option<(String, Range<u64>)> {
+        let location = self.chunks.get(hash)?;
+        Some((location.key.clone(), location.range.clone()))
+    }
+}
@@ -0,0 +1,346 @@
+use std::io;
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{request_response, StreamProtocol};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    file_sharing::{FileRequest, FileResponse},
+    CompressionAlgorithm, ContentSignature,
+};
+
+/// Largest request accepted, in bytes.
+const REQUEST_SIZE_MAXIMUM: u64 = 1024 * 1024;
+/// Largest response accepted, in bytes.
+const RESPONSE_SIZE_MAXIMUM: u64 = 10 * 1024 * 1024;
+/// Longest range a node asks for at once when a file is too large for one
+/// response. Its content takes at most four times as many bytes encoded,
+/// in JSON, so a response with it always fits.
+pub(crate) const RANGE_SIZE_MAXIMUM: u64 = 2 * 1024 * 1024;
+/// Takes the place of the encoding in raw responses carrying a manifest,
+/// which is never compressed.
+const MANIFEST_BYTE: u8 = 0x80;
+/// Takes the place of the encoding in raw responses saying that the file is
+/// too large to be sent.
+const TOO_LARGE_BYTE: u8 = 0x81;
+/// Takes the place of the encoding in raw responses saying that the request
+/// was turned down for its token.
+const DENIED_BYTE: u8 = 0x82;
+/// Starts raw requests carrying a token, which comes first. Other requests
+/// start with the length of the path, whose first byte is always 0 as paths
+/// are far shorter than requests can be.
+const TOKEN_BYTE: u8 = 0xff;
+
+/// How file requests and responses are encoded on the wire. Each format is a
+/// protocol of its own, so nodes only exchange files with peers using the
+/// same one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum WireFormat {
+    /// Compact, and what every node spoke before the format was configurable.
+    #[default]
+    Cbor,
+    /// Readable in packet captures and easy to produce from other tooling,
+    /// at the cost of a much bigger encoding of file content.
+    Json,
+    /// Length-prefixed fields with the content copied as is, for the least
+    /// overhead on big files.
+    Raw,
+}
+
+impl WireFormat {
+    pub(crate) fn protocol(self) -> StreamProtocol {
+        match self {
+            WireFormat::Cbor => StreamProtocol::new("/file-exchange/1"),
+            WireFormat::Json => StreamProtocol::new("/file-exchange/1/json"),
+            WireFormat::Raw => StreamProtocol::new("/file-exchange/1/raw"),
+        }
+    }
+}
+
+/// Encodes [`FileRequest`]s and [`FileResponse`]s in a [`WireFormat`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileCodec {
+    format: WireFormat,
+}
+
+impl FileCodec {
+    pub(crate) fn new(format: WireFormat) -> Self {
+        Self { format }
+    }
+
+    fn encode_request(&self, request: &FileRequest) -> io::Result<Vec<u8>> {
+        match self.format {
+            WireFormat::Cbor => to_cbor(request),
+            WireFormat::Json => serde_json::to_vec(request).map_err(io::Error::from),
+            WireFormat::Raw => {
+                // The range and the encodings are left out entirely when there
+                // are none, so that plain requests for whole files are
+                // unchanged. A range takes 16 bytes and the encodings a count
+                // and a byte each, always less, which tells them apart. A
+                // token goes before everything else, after a marker.
+                let mut buf = Vec::new();
+                if let Some(token) = &request.auth_token {
+                    buf.push(TOKEN_BYTE);
+                    put_bytes(&mut buf, token.as_bytes());
+                }
+                put_bytes(&mut buf, request.path.as_bytes());
+                if let Some(range) = &request.range {
+                    buf.extend_from_slice(&range.start.to_be_bytes());
+                    buf.extend_from_slice(&range.end.to_be_bytes());
+                }
+                if !request.accept_encodings.is_empty() {
+                    buf.push(request.accept_encodings.len() as u8);
+                    buf.extend(request.accept_encodings.iter().map(|e| encoding_byte(*e)));
+                }
+                Ok(buf)
+            }
+        }
+    }
+
+    fn decode_request(&self, buf: &[u8]) -> io::Result<FileRequest> {
+        match self.format {
+            WireFormat::Cbor => from_cbor(buf),
+            WireFormat::Json => serde_json::from_slice(buf).map_err(io::Error::from),
+            WireFormat::Raw => {
+                let mut buf = buf;
+                let auth_token = match buf.split_first() {
+                    Some((&TOKEN_BYTE, rest)) => {
+                        buf = rest;
+                        Some(
+                            String::from_utf8(take_bytes(&mut buf)?.to_vec())
+                                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                        )
+                    }
+                    _ => None,
+                };
+                let path = String::from_utf8(take_bytes(&mut buf)?.to_vec())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let range = if buf.len() >= 16 {
+                    Some(take_u64(&mut buf)?..take_u64(&mut buf)?)
+                } else {
+                    None
+                };
+                let mut accept_encodings = Vec::new();
+                if !buf.is_empty() {
+                    let (&count, rest) = buf.split_first().ok_or_else(truncated)?;
+                    if rest.len() < count as usize {
+                        return Err(truncated());
+                    }
+                    // Encodings this node does not know are of no use to it.
+                    accept_encodings = rest[..count as usize]
+                        .iter()
+                        .filter_map(|byte| byte_encoding(*byte))
+                        .collect();
+                }
+                Ok(FileRequest {
+                    path,
+                    range,
+                    accept_encodings,
+                    auth_token,
+                })
+            }
+        }
+    }
+
+    fn encode_response(&self, response: &FileResponse) -> io::Result<Vec<u8>> {
+        match self.format {
+            WireFormat::Cbor => to_cbor(response),
+            WireFormat::Json => serde_json::to_vec(response).map_err(io::Error::from),
+            WireFormat::Raw => {
+                // Content and signature, each as a presence byte and, when
+                // present, the length-prefixed bytes, then the encoding of
+                // the content if it has one, or a marker if it is a
+                // manifest, the file is too large or the request was denied.
+                let mut buf = Vec::new();
+                match &response.content {
+                    Some(content) => {
+                        buf.push(1);
+                        put_bytes(&mut buf, content);
+                    }
+                    None => buf.push(0),
+                }
+                match &response.signature {
+                    Some(signature) => {
+                        buf.push(1);
+                        put_bytes(&mut buf, &to_cbor(signature)?);
+                    }
+                    None => buf.push(0),
+                }
+                if response.manifest {
+                    buf.push(MANIFEST_BYTE);
+                } else if response.too_large {
+                    buf.push(TOO_LARGE_BYTE);
+                } else if response.denied {
+                    buf.push(DENIED_BYTE);
+                } else if let Some(encoding) = response.encoding {
+                    buf.push(encoding_byte(encoding));
+                }
+                Ok(buf)
+            }
+        }
+    }
+
+    fn decode_response(&self, buf: &[u8]) -> io::Result<FileResponse> {
+        match self.format {
+            WireFormat::Cbor => from_cbor(buf),
+            WireFormat::Json => serde_json::from_slice(buf).map_err(io::Error::from),
+            WireFormat::Raw => {
+                let mut buf = buf;
+                let content = if take_flag(&mut buf)? {
+                    Some(take_bytes(&mut buf)?.to_vec())
+                } else {
+                    None
+                };
+                let signature = if take_flag(&mut buf)? {
+                    Some(from_cbor::<ContentSignature>(take_bytes(&mut buf)?)?)
+                } else {
+                    None
+                };
+                let manifest = buf.first() == Some(&MANIFEST_BYTE);
+                let too_large = buf.first() == Some(&TOO_LARGE_BYTE);
+                let denied = buf.first() == Some(&DENIED_BYTE);
+                let encoding = match buf.first() {
+                    Some(_) if manifest || too_large || denied => None,
+                    Some(&byte) => Some(byte_encoding(byte).ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "unknown encoding")
+                    })?),
+                    None => None,
+                };
+                Ok(FileResponse {
+                    content,
+                    signature,
+                    encoding,
+                    manifest,
+                    too_large,
+                    denied,
+                })
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl request_response::Codec for FileCodec {
+    type Protocol = StreamProtocol;
+    type Request = FileRequest;
+    type Response = FileResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<FileRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.take(REQUEST_SIZE_MAXIMUM).read_to_end(&mut buf).await?;
+        self.decode_request(&buf)
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<FileResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.take(RESPONSE_SIZE_MAXIMUM).read_to_end(&mut buf).await?;
+        self.decode_response(&buf)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: FileRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&self.encode_request(&request)?).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: FileResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let mut buf = self.encode_response(&response)?;
+        if buf.len() as u64 > RESPONSE_SIZE_MAXIMUM {
+            // The requester would only get a truncated message, so tell it
+            // why instead, keeping the signature of the file for when it
+            // fetches the file in ranges.
+            buf = self.encode_response(&FileResponse {
+                content: None,
+                signature: response.signature,
+                encoding: None,
+                manifest: false,
+                too_large: true,
+                denied: false,
+            })?;
+        }
+        io.write_all(&buf).await
+    }
+}
+
+fn to_cbor<T: serde::Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    cbor4ii::serde::to_vec(Vec::new(), value).map_err(io::Error::other)
+}
+
+fn from_cbor<T: serde::de::DeserializeOwned>(buf: &[u8]) -> io::Result<T> {
+    cbor4ii::serde::from_slice(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn take_flag(buf: &mut &[u8]) -> io::Result<bool> {
+    let (&flag, rest) = buf.split_first().ok_or_else(truncated)?;
+    *buf = rest;
+    Ok(flag != 0)
+}
+
+fn take_u64(buf: &mut &[u8]) -> io::Result<u64> {
+    if buf.len() < 8 {
+        return Err(truncated());
+    }
+    let (value, rest) = buf.split_at(8);
+    *buf = rest;
+    Ok(u64::from_be_bytes(
+        value.try_into().expect("split at 8 bytes"),
+    ))
+}
+
+fn take_bytes<'a>(buf: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let len = take_u64(buf)?;
+    if (buf.len() as u64) < len {
+        return Err(truncated());
+    }
+    let (bytes, rest) = buf.split_at(len as usize);
+    *buf = rest;
+    Ok(bytes)
+}
+
+fn encoding_byte(encoding: CompressionAlgorithm) -> u8 {
+    match encoding {
+        CompressionAlgorithm::Zstd => 1,
+        CompressionAlgorithm::Gzip => 2,
+    }
+}
+
+fn byte_encoding(byte: u8) -> Option<CompressionAlgorithm> {
+    match byte {
+        1 => Some(CompressionAlgorithm::Zstd),
+        2 => Some(CompressionAlgorithm::Gzip),
+        _ => None,
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated message")
+}
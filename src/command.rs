@@ -0,0 +1,191 @@
+//! The calls a [`FileSharingP2P`] makes to its event loop, as plain data.
+//!
+//! Requests and replies carry no channels, so that they can cross a process
+//! boundary in whatever encoding and over whatever transport the application
+//! picks. A sidecar process owning the networking core hands every request it
+//! receives to [`FileSharingP2P::call`] and sends the reply back; its clients
+//! wrap their end of the transport in a [`CommandTransport`] and make calls
+//! through a [`CommandClient`], which has the methods of [`FileSharingP2P`]
+//! that go through the event loop.
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use libp2p::{Multiaddr, PeerId};
+
+use crate::{FileSharingP2P, GetOutcome, KadStats, SelfTestReport};
+
+/// A call to the event loop, see [`FileSharingP2P::call`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandRequest {
+    AddFile { path: String },
+    RemoveFile { path: String },
+    GetFile { path: String },
+    GetFileFrom { peer: PeerId, path: String },
+    Exists { path: String },
+    UnannounceAll,
+    AnnounceAll,
+    AddPeer { addr: Multiaddr },
+    StartListening { addr: Multiaddr },
+    StopListening { addr: Multiaddr },
+    KadStats,
+    KnownPeers,
+    SelfTest { peer: PeerId },
+}
+
+/// What the event loop answered to a [`CommandRequest`]. Failures are
+/// reported as the error of [`FileSharingP2P::call`] instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandReply {
+    /// The request had nothing to return.
+    Done,
+    File(GetOutcome<Vec<u8>>),
+    Exists(bool),
+    /// The address a [`CommandRequest::StartListening`] bound.
+    Listening(Multiaddr),
+    KadStats(KadStats),
+    /// The addresses of the peers a [`CommandRequest::KnownPeers`] asked for.
+    Peers(Vec<Multiaddr>),
+    SelfTest(SelfTestReport),
+}
+
+/// Carries [`CommandRequest`]s to an event loop, possibly in another process,
+/// and their replies back.
+#[async_trait]
+pub trait CommandTransport: Send + Sync {
+    async fn call(&self, request: CommandRequest) -> Result<CommandReply>;
+}
+
+#[async_trait]
+impl CommandTransport for FileSharingP2P {
+    async fn call(&self, request: CommandRequest) -> Result<CommandReply> {
+        FileSharingP2P::call(self, request).await
+    }
+}
+
+/// Typed calls over a [`CommandTransport`], mirroring those of
+/// [`FileSharingP2P`].
+#[derive(Debug, Clone)]
+pub struct CommandClient<T> {
+    transport: T,
+}
+
+impl<T: CommandTransport> CommandClient<T> {
+    pub fn new(transport: T) -> Self {
+        Self { transport }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub async fn add_file(&self, path: impl Into<String>) -> Result<()> {
+        let path = path.into();
+        self.call_done(CommandRequest::AddFile { path }).await
+    }
+
+    pub async fn remove_file(&self, path: impl Into<String>) -> Result<()> {
+        let path = path.into();
+        self.call_done(CommandRequest::RemoveFile { path }).await
+    }
+
+    pub async fn fetch_file(&self, path: impl Into<String>) -> Result<GetOutcome<Vec<u8>>> {
+        let path = path.into();
+        match self
+            .transport
+            .call(CommandRequest::GetFile { path })
+            .await?
+        {
+            CommandReply::File(outcome) => Ok(outcome),
+            reply => unexpected(reply),
+        }
+    }
+
+    pub async fn fetch_file_from(
+        &self,
+        peer: PeerId,
+        path: impl Into<String>,
+    ) -> Result<GetOutcome<Vec<u8>>> {
+        let path = path.into();
+        match self
+            .transport
+            .call(CommandRequest::GetFileFrom { peer, path })
+            .await?
+        {
+            CommandReply::File(outcome) => Ok(outcome),
+            reply => unexpected(reply),
+        }
+    }
+
+    pub async fn exists(&self, path: impl Into<String>) -> Result<bool> {
+        let path = path.into();
+        match self.transport.call(CommandRequest::Exists { path }).await? {
+            CommandReply::Exists(exists) => Ok(exists),
+            reply => unexpected(reply),
+        }
+    }
+
+    pub async fn unannounce_all(&self) -> Result<()> {
+        self.call_done(CommandRequest::UnannounceAll).await
+    }
+
+    pub async fn announce_all(&self) -> Result<()> {
+        self.call_done(CommandRequest::AnnounceAll).await
+    }
+
+    pub async fn add_peer(&self, addr: Multiaddr) -> Result<()> {
+        self.call_done(CommandRequest::AddPeer { addr }).await
+    }
+
+    pub async fn listen_on(&self, addr: Multiaddr) -> Result<Multiaddr> {
+        match self
+            .transport
+            .call(CommandRequest::StartListening { addr })
+            .await?
+        {
+            CommandReply::Listening(addr) => Ok(addr),
+            reply => unexpected(reply),
+        }
+    }
+
+    pub async fn stop_listening(&self, addr: Multiaddr) -> Result<()> {
+        self.call_done(CommandRequest::StopListening { addr }).await
+    }
+
+    pub async fn kad_stats(&self) -> Result<KadStats> {
+        match self.transport.call(CommandRequest::KadStats).await? {
+            CommandReply::KadStats(stats) => Ok(stats),
+            reply => unexpected(reply),
+        }
+    }
+
+    pub async fn known_peers(&self) -> Result<Vec<Multiaddr>> {
+        match self.transport.call(CommandRequest::KnownPeers).await? {
+            CommandReply::Peers(addrs) => Ok(addrs),
+            reply => unexpected(reply),
+        }
+    }
+
+    pub async fn self_test(&self, peer: PeerId) -> Result<SelfTestReport> {
+        match self
+            .transport
+            .call(CommandRequest::SelfTest { peer })
+            .await?
+        {
+            CommandReply::SelfTest(report) => Ok(report),
+            reply => unexpected(reply),
+        }
+    }
+
+    async fn call_done(&self, request: CommandRequest) -> Result<()> {
+        match self.transport.call(request).await? {
+            CommandReply::Done => Ok(()),
+            reply => unexpected(reply),
+        }
+    }
+}
+
+/// A transport mixing up replies is a bug in the transport, but one worth an
+/// error rather than a panic.
+fn unexpected<R>(reply: CommandReply) -> Result<R> {
+    bail!("unexpected reply from the event loop: {:?}", reply)
+}
@@ -0,0 +1,101 @@
+//! Compression of cached files at rest.
+
+use std::io::{self, Write};
+
+use serde::{Deserialize, Serialize};
+
+/// How cached files are compressed on disk, and how peers may send them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    /// Fast, with a good ratio. The one to pick unless something else has to
+    /// read the files.
+    Zstd,
+    /// Slower, but readable by about any tool.
+    Gzip,
+}
+
+/// Compresses or decompresses a stream one chunk at a time.
+pub(crate) enum Transcoder {
+    ZstdEncoder(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    ZstdDecoder(zstd::stream::write::Decoder<'static, Vec<u8>>),
+    GzipEncoder(flate2::write::GzEncoder<Vec<u8>>),
+    GzipDecoder(flate2::write::GzDecoder<Vec<u8>>),
+}
+
+impl Transcoder {
+    pub(crate) fn compressor(algorithm: CompressionAlgorithm) -> io::Result<Self> {
+        Ok(match algorithm {
+            CompressionAlgorithm::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 0)?;
+                // So that corrupt files fail to decompress.
+                encoder.include_checksum(true)?;
+                Transcoder::ZstdEncoder(encoder)
+            }
+            CompressionAlgorithm::Gzip => Transcoder::GzipEncoder(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::default(),
+            )),
+        })
+    }
+
+    pub(crate) fn decompressor(algorithm: CompressionAlgorithm) -> io::Result<Self> {
+        Ok(match algorithm {
+            CompressionAlgorithm::Zstd => {
+                Transcoder::ZstdDecoder(zstd::stream::write::Decoder::new(Vec::new())?)
+            }
+            CompressionAlgorithm::Gzip => {
+                Transcoder::GzipDecoder(flate2::write::GzDecoder::new(Vec::new()))
+            }
+        })
+    }
+
+    /// Feeds `buf` in, returning whatever output is ready.
+    pub(crate) fn update(&mut self, buf: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Transcoder::ZstdEncoder(encoder) => {
+                encoder.write_all(buf)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            Transcoder::ZstdDecoder(decoder) => {
+                decoder.write_all(buf)?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Transcoder::GzipEncoder(encoder) => {
+                encoder.write_all(buf)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            Transcoder::GzipDecoder(decoder) => {
+                decoder.write_all(buf)?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+        }
+    }
+
+    /// Ends the stream, returning the rest of the output.
+    pub(crate) fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Transcoder::ZstdEncoder(encoder) => encoder.finish(),
+            Transcoder::ZstdDecoder(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+            Transcoder::GzipEncoder(encoder) => encoder.finish(),
+            Transcoder::GzipDecoder(decoder) => decoder.finish(),
+        }
+    }
+}
+
+pub(crate) fn compress(algorithm: CompressionAlgorithm, buf: &[u8]) -> io::Result<Vec<u8>> {
+    transcode(Transcoder::compressor(algorithm)?, buf)
+}
+
+pub(crate) fn decompress(algorithm: CompressionAlgorithm, buf: &[u8]) -> io::Result<Vec<u8>> {
+    transcode(Transcoder::decompressor(algorithm)?, buf)
+}
+
+fn transcode(mut transcoder: Transcoder, buf: &[u8]) -> io::Result<Vec<u8>> {
+    let mut output = transcoder.update(buf)?;
+    output.extend(transcoder.finish()?);
+    Ok(output)
+}
@@ -0,0 +1,1100 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use libp2p::{identity::Keypair, Multiaddr, PeerId};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chunking, listen_addr, AccessLog, CompressionAlgorithm, Disca, EvictionPolicy,
+    FetchInterceptor, HashAlgorithm, KeyMapper, LowestRtt, ProviderSelector, SanitizedKeys,
+    ServedFile, SharedCapacity, TokenValidator, WireFormat,
+};
+
+/// Everything needed to start a [`Disca`] node.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DiscaConfig {
+    /// Directory the cached files are stored in.
+    pub root: PathBuf,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub cache: CacheConfig,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub network: NetworkConfig,
+}
+
+impl DiscaConfig {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            cache: CacheConfig::default(),
+            network: NetworkConfig::default(),
+        }
+    }
+
+    /// Fails if `new` changes settings that a node running with this
+    /// configuration, as `peer_id`, cannot take on, see
+    /// [`Disca::reconfigure`].
+    pub(crate) fn check_reconfigure(&self, new: &DiscaConfig, peer_id: &PeerId) -> Result<()> {
+        let (cache, new_cache) = (&self.cache, &new.cache);
+        let (network, new_network) = (&self.network, &new.network);
+        let shared_limit =
+            |cache: &CacheConfig| cache.shared_capacity.as_ref().map(SharedCapacity::limit);
+        let changed = [
+            ("root", self.root != new.root),
+            ("dedup", cache.dedup != new_cache.dedup),
+            (
+                "verify_local_checksums",
+                cache.verify_local_checksums != new_cache.verify_local_checksums,
+            ),
+            (
+                "compress_at_rest",
+                cache.compress_at_rest != new_cache.compress_at_rest,
+            ),
+            (
+                "shared_capacity",
+                shared_limit(cache) != shared_limit(new_cache),
+            ),
+            (
+                "namespace_reservations",
+                cache.namespace_reservations != new_cache.namespace_reservations,
+            ),
+            (
+                "listen_addrs",
+                network.listen_addrs != new_network.listen_addrs,
+            ),
+            (
+                "bootstrap_peers",
+                network.bootstrap_peers != new_network.bootstrap_peers,
+            ),
+            ("seed_peers", network.seed_peers != new_network.seed_peers),
+            (
+                "keypair",
+                new_network
+                    .keypair
+                    .as_ref()
+                    .is_some_and(|keypair| keypair.public().to_peer_id() != *peer_id),
+            ),
+            (
+                "sign_published",
+                network.sign_published != new_network.sign_published,
+            ),
+            (
+                "hash_algorithm",
+                network.hash_algorithm != new_network.hash_algorithm,
+            ),
+            (
+                "index_by_hash",
+                network.index_by_hash != new_network.index_by_hash,
+            ),
+            (
+                "parent_peers",
+                network.parent_peers != new_network.parent_peers,
+            ),
+            ("identify", network.identify != new_network.identify),
+            (
+                "connection_limits",
+                network.connection_limits != new_network.connection_limits,
+            ),
+            ("quic", network.quic != new_network.quic),
+            ("kad_mode", network.kad_mode != new_network.kad_mode),
+            ("read_only", network.read_only != new_network.read_only),
+            ("mdns", network.mdns != new_network.mdns),
+            (
+                "kad_parallelism",
+                network.kad_parallelism != new_network.kad_parallelism,
+            ),
+            (
+                "wire_format",
+                network.wire_format != new_network.wire_format,
+            ),
+            (
+                "local_fallback",
+                network.local_fallback != new_network.local_fallback,
+            ),
+            ("chunking", network.chunking != new_network.chunking),
+            (
+                "partial_dir",
+                network.partial_dir != new_network.partial_dir,
+            ),
+            (
+                "max_served_size",
+                network.max_served_size != new_network.max_served_size,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+        if !changed.is_empty() {
+            bail!(
+                "{} cannot change while the node runs, only on restart",
+                changed.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Settings of the local disk cache.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct CacheConfig {
+    /// Maximum number of bytes kept on disk.
+    pub capacity: u64,
+    /// How many files are evicted at once when the cache is full. More go,
+    /// as many at a time, until a new file fits.
+    pub files_to_evict: u64,
+    /// Fsync every cached file before reporting it as added. Off by default:
+    /// files then only reach the disk when the OS flushes them or on
+    /// [`Disca::flush`], and may be lost on power failure.
+    pub durable: bool,
+    /// How long a cached file is considered fresh. A stale file is refetched
+    /// from peers before being returned, and kept if nobody has it. Unset
+    /// means files never go stale.
+    #[cfg_attr(feature = "serde", serde(with = "option_duration_secs"))]
+    pub ttl: Option<Duration>,
+    /// Return stale files right away and refetch them in the background. The
+    /// refreshed content replaces the stale copy on a later get.
+    pub stale_while_revalidate: bool,
+    pub eviction_policy: EvictionPolicy,
+    /// Store content cached under several keys once, hardlinking the files
+    /// of the other keys to it. Costs hashing every inserted file.
+    pub dedup: bool,
+    /// Keep a checksum of every cached file and check it on every read. A
+    /// corrupted file is then dropped and fetched again instead of returned.
+    pub verify_local_checksums: bool,
+    /// Compress cached files on disk, trading CPU time on every read and
+    /// write for room. Files are decompressed before being returned or sent
+    /// to peers. Unset means files are stored as is.
+    pub compress_at_rest: Option<CompressionAlgorithm>,
+    /// A limit on the bytes this cache holds together with others, e.g. the
+    /// other namespaces of a deployment on the same disk. See
+    /// [`SharedCapacity`] for how room is made between them.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub shared_capacity: Option<SharedCapacity>,
+    /// Bytes of the shared capacity set aside for namespaces, by name, so
+    /// that a busy namespace cannot leave a critical one without room: the
+    /// others cannot take those bytes even while the namespace holds less,
+    /// see [`crate::DiskCache::with_reserved_capacity`]. Set aside once the
+    /// namespace is created with [`Disca::add_namespace`]. Requires a shared
+    /// capacity, which the reservations together cannot exceed, and no
+    /// reservation can exceed the capacity of its namespace.
+    pub namespace_reservations: HashMap<String, u64>,
+    /// Bytes of memory the files added with [`Disca::add_ephemeral`] may
+    /// take. The least recently served are dropped to make room for new
+    /// ones. 64 MiB by default.
+    pub ephemeral_capacity: u64,
+    /// How keys become the paths of their files under the root, for the
+    /// node's cache and those of its namespaces. [`SanitizedKeys`] by
+    /// default; see [`crate::HashedKeys`] and [`crate::ShardedKeys`] for
+    /// the others. Files cached with another mapper cannot be found, so it
+    /// has to stay the same for as long as the files are kept.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub key_mapper: Arc<dyn KeyMapper>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024 * 1024 * 1024,
+            files_to_evict: 10,
+            durable: false,
+            ttl: None,
+            stale_while_revalidate: false,
+            eviction_policy: EvictionPolicy::default(),
+            dedup: false,
+            verify_local_checksums: false,
+            compress_at_rest: None,
+            shared_capacity: None,
+            namespace_reservations: HashMap::new(),
+            ephemeral_capacity: 64 * 1024 * 1024,
+            key_mapper: Arc::new(SanitizedKeys),
+        }
+    }
+}
+
+impl CacheConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.namespace_reservations.is_empty() {
+            return Ok(());
+        }
+        let Some(shared_capacity) = &self.shared_capacity else {
+            bail!("namespace reservations require a shared capacity");
+        };
+        let reserved = self
+            .namespace_reservations
+            .values()
+            .fold(0u64, |reserved, bytes| reserved.saturating_add(*bytes));
+        if reserved > shared_capacity.limit() {
+            bail!(
+                "namespace reservations add up to {} bytes, more than the shared capacity of {} bytes",
+                reserved,
+                shared_capacity.limit()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Settings of the peer-to-peer layer.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct NetworkConfig {
+    /// Addresses to listen on. Each one must bind for start-up to succeed,
+    /// unless [`NetworkConfig::local_fallback`] is set.
+    #[cfg_attr(feature = "serde", serde(with = "as_string_vec"))]
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Peers dialed on start-up.
+    #[cfg_attr(feature = "serde", serde(with = "as_string_vec"))]
+    pub bootstrap_peers: Vec<Multiaddr>,
+    /// Peers put in the routing table on start-up, without dialing them, so
+    /// that the first lookups already have somewhere to go, e.g. those of
+    /// [`crate::Disca::known_peers`] saved by a previous run. Each address
+    /// must end with `/p2p/<peer id>`. Peers gone in the meantime are dropped
+    /// from the table once queries fail to reach them.
+    #[cfg_attr(feature = "serde", serde(with = "as_string_vec"))]
+    pub seed_peers: Vec<Multiaddr>,
+    /// How long to wait for a peer to answer a file request.
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs"))]
+    pub request_timeout: Duration,
+    /// How long providers found for a key are reused before querying the DHT
+    /// again. Each entry lives up to 10% longer, at random, so that nodes
+    /// looking up the same key do not all refresh it at once. Zero disables
+    /// the cache.
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs"))]
+    pub provider_cache_ttl: Duration,
+    /// Identity of the node. A new one is generated when unset, which is fine
+    /// unless other nodes need to recognise this one, e.g. as a trusted
+    /// publisher.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub keypair: Option<Keypair>,
+    /// Sign files added to this node so that fetchers can verify who
+    /// published them. See [`crate::ContentSignature`].
+    pub sign_published: bool,
+    /// Digest used when signing published files. Fetchers verify with
+    /// whatever algorithm the signature names, so nodes do not have to agree.
+    pub hash_algorithm: HashAlgorithm,
+    /// Also announce every cached file under the hash of its content, so
+    /// that peers can fetch it with [`Disca::get_by_hash`] without knowing
+    /// its key. Files added with [`Disca::add_from_reader`] are not indexed.
+    pub index_by_hash: bool,
+    /// When not empty, fetched files are only accepted if they are signed by
+    /// one of these publishers.
+    #[cfg_attr(feature = "serde", serde(with = "as_string_vec"))]
+    pub trusted_publishers: Vec<PeerId>,
+    /// Nodes of a parent cache, asked after the providers found on our own
+    /// network came up empty. Each address must end with `/p2p/<peer id>`.
+    /// Parents are contacted directly and never added to our routing table.
+    #[cfg_attr(feature = "serde", serde(with = "as_string_vec"))]
+    pub parent_peers: Vec<Multiaddr>,
+    pub identify: IdentifyConfig,
+    pub connection_limits: ConnectionLimitsConfig,
+    pub quic: QuicConfig,
+    pub kad_mode: KadMode,
+    /// Discover peers on the local network through mDNS and add them to the
+    /// routing table, so that a LAN cluster needs no bootstrap peers. Only
+    /// meant for local networks: it needs IPv4 multicast on UDP port 5353,
+    /// which most WANs, cloud networks and containers without host
+    /// networking do not carry.
+    pub mdns: bool,
+    /// Peers a Kademlia query, such as the provider lookup of a get, asks at
+    /// once. Wider queries find providers on large networks sooner, at the
+    /// cost of more requests per lookup; narrower ones go easy on bandwidth
+    /// but take more round trips. At least 1.
+    pub kad_parallelism: usize,
+    /// Order in which the providers of a key are tried, closest first by
+    /// default.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub provider_selector: Arc<dyn ProviderSelector>,
+    /// Encoding of file requests and responses. Peers only exchange files
+    /// when they use the same one.
+    pub wire_format: WireFormat,
+    /// Compressions this node accepts files in, which saves bandwidth when
+    /// providers store them that way. Received files are decompressed, so
+    /// this does not depend on how this node stores them. Empty by default:
+    /// files then always come uncompressed.
+    pub accept_encodings: Vec<CompressionAlgorithm>,
+    /// Upper bound on the network fetches a bulk operation such as
+    /// [`Disca::prefetch`] keeps in flight, so that warming the cache does
+    /// not starve regular gets or flood providers.
+    pub max_concurrent_fetches: usize,
+    /// Upper bound on the dials in flight, whether to bootstrap peers or
+    /// added later; further dials wait for one of them to connect or fail,
+    /// so that a long peer list does not overwhelm the network stack.
+    pub max_concurrent_dials: usize,
+    /// Called with every file fetched from a peer, after its signature or
+    /// hash was verified and before it is handed over to be cached, to check
+    /// or rewrite it. An error rejects the content, which then counts as a
+    /// miss from that provider and the next one is asked; the provider's
+    /// score is not affected. Rewritten content is served to other peers
+    /// without the publisher's signature, which no longer matches it.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub on_fetch: Option<FetchInterceptor>,
+    /// Keeps the node working as a local cache when the network fails,
+    /// instead of failing. Startup then goes on when no listen address can
+    /// be bound or a bootstrap peer cannot be dialed, network fetches that
+    /// fail count as misses, and failing to announce a file does not fail
+    /// adding it. Meanwhile the node keeps trying to listen and to reach its
+    /// bootstrap peers, and announces its files again once it has a peer.
+    /// See [`crate::Disca::network_status`].
+    pub local_fallback: bool,
+    /// Fetch files in content-defined chunks, so that only the chunks not
+    /// found in files cached already cross the network. Files are split into
+    /// chunks as they are cached, at the cost of hashing them, and providers
+    /// send the list of chunks of a file first. Files added from a reader
+    /// are always sent whole. Off by default; peers without it, or files too
+    /// small to be split, are fetched whole, the former after one more round
+    /// trip.
+    pub chunking: Option<ChunkingConfig>,
+    /// Fetch files too large to fit in one response in ranges instead, from
+    /// the provider that answered so. The whole file is then checked as if
+    /// it came at once. A provider that stops part way is taken over by the
+    /// next one from where it stopped, as long as the file is signed or
+    /// under a content key so that the whole can be checked; otherwise the
+    /// next one starts over. Chunks always carry over. On by default; off,
+    /// such a file is a failed fetch from that provider, with an error
+    /// saying why, and the next one is asked.
+    pub fetch_in_ranges: bool,
+    /// Read the files served to peers, and decompress and verify those
+    /// fetched from them, on the blocking threads of the runtime, so that a
+    /// large file or a slow disk does not hold up the event loop and every
+    /// request and lookup queued behind it. Each request is served by a clone
+    /// of the file provider, so several are read at once. On by default;
+    /// off, all of it runs on the event loop, one request at a time.
+    pub offload: bool,
+    /// Resolve a fetch in chunks or ranges that every provider gave up on
+    /// part way with what came, as [`crate::GetOutcome::Partial`], instead
+    /// of failing it, for callers that can make do, e.g. to show a preview.
+    /// Chunks and checkable ranges carry over from one provider to the next;
+    /// otherwise the most any of them sent is kept. Partial content is never
+    /// cached, so [`crate::Disca::get`] still misses and only
+    /// [`crate::Disca::get_outcome`] hands it over. Off by default.
+    pub allow_partial: bool,
+    /// Directory files fetched in chunks are written to as the chunks come,
+    /// see [`NetworkConfig::chunking`]. A fetch broken off part way, even one
+    /// whose caller gave up, picks up the chunks in there on the next try of
+    /// the file, from whichever provider, and asks for the others only. What
+    /// came of a file stays there until the file is fetched whole. Unset by
+    /// default: [`crate::Disca`] then uses `.partial` under its root, and
+    /// [`crate::FileSharingP2P`] a directory of its own under the temporary
+    /// directory of the system.
+    pub partial_dir: Option<PathBuf>,
+    /// Ask this many providers of a file at once and go on with whichever
+    /// answers first, ignoring the others, trading bandwidth for a lower
+    /// latency to the first byte. Only the first request of a fetch is sent
+    /// to several providers; the rest, e.g. for chunks or ranges, go to the
+    /// one that answered. Providers that had nothing are waited out while
+    /// others are still to answer, and the providers ignored are asked in
+    /// turn if the file from the first one does not pass its checks.
+    /// Providers are taken in the order of
+    /// [`NetworkConfig::provider_selector`]. The fetch starts with the first
+    /// providers found while the lookup goes on, and providers found next
+    /// are asked right away as long as the first answer is still to come
+    /// and fewer than this many requests are out. 1 by default, asking one
+    /// provider at a time and ending the lookup at the first providers; 0
+    /// counts as 1.
+    pub hedge_count: usize,
+    /// How announcing a file is retried when the DHT did not take it, e.g.
+    /// because too few peers stored the record while the network was still
+    /// settling.
+    pub provide_retry: ProvideRetry,
+    /// Announce files only while AutoNAT does not find the node private, so
+    /// that peers never look it up as a provider they cannot dial. Once it
+    /// is found private, the node stops announcing its files and adding one
+    /// fails with [`crate::DiscaError::Unreachable`]; files it caches
+    /// otherwise, e.g. fetched ones, are held back. Everything held back is
+    /// announced once it is found reachable again. Announcements stored by
+    /// peers before are not withdrawn, and expire. Whether the node is
+    /// reachable is unknown until enough peers tried to dial it, and files
+    /// are announced meanwhile. Off by default.
+    pub require_reachable_to_announce: bool,
+    /// Only fetch from the network, never contributing to it, e.g. for
+    /// untrusted edge nodes: files are still cached locally but never
+    /// announced, every file request from a peer is answered as if the node
+    /// had nothing, and Kademlia runs in client mode whatever
+    /// [`NetworkConfig::kad_mode`] says, so that peers never look the node
+    /// up as a provider. Off by default.
+    pub read_only: bool,
+    /// Files larger than this many bytes are not served to peers, who are
+    /// answered that the node does not have them. Unlimited if unset. See
+    /// [`crate::LimitedFileProvider`], which the node serves its files
+    /// through, for how it applies.
+    pub max_served_size: Option<u64>,
+    /// Called with every file a peer asked for, whether it was served or
+    /// refused for its size, e.g. to audit who fetched what.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub on_served: Option<AccessLog>,
+    /// Sent along with every file request, for peers that only serve those
+    /// carrying a token they accept, see [`NetworkConfig::token_validator`],
+    /// e.g. a secret shared by the nodes of a cluster. Sent as is: QUIC
+    /// encrypts it on the way, but every peer asked sees it, so it only
+    /// keeps out peers that never got it. Unset by default.
+    pub auth_token: Option<String>,
+    /// Serves only the file requests it accepts, from the peer asking and the
+    /// token sent along, see [`NetworkConfig::auth_token`]. Others are told
+    /// that they were denied, and nothing of the file. A middle ground
+    /// between serving everyone and allowing only known peers; it does not
+    /// encrypt or authenticate anything itself, which QUIC does for the
+    /// connection. Called for every request, off the event loop unless
+    /// [`NetworkConfig::offload`] is off, so it should be quick. Unset by
+    /// default, serving everyone.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub token_validator: Option<TokenValidator>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            listen_addrs: vec![listen_addr::quic_ipv6(0)],
+            bootstrap_peers: Vec::new(),
+            request_timeout: Duration::from_secs(10),
+            provider_cache_ttl: Duration::from_secs(60),
+            keypair: None,
+            sign_published: false,
+            hash_algorithm: HashAlgorithm::default(),
+            index_by_hash: false,
+            trusted_publishers: Vec::new(),
+            parent_peers: Vec::new(),
+            seed_peers: Vec::new(),
+            identify: IdentifyConfig::default(),
+            connection_limits: ConnectionLimitsConfig::default(),
+            quic: QuicConfig::default(),
+            kad_mode: KadMode::default(),
+            mdns: false,
+            kad_parallelism: 3,
+            provider_selector: Arc::new(LowestRtt),
+            wire_format: WireFormat::default(),
+            accept_encodings: Vec::new(),
+            max_concurrent_fetches: 16,
+            max_concurrent_dials: 8,
+            on_fetch: None,
+            local_fallback: false,
+            chunking: None,
+            fetch_in_ranges: true,
+            offload: true,
+            allow_partial: false,
+            partial_dir: None,
+            hedge_count: 1,
+            provide_retry: ProvideRetry::default(),
+            require_reachable_to_announce: false,
+            read_only: false,
+            max_served_size: None,
+            on_served: None,
+            auth_token: None,
+            token_validator: None,
+        }
+    }
+}
+
+/// What the node tells its peers about itself through the identify protocol.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct IdentifyConfig {
+    /// Version of the disca protocol family spoken by the node.
+    pub protocol_version: String,
+    /// Name and version of the node software, e.g. to tell builds apart when
+    /// looking at a fleet.
+    pub agent_version: String,
+    /// How often connected peers are identified again.
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs"))]
+    pub interval: Duration,
+    /// Push changes of our listen addresses to connected peers right away
+    /// instead of waiting for the next periodic identify.
+    pub push_listen_addr_updates: bool,
+    /// How many discovered peers to remember.
+    pub cache_size: usize,
+}
+
+impl Default for IdentifyConfig {
+    fn default() -> Self {
+        Self {
+            protocol_version: "disca/v1".to_string(),
+            agent_version: format!("disca/{}", env!("CARGO_PKG_VERSION")),
+            interval: Duration::from_secs(5 * 60),
+            push_listen_addr_updates: false,
+            cache_size: 100,
+        }
+    }
+}
+
+/// Whether a node answers DHT queries and stores records for others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum KadMode {
+    /// Always act as a DHT server.
+    Server,
+    /// Only query the DHT, e.g. for cache consumers that come and go.
+    Client,
+    /// Act as a server unless AutoNAT finds that peers cannot dial us.
+    #[default]
+    Auto,
+}
+
+/// Bounds on the connections a node keeps, so that a node exposed to the open
+/// network cannot be made to hold an unbounded number of them. Connections
+/// beyond a limit are refused; `None` lifts a limit.
+///
+/// By default a node accepts up to 256 established connections in each
+/// direction and up to 128 connections still being negotiated in each
+/// direction.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ConnectionLimitsConfig {
+    pub max_established_incoming: Option<u32>,
+    pub max_established_outgoing: Option<u32>,
+    pub max_pending_incoming: Option<u32>,
+    pub max_pending_outgoing: Option<u32>,
+}
+
+impl Default for ConnectionLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_established_incoming: Some(256),
+            max_established_outgoing: Some(256),
+            max_pending_incoming: Some(128),
+            max_pending_outgoing: Some(128),
+        }
+    }
+}
+
+/// Sizes of the chunks files are split into, see
+/// [`NetworkConfig::chunking`]. The chunks of a file depend on these, so
+/// nodes only find the chunks they have in common when they agree on them.
+/// Smaller chunks find more in common, at the cost of longer manifests and
+/// more requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ChunkingConfig {
+    /// No chunk is cut shorter, apart from the last of a file. 16 KiB by
+    /// default.
+    pub min_size: usize,
+    /// Chunks are this long on average, rounded to a power of two. 64 KiB by
+    /// default.
+    pub avg_size: usize,
+    /// No chunk is longer, at most 4 MiB. 256 KiB by default.
+    pub max_size: usize,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 16 * 1024,
+            avg_size: 64 * 1024,
+            max_size: 256 * 1024,
+        }
+    }
+}
+
+impl ChunkingConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if !(self.min_size <= self.avg_size && self.avg_size <= self.max_size) {
+            bail!(
+                "chunk sizes must satisfy min_size <= avg_size <= max_size, got {}, {} and {}",
+                self.min_size,
+                self.avg_size,
+                self.max_size
+            );
+        }
+        if self.max_size == 0 || self.max_size as u64 > chunking::MAX_CHUNK_REQUEST {
+            bail!(
+                "max_size of chunks must be between 1 and {} bytes, got {}",
+                chunking::MAX_CHUNK_REQUEST,
+                self.max_size
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Parameters of the QUIC transport. The defaults are those of libp2p, made
+/// for low-latency links: on a WAN, a longer idle timeout keeps quiet
+/// connections from being dropped, and bigger flow control windows let fast
+/// links with a high latency carry big files at full speed.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct QuicConfig {
+    /// How long the handshake of a new connection may take, at most the idle
+    /// timeout. QUIC secures and multiplexes a connection as part of its
+    /// handshake, so this bounds the whole upgrade of the connection, apart
+    /// from request and idle timeouts. 5 seconds by default; on links with a
+    /// high latency or loss, too short a timeout shows as dials to distant
+    /// peers failing now and then.
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs"))]
+    pub handshake_timeout: Duration,
+    /// How long a connection may go without hearing from the peer before it
+    /// is closed. Counted in milliseconds, up to about 49 days. 30 seconds by
+    /// default.
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs"))]
+    pub max_idle_timeout: Duration,
+    /// How long a connection may be quiet before a keep-alive is sent, which
+    /// only helps if shorter than the idle timeout of both peers. 15 seconds
+    /// by default.
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs"))]
+    pub keep_alive_interval: Duration,
+    /// How many streams the peer may have open on a connection at once, i.e.
+    /// requests in flight. 256 by default.
+    pub max_concurrent_stream_limit: u32,
+    /// Bytes that may be in flight unacknowledged on one stream. 10 MB by
+    /// default.
+    pub max_stream_data: u32,
+    /// Bytes that may be in flight unacknowledged on all streams of a
+    /// connection. 15 MB by default.
+    pub max_connection_data: u32,
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: Duration::from_secs(5),
+            max_idle_timeout: Duration::from_secs(30),
+            keep_alive_interval: Duration::from_secs(15),
+            max_concurrent_stream_limit: 256,
+            max_stream_data: 10_000_000,
+            max_connection_data: 15_000_000,
+        }
+    }
+}
+
+impl QuicConfig {
+    pub(crate) fn apply(&self, mut config: libp2p::quic::Config) -> libp2p::quic::Config {
+        config.handshake_timeout = self.handshake_timeout;
+        config.max_idle_timeout =
+            u32::try_from(self.max_idle_timeout.as_millis()).unwrap_or(u32::MAX);
+        config.keep_alive_interval = self.keep_alive_interval;
+        config.max_concurrent_stream_limit = self.max_concurrent_stream_limit;
+        config.max_stream_data = self.max_stream_data;
+        config.max_connection_data = self.max_connection_data;
+        config
+    }
+}
+
+/// How announcing a file to the DHT is retried after it failed, e.g. because
+/// fewer peers than the replication factor stored the record, which is common
+/// while the network is small or still settling. Each retry waits twice as
+/// long as the one before, up to `max_backoff`, and adding the file fails
+/// with the error of the last attempt.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ProvideRetry {
+    /// Attempts made at most, the first one included. 3 by default; 0 counts
+    /// as 1, never retrying.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry. 1 second by default.
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs"))]
+    pub initial_backoff: Duration,
+    /// How long to wait between retries at most. 30 seconds by default.
+    #[cfg_attr(feature = "serde", serde(with = "duration_secs"))]
+    pub max_backoff: Duration,
+}
+
+impl Default for ProvideRetry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ProvideRetry {
+    /// How long to wait before the attempt after `attempt`, counted from 1.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(1 << attempt.saturating_sub(1).min(31))
+            .min(self.max_backoff)
+    }
+}
+
+/// Builds a [`Disca`] node step by step, starting from the defaults of
+/// [`DiscaConfig`].
+#[derive(Debug, Clone)]
+pub struct DiscaBuilder {
+    config: DiscaConfig,
+}
+
+impl DiscaBuilder {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            config: DiscaConfig::new(root),
+        }
+    }
+
+    pub fn from_config(config: DiscaConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn capacity(mut self, capacity: u64) -> Self {
+        self.config.cache.capacity = capacity;
+        self
+    }
+
+    pub fn files_to_evict(mut self, files_to_evict: u64) -> Self {
+        self.config.cache.files_to_evict = files_to_evict;
+        self
+    }
+
+    pub fn durable(mut self, durable: bool) -> Self {
+        self.config.cache.durable = durable;
+        self
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.config.cache.ttl = Some(ttl);
+        self
+    }
+
+    pub fn stale_while_revalidate(mut self, stale_while_revalidate: bool) -> Self {
+        self.config.cache.stale_while_revalidate = stale_while_revalidate;
+        self
+    }
+
+    pub fn eviction_policy(mut self, eviction_policy: EvictionPolicy) -> Self {
+        self.config.cache.eviction_policy = eviction_policy;
+        self
+    }
+
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.config.cache.dedup = dedup;
+        self
+    }
+
+    pub fn verify_local_checksums(mut self, verify_local_checksums: bool) -> Self {
+        self.config.cache.verify_local_checksums = verify_local_checksums;
+        self
+    }
+
+    pub fn compress_at_rest(mut self, algorithm: CompressionAlgorithm) -> Self {
+        self.config.cache.compress_at_rest = Some(algorithm);
+        self
+    }
+
+    pub fn ephemeral_capacity(mut self, ephemeral_capacity: u64) -> Self {
+        self.config.cache.ephemeral_capacity = ephemeral_capacity;
+        self
+    }
+
+    /// See [`CacheConfig::key_mapper`].
+    pub fn key_mapper<M: KeyMapper + 'static>(mut self, key_mapper: M) -> Self {
+        self.config.cache.key_mapper = Arc::new(key_mapper);
+        self
+    }
+
+    pub fn shared_capacity(mut self, shared_capacity: SharedCapacity) -> Self {
+        self.config.cache.shared_capacity = Some(shared_capacity);
+        self
+    }
+
+    /// Sets `bytes` of the shared capacity aside for the namespace `name`,
+    /// see [`CacheConfig::namespace_reservations`].
+    pub fn namespace_reservation(mut self, name: impl Into<String>, bytes: u64) -> Self {
+        self.config
+            .cache
+            .namespace_reservations
+            .insert(name.into(), bytes);
+        self
+    }
+
+    /// Replaces the listen addresses with `addr`.
+    pub fn listen_addr(mut self, addr: Multiaddr) -> Self {
+        self.config.network.listen_addrs = vec![addr];
+        self
+    }
+
+    /// Adds another address to listen on.
+    pub fn add_listen_addr(mut self, addr: Multiaddr) -> Self {
+        self.config.network.listen_addrs.push(addr);
+        self
+    }
+
+    pub fn bootstrap_peer(mut self, addr: Multiaddr) -> Self {
+        self.config.network.bootstrap_peers.push(addr);
+        self
+    }
+
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.config.network.request_timeout = timeout;
+        self
+    }
+
+    pub fn provider_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.config.network.provider_cache_ttl = ttl;
+        self
+    }
+
+    pub fn keypair(mut self, keypair: Keypair) -> Self {
+        self.config.network.keypair = Some(keypair);
+        self
+    }
+
+    pub fn sign_published(mut self, sign_published: bool) -> Self {
+        self.config.network.sign_published = sign_published;
+        self
+    }
+
+    pub fn hash_algorithm(mut self, hash_algorithm: HashAlgorithm) -> Self {
+        self.config.network.hash_algorithm = hash_algorithm;
+        self
+    }
+
+    pub fn index_by_hash(mut self, index_by_hash: bool) -> Self {
+        self.config.network.index_by_hash = index_by_hash;
+        self
+    }
+
+    pub fn trusted_publisher(mut self, publisher: PeerId) -> Self {
+        self.config.network.trusted_publishers.push(publisher);
+        self
+    }
+
+    pub fn parent_peer(mut self, addr: Multiaddr) -> Self {
+        self.config.network.parent_peers.push(addr);
+        self
+    }
+
+    /// See [`NetworkConfig::seed_peers`].
+    pub fn seed_peers(mut self, addrs: impl IntoIterator<Item = Multiaddr>) -> Self {
+        self.config.network.seed_peers.extend(addrs);
+        self
+    }
+
+    pub fn identify(mut self, identify: IdentifyConfig) -> Self {
+        self.config.network.identify = identify;
+        self
+    }
+
+    pub fn kad_mode(mut self, kad_mode: KadMode) -> Self {
+        self.config.network.kad_mode = kad_mode;
+        self
+    }
+
+    pub fn provider_selector<S: ProviderSelector + 'static>(mut self, selector: S) -> Self {
+        self.config.network.provider_selector = Arc::new(selector);
+        self
+    }
+
+    pub fn connection_limits(mut self, connection_limits: ConnectionLimitsConfig) -> Self {
+        self.config.network.connection_limits = connection_limits;
+        self
+    }
+
+    pub fn quic(mut self, quic: QuicConfig) -> Self {
+        self.config.network.quic = quic;
+        self
+    }
+
+    /// Sets how long the upgrade of a new connection may take, see
+    /// [`QuicConfig::handshake_timeout`].
+    pub fn upgrade_timeout(mut self, upgrade_timeout: Duration) -> Self {
+        self.config.network.quic.handshake_timeout = upgrade_timeout;
+        self
+    }
+
+    pub fn wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.config.network.wire_format = wire_format;
+        self
+    }
+
+    pub fn accept_encodings(mut self, accept_encodings: Vec<CompressionAlgorithm>) -> Self {
+        self.config.network.accept_encodings = accept_encodings;
+        self
+    }
+
+    pub fn max_concurrent_fetches(mut self, max_concurrent_fetches: usize) -> Self {
+        self.config.network.max_concurrent_fetches = max_concurrent_fetches;
+        self
+    }
+
+    pub fn mdns(mut self, mdns: bool) -> Self {
+        self.config.network.mdns = mdns;
+        self
+    }
+
+    pub fn kad_parallelism(mut self, kad_parallelism: usize) -> Self {
+        self.config.network.kad_parallelism = kad_parallelism;
+        self
+    }
+
+    /// See [`NetworkConfig::max_served_size`].
+    pub fn max_served_size(mut self, max_served_size: u64) -> Self {
+        self.config.network.max_served_size = Some(max_served_size);
+        self
+    }
+
+    /// See [`NetworkConfig::on_served`].
+    pub fn on_served(mut self, on_served: impl Fn(&ServedFile) + Send + Sync + 'static) -> Self {
+        self.config.network.on_served = Some(AccessLog::new(on_served));
+        self
+    }
+
+    /// See [`NetworkConfig::auth_token`].
+    pub fn auth_token(mut self, auth_token: impl Into<String>) -> Self {
+        self.config.network.auth_token = Some(auth_token.into());
+        self
+    }
+
+    /// See [`NetworkConfig::token_validator`].
+    pub fn token_validator(
+        mut self,
+        token_validator: impl Fn(&PeerId, Option<&str>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.config.network.token_validator = Some(TokenValidator::new(token_validator));
+        self
+    }
+
+    /// See [`NetworkConfig::on_fetch`].
+    pub fn on_fetch(
+        mut self,
+        on_fetch: impl Fn(&str, Vec<u8>) -> Result<Vec<u8>> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.network.on_fetch = Some(FetchInterceptor::new(on_fetch));
+        self
+    }
+
+    pub fn max_concurrent_dials(mut self, max_concurrent_dials: usize) -> Self {
+        self.config.network.max_concurrent_dials = max_concurrent_dials;
+        self
+    }
+
+    /// See [`NetworkConfig::chunking`].
+    pub fn chunking(mut self, chunking: ChunkingConfig) -> Self {
+        self.config.network.chunking = Some(chunking);
+        self
+    }
+
+    pub fn local_fallback(mut self, local_fallback: bool) -> Self {
+        self.config.network.local_fallback = local_fallback;
+        self
+    }
+
+    pub fn fetch_in_ranges(mut self, fetch_in_ranges: bool) -> Self {
+        self.config.network.fetch_in_ranges = fetch_in_ranges;
+        self
+    }
+
+    /// See [`NetworkConfig::offload`].
+    pub fn offload(mut self, offload: bool) -> Self {
+        self.config.network.offload = offload;
+        self
+    }
+
+    /// See [`NetworkConfig::allow_partial`].
+    pub fn allow_partial(mut self, allow_partial: bool) -> Self {
+        self.config.network.allow_partial = allow_partial;
+        self
+    }
+
+    /// See [`NetworkConfig::partial_dir`].
+    pub fn partial_dir<P: Into<PathBuf>>(mut self, partial_dir: P) -> Self {
+        self.config.network.partial_dir = Some(partial_dir.into());
+        self
+    }
+
+    /// See [`NetworkConfig::hedge_count`].
+    pub fn hedge_count(mut self, hedge_count: usize) -> Self {
+        self.config.network.hedge_count = hedge_count;
+        self
+    }
+
+    /// See [`NetworkConfig::provide_retry`].
+    pub fn provide_retry(mut self, provide_retry: ProvideRetry) -> Self {
+        self.config.network.provide_retry = provide_retry;
+        self
+    }
+
+    /// See [`NetworkConfig::require_reachable_to_announce`].
+    pub fn require_reachable_to_announce(mut self, require_reachable_to_announce: bool) -> Self {
+        self.config.network.require_reachable_to_announce = require_reachable_to_announce;
+        self
+    }
+
+    /// See [`NetworkConfig::read_only`].
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.config.network.read_only = read_only;
+        self
+    }
+
+    pub fn config(&self) -> &DiscaConfig {
+        &self.config
+    }
+
+    pub async fn build(self) -> Result<Disca> {
+        Disca::with_config(self.config).await
+    }
+}
+
+/// (De)serializes a list of values through their string form, e.g.
+/// `/ip4/1.2.3.4/udp/4001/quic-v1` for a [`Multiaddr`].
+#[cfg(feature = "serde")]
+mod as_string_vec {
+    use std::{fmt::Display, str::FromStr};
+
+    use serde::{de::Error, ser::SerializeSeq, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T: Display, S: Serializer>(values: &[T], s: S) -> Result<S::Ok, S::Error> {
+        let mut seq = s.serialize_seq(Some(values.len()))?;
+        for value in values {
+            seq.serialize_element(&value.to_string())?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<Vec<T>, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        Vec::<String>::deserialize(d)?
+            .into_iter()
+            .map(|value| value.parse().map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// (De)serializes a [`Duration`] as a number of seconds.
+#[cfg(feature = "serde")]
+mod duration_secs {
+    use std::time::Duration;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        Duration::try_from_secs_f64(f64::deserialize(d)?).map_err(D::Error::custom)
+    }
+}
+
+/// (De)serializes an optional [`Duration`] as a number of seconds.
+#[cfg(feature = "serde")]
+mod option_duration_secs {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => super::duration_secs::serialize(duration, s),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        Option::<f64>::deserialize(d)?
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,175 @@
+//! A control interface for driving a running node from other processes,
+//! available with the `control-socket` feature.
+//!
+//! Clients connect to a Unix domain socket and send one JSON object per line,
+//! naming the operation in its `op` field:
+//!
+//! ```text
+//! {"op": "add", "key": "a.txt", "content": "hello"}
+//! {"op": "update", "key": "a.txt", "content": "hello again"}
+//! {"op": "get", "key": "a.txt"}
+//! {"op": "delete", "key": "a.txt"}
+//! {"op": "list", "prefix": "a"}
+//! {"op": "stats"}
+//! {"op": "peers"}
+//! {"op": "add_peer", "addr": "/ip4/10.0.0.2/udp/4001/quic-v1"}
+//! ```
+//!
+//! Every request is answered by one JSON line with `"ok": true` and the
+//! operation's results, or `"ok": false` and an `error` message. File content
+//! is sent as a string when it is valid UTF-8 and as an array of bytes
+//! (`bytes`) otherwise.
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::Mutex,
+};
+
+use crate::{listen_addr, Disca, LatencyHistogram};
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum ControlRequest {
+    Add {
+        key: String,
+        content: String,
+    },
+    Update {
+        key: String,
+        content: String,
+    },
+    Get {
+        key: String,
+    },
+    Delete {
+        key: String,
+    },
+    List {
+        #[serde(default)]
+        prefix: String,
+    },
+    Stats,
+    Peers,
+    AddPeer {
+        addr: String,
+    },
+}
+
+/// Accepts control connections on a Unix socket at `path` until accepting
+/// fails. Each connection is served on its own task; requests are applied to
+/// the node one at a time.
+pub async fn serve(disca: Arc<Mutex<Disca>>, path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("binding control socket {}", path.display()))?;
+    loop {
+        let (stream, _) = listener
+            .accept()
+            .await
+            .with_context(|| format!("accepting on control socket {}", path.display()))?;
+        let disca = disca.clone();
+        tokio::spawn(async move {
+            // A client going away mid-request is its own business.
+            let _ = serve_connection(disca, stream).await;
+        });
+    }
+}
+
+async fn serve_connection(disca: Arc<Mutex<Disca>>, stream: UnixStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => match handle(&disca, request).await {
+                Ok(Value::Object(mut fields)) => {
+                    fields.insert("ok".to_string(), Value::Bool(true));
+                    Value::Object(fields)
+                }
+                Ok(_) => json!({ "ok": true }),
+                Err(e) => json!({ "ok": false, "error": format!("{:#}", e) }),
+            },
+            Err(e) => json!({ "ok": false, "error": format!("invalid request: {}", e) }),
+        };
+        let mut response = serde_json::to_vec(&response)?;
+        response.push(b'\n');
+        writer.write_all(&response).await?;
+    }
+    Ok(())
+}
+
+async fn handle(disca: &Mutex<Disca>, request: ControlRequest) -> Result<Value> {
+    let mut disca = disca.lock().await;
+    Ok(match request {
+        ControlRequest::Add { key, content } => {
+            disca.add(&key, content.as_bytes()).await?;
+            json!({})
+        }
+        ControlRequest::Update { key, content } => {
+            disca.update(&key, content.as_bytes()).await?;
+            json!({})
+        }
+        ControlRequest::Get { key } => match disca.get(&key).await? {
+            Some(mut file) => {
+                let mut content = Vec::new();
+                file.read_to_end(&mut content).await?;
+                match String::from_utf8(content) {
+                    Ok(content) => json!({ "found": true, "content": content }),
+                    Err(e) => json!({ "found": true, "bytes": e.into_bytes() }),
+                }
+            }
+            None => json!({ "found": false }),
+        },
+        ControlRequest::Delete { key } => json!({ "deleted": disca.delete(&key).await? }),
+        ControlRequest::List { prefix } => json!({ "keys": disca.list_keys_with_prefix(&prefix) }),
+        ControlRequest::Stats => {
+            let latencies = disca.latencies();
+            json!({
+                "entries": disca.len(),
+                "size": disca.size(),
+                "disk_usage": disca.disk_usage().await?,
+                "capacity": disca.capacity(),
+                "latencies": {
+                    "local_get": latency_json(&latencies.local_get),
+                    "network_fetch": latency_json(&latencies.network_fetch),
+                    "insert": latency_json(&latencies.insert),
+                },
+            })
+        }
+        ControlRequest::Peers => {
+            let stats = disca.kad_stats().await?;
+            json!({
+                "peer_id": disca.peer_id().to_string(),
+                "listen_addrs": disca
+                    .listen_addrs()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>(),
+                "known_peers": stats.known_peers,
+            })
+        }
+        ControlRequest::AddPeer { addr } => {
+            disca.add_peer(listen_addr::parse(&addr)?).await?;
+            json!({})
+        }
+    })
+}
+
+/// Percentiles in microseconds.
+fn latency_json(histogram: &LatencyHistogram) -> Value {
+    json!({
+        "count": histogram.count(),
+        "p50_us": histogram.quantile(0.5).as_micros() as u64,
+        "p90_us": histogram.quantile(0.9).as_micros() as u64,
+        "p99_us": histogram.quantile(0.99).as_micros() as u64,
+        "max_us": histogram.max().as_micros() as u64,
+    })
+}
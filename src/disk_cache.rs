@@ -1,103 +1,1602 @@
-use anyhow::Result;
-use futures::future::join_all;
+use anyhow::{Context, Result};
+use futures::{stream, StreamExt};
 use sccache::lru_disk_cache::Meter;
-use std::{collections::hash_map::RandomState, hash::BuildHasher, path::PathBuf, sync::Arc};
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    hash::BuildHasher,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
-use crate::FileNotifier;
+use crate::{
+    compression::{self, CompressionAlgorithm, Transcoder},
+    content_hash, FileNotifier, KeyMapper, SanitizedKeys,
+};
 
 pub struct DiskCacheMeter {}
 
-impl<K> Meter<K, u64> for DiskCacheMeter {
+impl<K> Meter<K, EntryInfo> for DiskCacheMeter {
     type Measure = usize;
 
-    fn measure<Q: ?Sized>(&self, _: &Q, value: &u64) -> Self::Measure
+    fn measure<Q: ?Sized>(&self, _: &Q, value: &EntryInfo) -> Self::Measure
     where
         K: std::borrow::Borrow<Q>,
     {
-        *value as usize
+        if value.deduplicated || value.pinned {
+            0
+        } else {
+            value.size as usize
+        }
+    }
+}
+
+/// What the cache knows about one of its entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryInfo {
+    /// Size of the file on disk in bytes, which is what counts towards the
+    /// capacity.
+    pub size: u64,
+    /// Size of the content in bytes, which differs from `size` when the cache
+    /// compresses files.
+    pub uncompressed_size: u64,
+    pub inserted_at: SystemTime,
+    /// Last time the entry was read or touched.
+    pub last_access: SystemTime,
+    /// How many times the entry was read or touched since it was inserted.
+    pub hits: u64,
+    /// Whether the file shares its content with another key that accounts
+    /// for it, so that the entry takes no room in the cache.
+    pub deduplicated: bool,
+    /// BLAKE3 hash of the content as written, when the cache verifies
+    /// checksums.
+    pub checksum: Option<[u8; 32]>,
+    /// Pinned entries are never evicted and take no room in the cache.
+    pub pinned: bool,
+}
+
+impl EntryInfo {
+    fn new(size: u64) -> Self {
+        let now = SystemTime::now();
+        Self {
+            size,
+            uncompressed_size: size,
+            inserted_at: now,
+            last_access: now,
+            hits: 0,
+            deduplicated: false,
+            checksum: None,
+            pinned: false,
+        }
+    }
+}
+
+/// Which entries make room when the cache is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum EvictionPolicy {
+    /// Least recently used first.
+    #[default]
+    Lru,
+    /// Least often used first, for workloads where a few keys are much more
+    /// popular than the rest. Ties go to the least recently used.
+    Lfu,
+    /// Oldest inserted first, regardless of use, e.g. for streamed content
+    /// that is read once.
+    Fifo,
+    /// Entries past the cache's time to live first, oldest first, then least
+    /// recently used. Like [`EvictionPolicy::Lru`] without a time to live.
+    TtlFirst,
+}
+
+impl EvictionPolicy {
+    /// Orders `entries`, given from least to most recently used, from the
+    /// first to the last to evict.
+    fn order(self, mut entries: Vec<(String, EntryInfo)>, ttl: Option<Duration>) -> Vec<String> {
+        // Sorts are stable, so ties keep their recency order.
+        match self {
+            EvictionPolicy::Lru => {}
+            EvictionPolicy::Lfu => entries.sort_by_key(|(_, info)| info.hits),
+            EvictionPolicy::Fifo => entries.sort_by_key(|(_, info)| info.inserted_at),
+            EvictionPolicy::TtlFirst => {
+                entries.sort_by_key(|(_, info)| {
+                    if is_expired(info, ttl) {
+                        (false, Some(info.inserted_at))
+                    } else {
+                        (true, None)
+                    }
+                });
+            }
+        }
+        entries.into_iter().map(|(key, _)| key).collect()
+    }
+}
+
+/// How much of a file is read or written at once when streaming it.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How many files an eviction removes, and reports as removed, at once.
+const MAX_CONCURRENT_REMOVALS: usize = 16;
+
+/// Directory under the cache root where partially written files live until
+/// they are complete.
+const TMP_DIR: &str = ".tmp";
+
+/// The result of looking a key up in the cache.
+#[derive(Debug)]
+pub enum CacheLookup {
+    Fresh(tokio::fs::File),
+    /// The entry is older than the cache's time to live.
+    Stale(tokio::fs::File),
+    Missing,
+}
+
+/// Called with the key and size of every evicted entry.
+pub type EvictionHook = Box<dyn Fn(&str, u64) + Send + Sync>;
+
+/// Which keys share which content, when deduplicating.
+#[derive(Default)]
+struct DedupIndex {
+    /// Keys holding the content with a given hash. The first one accounts for
+    /// the size of the content, the others are deduplicated.
+    by_hash: HashMap<Vec<u8>, Vec<String>>,
+    by_key: HashMap<String, Vec<u8>>,
+}
+
+/// Keys whose files are being read for peers, so that eviction leaves them
+/// alone until the reads are over. Clones share the keys.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Serving {
+    state: Arc<Mutex<ServingState>>,
+}
+
+#[derive(Debug, Default)]
+struct ServingState {
+    /// Reads in flight per key.
+    reads: HashMap<String, usize>,
+    /// Keys picked for eviction or being removed, whose files may be gone
+    /// any moment.
+    evicting: HashSet<String>,
+}
+
+impl Serving {
+    /// Keeps `key` from being evicted until the guard is dropped. `None` if
+    /// its file is already being evicted, in which case it is as good as gone.
+    pub(crate) fn start(&self, key: &str) -> Option<ServeGuard> {
+        let mut state = self.state();
+        if state.evicting.contains(key) {
+            return None;
+        }
+        *state.reads.entry(key.to_owned()).or_default() += 1;
+        Some(ServeGuard {
+            serving: self.clone(),
+            key: key.to_owned(),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, ServingState> {
+        self.state.lock().expect("serving lock poisoned")
+    }
+}
+
+/// A read in flight, see [`Serving::start`].
+pub(crate) struct ServeGuard {
+    serving: Serving,
+    key: String,
+}
+
+impl Drop for ServeGuard {
+    fn drop(&mut self) {
+        let mut state = self.serving.state();
+        if let Some(reads) = state.reads.get_mut(&self.key) {
+            *reads -= 1;
+            if *reads == 0 {
+                state.reads.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// A byte budget shared by several caches, e.g. the namespaces of a
+/// deployment running one [`DiskCache`] each on the same disk. Clones share
+/// the budget.
+///
+/// Every cache keeps its own capacity and adds what it holds to the shared
+/// budget, counted like its capacity. Caches only ever evict their own
+/// entries: an insert that does not fit in the shared budget first evicts
+/// from the cache it goes to, and fails if there is still no room, so that
+/// the caches together never hold more than the limit. A cache that is idle
+/// keeps its share; size the capacities of the caches so that none of them
+/// can take the whole budget if others have to keep taking new files.
+///
+/// A cache can also have part of the budget set aside for itself, see
+/// [`DiskCache::with_reserved_capacity`]: it then counts as holding at least
+/// that much, so that the others cannot take the room it has yet to fill,
+/// and it always has room for its files up to there.
+#[derive(Debug, Clone)]
+pub struct SharedCapacity {
+    inner: Arc<SharedCapacityInner>,
+}
+
+#[derive(Debug)]
+struct SharedCapacityInner {
+    limit: u64,
+    /// Bytes held by the caches, plus those reserved for inserts underway.
+    used: AtomicU64,
+    /// Like `used`, but counting every cache as holding at least the bytes
+    /// set aside for it. What has to stay within the limit.
+    charged: AtomicU64,
+}
+
+impl SharedCapacity {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            inner: Arc::new(SharedCapacityInner {
+                limit,
+                used: AtomicU64::new(0),
+                charged: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Maximum number of bytes the caches hold together.
+    pub fn limit(&self) -> u64 {
+        self.inner.limit
+    }
+
+    /// Number of bytes the caches hold together, including those of inserts
+    /// underway.
+    pub fn used(&self) -> u64 {
+        self.inner.used.load(Ordering::Acquire)
+    }
+
+    /// Number of bytes taken from the limit: those held, and those set aside
+    /// for caches holding less than their reserved capacity.
+    pub fn charged(&self) -> u64 {
+        self.inner.charged.load(Ordering::Acquire)
+    }
+
+    fn charge(&self, from: u64, to: u64) {
+        if to >= from {
+            self.inner.charged.fetch_add(to - from, Ordering::AcqRel);
+        } else {
+            self.inner.charged.fetch_sub(from - to, Ordering::AcqRel);
+        }
+    }
+}
+
+/// What one cache takes of a [`SharedCapacity`].
+struct Share {
+    shared: SharedCapacity,
+    /// Bytes set aside for the cache alone.
+    reserved: u64,
+    /// Bytes held by the cache, plus those reserved for inserts underway.
+    held: AtomicU64,
+    /// Size of the cache as last added to `held`.
+    reported: AtomicU64,
+}
+
+impl Share {
+    fn new(shared: SharedCapacity) -> Self {
+        Self {
+            shared,
+            reserved: 0,
+            held: AtomicU64::new(0),
+            reported: AtomicU64::new(0),
+        }
+    }
+
+    /// What holding `held` bytes takes from the limit.
+    fn charge(&self, held: u64) -> u64 {
+        held.max(self.reserved)
+    }
+
+    /// Adds `bytes` to those held, returning how much more that charges.
+    fn grow(&self, bytes: u64) -> u64 {
+        let held = self.held.fetch_add(bytes, Ordering::AcqRel);
+        self.charge(held + bytes) - self.charge(held)
+    }
+
+    /// Takes `bytes` off those held, returning how much less that charges.
+    fn shrink(&self, bytes: u64) -> u64 {
+        let held = self.held.fetch_sub(bytes, Ordering::AcqRel);
+        self.charge(held) - self.charge(held - bytes)
+    }
+
+    /// Sets `bytes` aside for an insert until the reservation is dropped, if
+    /// they fit.
+    fn reserve(&self, bytes: u64) -> Option<Reservation<'_>> {
+        let extra = self.grow(bytes);
+        let shared = &self.shared.inner;
+        let charged = shared
+            .charged
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |charged| {
+                charged
+                    .checked_add(extra)
+                    .filter(|charged| *charged <= shared.limit)
+            });
+        if charged.is_err() {
+            // What growing charged was never added, what shrinking back frees
+            // was not necessarily the same if the cache changed meanwhile.
+            let freed = self.shrink(bytes);
+            self.shared.charge(freed, extra);
+            return None;
+        }
+        shared.used.fetch_add(bytes, Ordering::AcqRel);
+        Some(Reservation { share: self, bytes })
+    }
+
+    /// Accounts for the cache going from `from` to `to` bytes.
+    fn resize(&self, from: u64, to: u64) {
+        let shared = &self.shared.inner;
+        if to >= from {
+            let extra = self.grow(to - from);
+            shared.used.fetch_add(to - from, Ordering::AcqRel);
+            shared.charged.fetch_add(extra, Ordering::AcqRel);
+        } else {
+            let freed = self.shrink(from - to);
+            shared.used.fetch_sub(from - to, Ordering::AcqRel);
+            shared.charged.fetch_sub(freed, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Bytes of a [`SharedCapacity`] set aside for an insert underway.
+struct Reservation<'a> {
+    share: &'a Share,
+    bytes: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.share.resize(self.bytes, 0);
+    }
+}
+
+/// Bytes of the capacity of a cache set aside for inserts underway, whose
+/// files are not in the LRU yet.
+#[derive(Default)]
+struct Reserved {
+    /// Only grows with the LRU locked, so that concurrent inserts cannot
+    /// take the same room.
+    bytes: AtomicU64,
+    /// Entries picked for eviction or being removed, which give their room
+    /// back once their files are deleted.
+    removals: AtomicU64,
+    /// Woken whenever bytes are handed back, or removals are over.
+    released: tokio::sync::Notify,
+}
+
+impl Reserved {
+    fn removals_over(&self, removals: u64) {
+        if removals > 0 {
+            self.removals.fetch_sub(removals, Ordering::AcqRel);
+            self.released.notify_waiters();
+        }
+    }
+}
+
+/// Room made for an insert, see [`DiskCache::make_room`], held until its
+/// file is accounted for.
+struct Room<'a> {
+    reserved: &'a Reserved,
+    bytes: u64,
+    _shared: Option<Reservation<'a>>,
+}
+
+impl Drop for Room<'_> {
+    fn drop(&mut self) {
+        self.reserved.bytes.fetch_sub(self.bytes, Ordering::AcqRel);
+        self.reserved.released.notify_waiters();
+    }
+}
+
+type Lru<H> = sccache::lru_disk_cache::LruCache<String, EntryInfo, H, DiskCacheMeter>;
+
+/// The locked LRU of a cache. With a shared capacity, changes to the size of
+/// the cache are passed on to it once unlocked.
+struct LruGuard<'a> {
+    lru: MutexGuard<'a, Lru<RandomState>>,
+    share: Option<&'a Share>,
+}
+
+impl std::ops::Deref for LruGuard<'_> {
+    type Target = Lru<RandomState>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.lru
+    }
+}
+
+impl std::ops::DerefMut for LruGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.lru
+    }
+}
+
+impl Drop for LruGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(share) = self.share {
+            let size = self.lru.size();
+            // Still locked, so reports of the same cache cannot interleave.
+            let previous = share.reported.swap(size, Ordering::AcqRel);
+            share.resize(previous, size);
+        }
     }
 }
 
+/// A size-bounded cache of files under a root directory.
+///
+/// Every method takes `&self`, so a cache can be shared between tasks. The
+/// bookkeeping is locked only briefly and never while doing IO, so inserts of
+/// different keys write their files concurrently. A file is written to a
+/// temporary path and only accounted for once it was renamed into place, so
+/// readers never see a partially written entry.
 pub struct DiskCache<N, H: BuildHasher = RandomState> {
     root: PathBuf,
-    lru: sccache::lru_disk_cache::LruCache<String, u64, H, DiskCacheMeter>,
+    lru: Mutex<Lru<H>>,
     files_to_evict: u64,
     notifier: N,
+    next_tmp_file: AtomicU64,
+    durable: bool,
+    unsynced: Mutex<HashSet<String>>,
+    on_evict: Option<EvictionHook>,
+    ttl: Option<Duration>,
+    eviction_policy: EvictionPolicy,
+    /// Locked before `lru` when both are needed.
+    dedup: Option<Mutex<DedupIndex>>,
+    verify_checksums: bool,
+    compression: Option<CompressionAlgorithm>,
+    serving: Serving,
+    share: Option<Share>,
+    reserved: Reserved,
+    key_mapper: Arc<dyn KeyMapper>,
 }
 
 impl<N: FileNotifier> DiskCache<N> {
-    pub fn new<P: Into<PathBuf>>(root: P, files_to_evict: u64, capacity: u64, notifier: N) -> Self {
+    /// Creates the cache directory under `root` if needed, failing if it
+    /// cannot be created, e.g. for lack of permissions or because the path
+    /// is a file.
+    pub fn new<P: Into<PathBuf>>(
+        root: P,
+        files_to_evict: u64,
+        capacity: u64,
+        notifier: N,
+    ) -> Result<Self> {
         let root = root.into();
         let meter = DiskCacheMeter {};
         let lru = sccache::lru_disk_cache::LruCache::with_meter(capacity, meter);
-        std::fs::create_dir_all(&root).unwrap();
-        Self {
+        let tmp_dir = root.join(TMP_DIR);
+        std::fs::create_dir_all(&tmp_dir)
+            .with_context(|| format!("creating cache directory {}", tmp_dir.display()))?;
+        Ok(Self {
             root,
-            lru,
+            lru: Mutex::new(lru),
             files_to_evict,
             notifier,
+            next_tmp_file: AtomicU64::new(0),
+            durable: false,
+            unsynced: Mutex::new(HashSet::new()),
+            on_evict: None,
+            ttl: None,
+            eviction_policy: EvictionPolicy::default(),
+            dedup: None,
+            verify_checksums: false,
+            compression: None,
+            serving: Serving::default(),
+            share: None,
+            reserved: Reserved::default(),
+            key_mapper: Arc::new(SanitizedKeys),
+        })
+    }
+
+    /// Lays the files out on disk as `key_mapper` says, [`SanitizedKeys`] by
+    /// default. Meant to be set before anything is inserted, and kept for as
+    /// long as the files are: files cached with another mapper cannot be
+    /// found.
+    pub fn with_key_mapper(mut self, key_mapper: Arc<dyn KeyMapper>) -> Self {
+        self.key_mapper = key_mapper;
+        self
+    }
+
+    /// Entries inserted longer than `ttl` ago are reported as stale by
+    /// [`DiskCache::lookup`]. They are kept until evicted or replaced.
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn with_eviction_policy(mut self, eviction_policy: EvictionPolicy) -> Self {
+        self.eviction_policy = eviction_policy;
+        self
+    }
+
+    /// With dedup, content inserted under a key while identical content is
+    /// cached under another one is not written again: the new file is a
+    /// hardlink to the existing one and only the first key counts towards the
+    /// capacity. The content leaves the disk once no key links to it anymore.
+    /// Content streamed with [`DiskCache::insert_from_reader`] is not
+    /// deduplicated, since it is never held in memory to be hashed up front.
+    pub fn with_dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup.then(|| Mutex::new(DedupIndex::default()));
+        self
+    }
+
+    /// With checksums, the hash of every file is kept along with its entry and
+    /// checked whenever the file is read. A file whose content no longer
+    /// matches, e.g. after a disk fault, is removed and reported as missing,
+    /// so that it is fetched again. Reading then costs hashing the whole file.
+    pub fn with_verify_checksums(mut self, verify_checksums: bool) -> Self {
+        self.verify_checksums = verify_checksums;
+        self
+    }
+
+    /// With compression, files are compressed before they are written, and
+    /// only their compressed size counts towards the capacity. Reads still
+    /// return a plain [`tokio::fs::File`] with the original content: the file
+    /// is decompressed into an anonymous temporary file first, which costs
+    /// decompressing it whole on every read. Files cached before compression
+    /// was turned on, or with another algorithm, cannot be read back.
+    pub fn with_compression(mut self, compression: Option<CompressionAlgorithm>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Counts the cache against `shared_capacity` as well as its own capacity,
+    /// see [`SharedCapacity`]. Meant to be set before anything is inserted.
+    pub fn with_shared_capacity(mut self, shared_capacity: Option<SharedCapacity>) -> Self {
+        self.share = shared_capacity.map(Share::new);
+        self
+    }
+
+    /// Sets `reserved` bytes of the shared capacity aside for this cache:
+    /// the other caches sharing it cannot take them, even while this one
+    /// holds less, so that it always has room for that much of its own
+    /// files. Meant to be set after the shared capacity and before anything
+    /// is inserted. Does nothing without a shared capacity.
+    pub fn with_reserved_capacity(mut self, reserved: u64) -> Self {
+        if let Some(share) = &mut self.share {
+            let held = *share.held.get_mut();
+            let charged = share.charge(held);
+            share.reserved = reserved;
+            share.shared.charge(charged, share.charge(held));
+        }
+        self
+    }
+
+    /// Shares the keys being served with whoever reads the cached files for
+    /// peers, so that eviction skips them.
+    pub(crate) fn with_serving(mut self, serving: Serving) -> Self {
+        self.serving = serving;
+        self
+    }
+
+    /// Runs `hook` for every entry evicted to make room, after its file is
+    /// gone, independently of the notifier. Explicit removals do not count.
+    pub fn with_on_evict(mut self, hook: EvictionHook) -> Self {
+        self.on_evict = Some(hook);
+        self
+    }
+
+    /// By default files are written without waiting for them to reach the
+    /// disk, so a power failure can lose entries the cache (and the network)
+    /// believe are there. A durable cache fsyncs every file, and the cache
+    /// directory, before an insert returns, at a cost in throughput.
+    pub fn with_durable(mut self, durable: bool) -> Self {
+        self.durable = durable;
+        self
+    }
+
+    /// Fsyncs every file written since the last flush, and the directories
+    /// they are in. Does nothing extra for a durable cache.
+    pub async fn flush(&self) -> Result<()> {
+        let unsynced = std::mem::take(&mut *self.unsynced());
+        let mut dirs = HashSet::from([self.root.clone()]);
+        for key in unsynced {
+            let path = self.path(&key);
+            if let Some(dir) = path.parent() {
+                dirs.insert(dir.to_owned());
+            }
+            match tokio::fs::File::open(&path).await {
+                Ok(file) => file
+                    .sync_all()
+                    .await
+                    .with_context(|| format!("syncing {} for key {}", path.display(), key))?,
+                // Evicted in the meantime.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("opening {} for key {}", path.display(), key))
+                }
+            }
+        }
+        for dir in dirs {
+            sync_dir(&dir).await?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure a freshly written file survives a crash, now or on the next
+    /// flush depending on durability.
+    async fn written(&self, key: &str) -> Result<()> {
+        if self.durable {
+            sync_dir(self.path(key).parent().unwrap_or(&self.root)).await
+        } else {
+            self.unsynced().insert(key.to_owned());
+            Ok(())
+        }
+    }
+
+    /// Where the file of `key` is.
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(self.key_mapper.path(key))
+    }
+
+    /// Creates the directory `path` goes in, for key mappers putting files
+    /// in subdirectories.
+    async fn create_dir_for(&self, path: &Path) -> std::io::Result<()> {
+        match path.parent() {
+            Some(dir) if dir != self.root => tokio::fs::create_dir_all(dir).await,
+            _ => Ok(()),
+        }
+    }
+
+    fn lru(&self) -> LruGuard<'_> {
+        LruGuard {
+            lru: self.lru.lock().expect("lru lock poisoned"),
+            share: self.share.as_ref(),
+        }
+    }
+
+    /// Sets `needed` bytes aside in the cache, and in the shared capacity if
+    /// any, if they fit in both. Checked and taken with the LRU locked, so
+    /// that concurrent inserts cannot both take the last of the room.
+    fn reserve(&self, needed: u64) -> Option<Room<'_>> {
+        let lru = self.lru();
+        let reserved = self.reserved.bytes.load(Ordering::Acquire);
+        if lru.size() + reserved + needed > lru.capacity() {
+            return None;
+        }
+        let shared = match &self.share {
+            Some(share) => Some(share.reserve(needed)?),
+            None => None,
+        };
+        self.reserved.bytes.fetch_add(needed, Ordering::AcqRel);
+        Some(Room {
+            reserved: &self.reserved,
+            bytes: needed,
+            _shared: shared,
+        })
+    }
+
+    /// Evicts until `needed` more bytes fit in the cache and the shared
+    /// capacity, then sets them aside in both until the returned room is
+    /// dropped, once the file is accounted for. Waits for the inserts
+    /// underway when only their files are in the way, which can be evicted
+    /// once written, and for the removals underway. Fails if the bytes do
+    /// not fit once nothing more can be evicted, e.g. because the files left
+    /// are pinned or being read, and right away if they would not fit in the
+    /// cache even empty.
+    async fn make_room(&self, needed: u64) -> Result<Room<'_>> {
+        let capacity = self.capacity();
+        if needed > capacity {
+            anyhow::bail!(
+                "{} bytes do not fit in a cache of {} bytes",
+                needed,
+                capacity
+            );
+        }
+        loop {
+            // Listening before checking, so that no release goes unnoticed.
+            let released = self.reserved.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            if let Some(room) = self.reserve(needed) {
+                return Ok(room);
+            }
+            if self.evict().await? > 0 {
+                continue;
+            }
+            if self.reserved.bytes.load(Ordering::Acquire) > 0
+                || self.reserved.removals.load(Ordering::Acquire) > 0
+            {
+                released.await;
+                continue;
+            }
+            // Removals that were underway while evicting may have made room.
+            if let Some(room) = self.reserve(needed) {
+                return Ok(room);
+            }
+            // Going ahead would have the LRU drop entries on its own, leaving
+            // their files behind.
+            let size = self.size();
+            if size + needed > capacity {
+                anyhow::bail!(
+                    "no room for {} bytes in a cache of {} bytes holding {} bytes that cannot be evicted",
+                    needed,
+                    capacity,
+                    size
+                );
+            }
+            let limit = self.share.as_ref().map_or(0, |share| share.shared.limit());
+            anyhow::bail!(
+                "no room for {} bytes in the shared capacity of {} bytes",
+                needed,
+                limit
+            );
+        }
+    }
+
+    fn unsynced(&self) -> MutexGuard<'_, HashSet<String>> {
+        self.unsynced.lock().expect("unsynced lock poisoned")
+    }
+
+    /// Maximum number of bytes kept on disk.
+    pub fn capacity(&self) -> u64 {
+        self.lru().capacity()
+    }
+
+    /// Changes the maximum number of bytes kept on disk, evicting files
+    /// until the cache fits. Fails, with the capacity unchanged, if files
+    /// being read keep it from fitting.
+    pub async fn set_capacity(&self, capacity: u64) -> Result<()> {
+        loop {
+            let size = self.size();
+            if size <= capacity {
+                break;
+            }
+            self.evict().await?;
+            if self.size() >= size {
+                anyhow::bail!(
+                    "cannot shrink the cache to {} bytes, files in use keep it at {} bytes",
+                    capacity,
+                    size
+                );
+            }
+        }
+        self.lru().set_capacity(capacity);
+        Ok(())
+    }
+
+    /// Changes how many files are evicted at once when the cache is full,
+    /// see [`crate::CacheConfig::files_to_evict`].
+    pub fn set_files_to_evict(&mut self, files_to_evict: u64) {
+        self.files_to_evict = files_to_evict;
+    }
+
+    /// See [`DiskCache::with_durable`].
+    pub fn set_durable(&mut self, durable: bool) {
+        self.durable = durable;
+    }
+
+    /// See [`DiskCache::with_ttl`]. Applies to the files cached already.
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
+
+    /// See [`DiskCache::with_eviction_policy`].
+    pub fn set_eviction_policy(&mut self, eviction_policy: EvictionPolicy) {
+        self.eviction_policy = eviction_policy;
+    }
+
+    /// Number of bytes currently cached.
+    pub fn size(&self) -> u64 {
+        self.lru().size()
+    }
+
+    /// Number of bytes the files under the root actually take, temporary
+    /// files included, as opposed to what [`DiskCache::size`] accounts for.
+    /// Files hardlinked under several keys count once. Only the files directly
+    /// under the root and those in directories starting with a `.`, where
+    /// temporary files and the files of some key mappers go, are the cache's:
+    /// other subdirectories, e.g. those of namespaces, are left out.
+    ///
+    /// Pinned files count here but not in the size; other than that, the two
+    /// only drift apart when files are left behind or go missing. Walks the
+    /// directory, so it takes time proportional to the number of files.
+    pub async fn disk_usage(&self) -> Result<u64> {
+        let mut seen = HashSet::new();
+        let mut usage = 0;
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = tokio::fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("listing {}", dir.display()))?;
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .with_context(|| format!("listing {}", dir.display()))?
+            {
+                let metadata = match entry.metadata().await {
+                    Ok(metadata) => metadata,
+                    // Evicted or renamed since it was listed.
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => {
+                        return Err(e).with_context(|| {
+                            format!("reading metadata of {}", entry.path().display())
+                        })
+                    }
+                };
+                if metadata.is_dir()
+                    && (dir != self.root || entry.file_name().to_string_lossy().starts_with('.'))
+                {
+                    dirs.push(entry.path());
+                } else if metadata.is_file() && seen.insert(file_id(&metadata, &entry.path())) {
+                    usage += metadata.len();
+                }
+            }
         }
+        Ok(usage)
+    }
+
+    /// Number of cached entries.
+    pub fn len(&self) -> usize {
+        self.lru().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lru().is_empty()
+    }
+
+    /// Whether `key` is cached, without affecting its recency.
+    pub fn contains_key<S: AsRef<str>>(&self, key: S) -> bool {
+        self.lru().contains_key(key.as_ref())
+    }
+
+    /// Counts as an access to `key` without reading it. Returns whether it
+    /// was cached; nothing is created otherwise.
+    pub fn touch<S: AsRef<str>>(&self, key: S) -> bool {
+        self.mark_accessed(key.as_ref()).is_some()
     }
 
-    pub fn touch<S: AsRef<str>>(&mut self, key: S) {
-        self.lru.get(key.as_ref());
+    pub fn entry_info<S: AsRef<str>>(&self, key: S) -> Option<EntryInfo> {
+        // `iter` does not affect recency, unlike `get`.
+        self.lru()
+            .iter()
+            .find(|(k, _)| k.as_str() == key.as_ref())
+            .map(|(_, info)| *info)
     }
 
-    pub async fn get<S: AsRef<str>>(&mut self, key: S) -> Result<Option<tokio::fs::File>> {
-        self.lru.get(key.as_ref());
+    /// Bumps the recency and access time of `key`, returning its entry if it
+    /// is cached.
+    fn mark_accessed(&self, key: &str) -> Option<EntryInfo> {
+        self.update_entry(key, |info| {
+            info.last_access = SystemTime::now();
+            info.hits += 1;
+        })
+    }
+
+    /// Changes the entry of `key`, if cached, and bumps its recency.
+    fn update_entry(&self, key: &str, update: impl FnOnce(&mut EntryInfo)) -> Option<EntryInfo> {
+        // The LRU does not hand out mutable values with a custom meter;
+        // re-inserting refreshes recency just like `get` would.
+        let mut lru = self.lru();
+        let mut info = lru.remove(key)?;
+        update(&mut info);
+        lru.insert(key.to_owned(), info);
+        Some(info)
+    }
+
+    /// Keeps `key` from ever being evicted, e.g. for the authoritative copy
+    /// of a dataset on a seed node, and stops counting it towards the
+    /// capacity, so that fetched content cannot push it out. Explicit
+    /// removals and replacements still apply. Returns whether it was cached.
+    pub fn pin<S: AsRef<str>>(&self, key: S) -> bool {
+        self.update_entry(key.as_ref(), |info| info.pinned = true)
+            .is_some()
+    }
+
+    /// Makes `key` evictable again, making room for it if it does not fit
+    /// anymore. Returns whether it was cached. Fails, leaving it pinned, if
+    /// no room can be made for it.
+    pub async fn unpin<S: AsRef<str>>(&self, key: S) -> Result<bool> {
+        let Some(info) = self.entry_info(key.as_ref()) else {
+            return Ok(false);
+        };
+        let room = if info.pinned {
+            Some(self.make_room(info.size).await?)
+        } else {
+            None
+        };
+        let unpinned = self
+            .update_entry(key.as_ref(), |info| info.pinned = false)
+            .is_some();
+        drop(room);
+        Ok(unpinned)
+    }
+
+    /// Like [`DiskCache::get`], but tells apart entries that outlived the time
+    /// to live.
+    pub async fn lookup<S: AsRef<str>>(&self, key: S) -> Result<CacheLookup> {
+        let info = self.mark_accessed(key.as_ref());
+        Ok(match self.open_verified(key.as_ref(), info).await? {
+            Some(file) if info.is_some_and(|info| is_expired(&info, self.ttl)) => {
+                CacheLookup::Stale(file)
+            }
+            Some(file) => CacheLookup::Fresh(file),
+            None => CacheLookup::Missing,
+        })
+    }
 
-        let path = self.root.join(key.as_ref());
-        match tokio::fs::File::open(path).await {
-            Ok(file) => Ok(Some(file)),
+    pub async fn get<S: AsRef<str>>(&self, key: S) -> Result<Option<tokio::fs::File>> {
+        let info = self.mark_accessed(key.as_ref());
+        self.open_verified(key.as_ref(), info).await
+    }
+
+    /// Like [`DiskCache::get`], but without counting as an access, e.g. for
+    /// reading every entry without reordering them.
+    pub async fn peek<S: AsRef<str>>(&self, key: S) -> Result<Option<tokio::fs::File>> {
+        let info = self.entry_info(key.as_ref());
+        self.open_verified(key.as_ref(), info).await
+    }
+
+    /// Like [`DiskCache::peek`], for a key listed by [`DiskCache::entries`]
+    /// along with `info`, so that reading every entry does not look each one
+    /// up again. A key replaced since it was listed reads as its new content,
+    /// one removed since as missing.
+    pub async fn peek_listed<S: AsRef<str>>(
+        &self,
+        key: S,
+        info: EntryInfo,
+    ) -> Result<Option<tokio::fs::File>> {
+        self.open_verified(key.as_ref(), Some(info)).await
+    }
+
+    /// Opens the file of `key`, decompressed, treating content that does not
+    /// decompress or match the checksum of its entry as missing. The corrupt
+    /// entry is removed.
+    async fn open_verified(
+        &self,
+        key: &str,
+        info: Option<EntryInfo>,
+    ) -> Result<Option<tokio::fs::File>> {
+        let Some(file) = self.open(key)? else {
+            return Ok(None);
+        };
+        let file = match self.compression {
+            Some(algorithm) => self.decompress(algorithm, file).await.with_context(|| {
+                format!("decompressing {} for key {}", self.path(key).display(), key)
+            })?,
+            None => Some(file),
+        };
+        let Some(mut file) = file else {
+            self.remove(key).await?;
+            return Ok(None);
+        };
+        let Some(expected) = info.and_then(|info| info.checksum) else {
+            return Ok(Some(file));
+        };
+        let actual = async {
+            let actual = checksum_reader(&mut file).await?;
+            file.rewind().await?;
+            Ok::<_, std::io::Error>(actual)
+        }
+        .await
+        .with_context(|| format!("verifying {} for key {}", self.path(key).display(), key))?;
+        // The entry may have been replaced since `info` was read.
+        if actual == expected || self.entry_info(key).and_then(|info| info.checksum) == Some(actual)
+        {
+            return Ok(Some(file));
+        }
+        drop(file);
+        self.remove(key).await?;
+        Ok(None)
+    }
+
+    /// Decompresses `compressed` into a temporary file that is gone from the
+    /// disk as soon as the returned handle is dropped. `None` if the content
+    /// does not decompress.
+    async fn decompress(
+        &self,
+        algorithm: CompressionAlgorithm,
+        mut compressed: tokio::fs::File,
+    ) -> std::io::Result<Option<tokio::fs::File>> {
+        let tmp_path = self.next_tmp_path();
+        let mut file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .await?;
+        // The open handle keeps the content around.
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        let mut decompressor = Transcoder::decompressor(algorithm)?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let read = compressed.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            let Ok(output) = decompressor.update(&buf[..read]) else {
+                return Ok(None);
+            };
+            file.write_all(&output).await?;
+        }
+        let Ok(output) = decompressor.finish() else {
+            return Ok(None);
+        };
+        file.write_all(&output).await?;
+        file.flush().await?;
+        file.rewind().await?;
+        Ok(Some(file))
+    }
+
+    /// Opens the file of `key`, if there is one. Opened right away rather
+    /// than on a blocking thread, as [`tokio::fs::File::open`] would: opening
+    /// a file reads none of it, and the round trip to another thread would
+    /// take longer than the open itself, on the path every local hit goes
+    /// through.
+    fn open(&self, key: &str) -> Result<Option<tokio::fs::File>> {
+        let path = self.path(key);
+        match std::fs::File::open(&path) {
+            Ok(file) => Ok(Some(tokio::fs::File::from_std(file))),
             Err(e) => {
                 if e.kind() == std::io::ErrorKind::NotFound {
                     Ok(None)
                 } else {
-                    Err(e.into())
+                    Err(e).with_context(|| format!("opening {} for key {}", path.display(), key))
                 }
             }
         }
     }
 
-    pub async fn insert<S: AsRef<str>>(&mut self, key: S, buf: &[u8]) -> Result<()> {
-        if self.lru.contains_key(key.as_ref()) {
+    pub async fn insert<S: AsRef<str>>(&self, key: S, buf: &[u8]) -> Result<()> {
+        self.insert_entry(key.as_ref(), buf, false).await
+    }
+
+    /// Like [`DiskCache::insert`], but the entry is pinned from the start, see
+    /// [`DiskCache::pin`]: since it takes no room, nothing is evicted for it,
+    /// and it fits however large it is. A key already cached is pinned as is.
+    pub async fn insert_pinned<S: AsRef<str>>(&self, key: S, buf: &[u8]) -> Result<()> {
+        self.insert_entry(key.as_ref(), buf, true).await
+    }
+
+    async fn insert_entry(&self, key: &str, buf: &[u8], pinned: bool) -> Result<()> {
+        if self.contains_key(key) {
+            if pinned {
+                self.pin(key);
+            }
             return Ok(());
         }
 
-        if self.lru.size() + buf.len() as u64 > self.lru.capacity() {
-            self.evict().await?;
+        let hash = self.dedup.as_ref().map(|_| content_hash(buf));
+        let source = hash.as_deref().and_then(|hash| self.dedup_source(hash));
+        let stored = self
+            .stored(buf)
+            .with_context(|| format!("compressing {}", key))?;
+        let needed = if source.is_some() {
+            0
+        } else {
+            stored.len() as u64
+        };
+        let room = if pinned {
+            None
+        } else {
+            Some(self.make_room(needed).await?)
+        };
+        let tmp_path = self.next_tmp_path();
+        let path = self.path(key);
+        let written = async {
+            self.write_or_link(&tmp_path, &stored, source.as_deref())
+                .await?;
+            self.create_dir_for(&path).await?;
+            tokio::fs::rename(&tmp_path, &path).await
         }
-        let path = self.root.join(key.as_ref());
-        self.lru.insert(key.as_ref().to_owned(), buf.len() as u64);
-        tokio::fs::write(path, buf).await?;
-        self.notifier.added(key.as_ref().to_owned()).await;
+        .await;
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e).with_context(|| format!("writing {} for key {}", path.display(), key));
+        }
+        let mut info = self.entry_for(buf, &stored);
+        info.pinned = pinned;
+        // A pinned entry takes no room, so it cannot account for content
+        // other keys share.
+        self.account(key, info, hash.filter(|_| !pinned));
+        drop(room);
+        self.written(key).await?;
+        self.notifier.added(key.to_owned()).await?;
         Ok(())
     }
 
-    async fn evict(&mut self) -> Result<()> {
-        let files_to_evict = (1..self.files_to_evict)
-            .filter_map(|_| self.lru.remove_lru())
-            .map(|(key, size)| {
-                let path = self.root.join(&key);
-                (key, size, path)
-            })
-            .collect::<Vec<_>>();
+    /// Streams `reader` into the cache under `key` without holding the whole
+    /// content in memory.
+    ///
+    /// Since the final size is only known once the reader is exhausted, the
+    /// caller has to provide `expected_len` up front: room for it is made
+    /// before anything is written. The content goes to a temporary file which
+    /// is renamed into place, accounted for and announced only if the reader
+    /// yielded exactly `expected_len` bytes. Otherwise the temporary file is
+    /// removed and an error is returned, leaving the cache untouched apart
+    /// from the eviction.
+    pub async fn insert_from_reader<S, R>(&self, key: S, reader: R, expected_len: u64) -> Result<()>
+    where
+        S: AsRef<str>,
+        R: AsyncRead + Unpin,
+    {
+        if self.contains_key(key.as_ref()) {
+            return Ok(());
+        }
 
-        let lru = &Arc::new(tokio::sync::Mutex::new(&mut self.lru));
-        let notifier = &self.notifier;
-        join_all(
-            files_to_evict
-                .into_iter()
-                .map(|(key, size, path)| async move {
-                    if let Err(_) = tokio::fs::remove_file(path).await {
-                        lru.lock().await.insert(key, size);
-                    } else {
-                        notifier.removed(key).await;
+        // Compressed content is only measured once written, so room is made
+        // for the uncompressed length.
+        let room = self.make_room(expected_len).await?;
+
+        let tmp_path = self.next_tmp_path();
+
+        // Read one byte more than expected so that a longer reader is noticed.
+        let durable = self.durable;
+        let verify_checksums = self.verify_checksums;
+        let compression = self.compression;
+        let written = async {
+            let mut file = tokio::fs::File::create(&tmp_path).await?;
+            let mut reader = reader.take(expected_len + 1);
+            let mut hasher = verify_checksums.then(blake3::Hasher::new);
+            let mut compressor = compression.map(Transcoder::compressor).transpose()?;
+            let mut buf = vec![0; CHUNK_SIZE];
+            let mut written = 0;
+            let mut stored = 0;
+            loop {
+                let read = reader.read(&mut buf).await?;
+                if read == 0 {
+                    break;
+                }
+                match &mut compressor {
+                    Some(compressor) => {
+                        let output = compressor.update(&buf[..read])?;
+                        file.write_all(&output).await?;
+                        stored += output.len() as u64;
+                    }
+                    None => {
+                        file.write_all(&buf[..read]).await?;
+                        stored += read as u64;
                     }
-                }),
-        )
+                }
+                if let Some(hasher) = &mut hasher {
+                    hasher.update(&buf[..read]);
+                }
+                written += read as u64;
+            }
+            if let Some(compressor) = compressor {
+                let output = compressor.finish()?;
+                file.write_all(&output).await?;
+                stored += output.len() as u64;
+            }
+            file.flush().await?;
+            if durable {
+                file.sync_all().await?;
+            }
+            let checksum = hasher.map(|hasher| *hasher.finalize().as_bytes());
+            Ok::<_, std::io::Error>((written, stored, checksum))
+        }
+        .await;
+        let (written, stored, checksum) = match written {
+            Ok((written, stored, checksum)) if written == expected_len => {
+                (written, stored, checksum)
+            }
+            Ok((written, ..)) => {
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+                if written > expected_len {
+                    anyhow::bail!(
+                        "reader for {} yielded more than the expected {} bytes",
+                        key.as_ref(),
+                        expected_len
+                    );
+                }
+                anyhow::bail!(
+                    "reader for {} yielded {} bytes, expected {}",
+                    key.as_ref(),
+                    written,
+                    expected_len
+                );
+            }
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+                return Err(e).with_context(|| {
+                    format!("streaming {} into {}", key.as_ref(), tmp_path.display())
+                });
+            }
+        };
+
+        let path = self.path(key.as_ref());
+        let moved = async {
+            self.create_dir_for(&path).await?;
+            tokio::fs::rename(&tmp_path, &path).await
+        };
+        moved.await.with_context(|| {
+            format!(
+                "moving {} to {} for key {}",
+                tmp_path.display(),
+                path.display(),
+                key.as_ref()
+            )
+        })?;
+        let mut info = EntryInfo::new(stored);
+        info.uncompressed_size = written;
+        info.checksum = checksum;
+        self.lru().insert(key.as_ref().to_owned(), info);
+        drop(room);
+        self.written(key.as_ref()).await?;
+        self.notifier.added(key.as_ref().to_owned()).await?;
+        Ok(())
+    }
+
+    /// Overwrites the content of `key`, or inserts it if it is not cached. The
+    /// new content is written to a temporary file and renamed over the old
+    /// one, so readers see either version in full. The entry counts as freshly
+    /// inserted and is announced again.
+    pub async fn replace<S: AsRef<str>>(&self, key: S, buf: &[u8]) -> Result<()> {
+        let old = self.lru().remove(key.as_ref());
+        let Some(old) = old else {
+            return self.insert(key, buf).await;
+        };
+
+        let hash = self.dedup.as_ref().map(|_| content_hash(buf));
+        let source = hash.as_deref().and_then(|hash| self.dedup_source(hash));
+        let stored = match self.stored(buf) {
+            Ok(stored) => stored,
+            Err(e) => {
+                self.lru().insert(key.as_ref().to_owned(), old);
+                return Err(e).with_context(|| format!("compressing {}", key.as_ref()));
+            }
+        };
+        let needed = if source.is_some() {
+            0
+        } else {
+            stored.len() as u64
+        };
+        let room = match self.make_room(needed).await {
+            Ok(room) => room,
+            Err(e) => {
+                self.lru().insert(key.as_ref().to_owned(), old);
+                return Err(e);
+            }
+        };
+
+        let tmp_path = self.next_tmp_path();
+        let path = self.path(key.as_ref());
+        let written = async {
+            self.write_or_link(&tmp_path, &stored, source.as_deref())
+                .await?;
+            self.create_dir_for(&path).await?;
+            tokio::fs::rename(&tmp_path, &path).await
+        }
         .await;
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            self.lru().insert(key.as_ref().to_owned(), old);
+            return Err(e)
+                .with_context(|| format!("replacing {} for key {}", path.display(), key.as_ref()));
+        }
 
+        let mut info = self.entry_for(buf, &stored);
+        info.pinned = old.pinned;
+        self.forget_content(key.as_ref());
+        self.account(key.as_ref(), info, hash);
+        drop(room);
+        self.written(key.as_ref()).await?;
+        self.notifier.added(key.as_ref().to_owned()).await?;
         Ok(())
     }
+
+    /// A cached key holding the content with `hash`, if any.
+    fn dedup_source(&self, hash: &[u8]) -> Option<String> {
+        let dedup = self.dedup.as_ref()?.lock().expect("dedup lock poisoned");
+        dedup.by_hash.get(hash)?.first().cloned()
+    }
+
+    /// Writes `buf` to `path`, or hardlinks `path` to the file of `source`
+    /// when there is one. The source may have been removed since it was
+    /// picked, in which case the content is written after all.
+    async fn write_or_link(
+        &self,
+        path: &Path,
+        buf: &[u8],
+        source: Option<&str>,
+    ) -> std::io::Result<()> {
+        if let Some(source) = source {
+            if tokio::fs::hard_link(self.path(source), path).await.is_ok() {
+                return Ok(());
+            }
+        }
+        write_file(path, buf, self.durable).await
+    }
+
+    /// What is written to disk for `buf`: `buf` itself, or compressed.
+    fn stored<'a>(&self, buf: &'a [u8]) -> std::io::Result<std::borrow::Cow<'a, [u8]>> {
+        Ok(match self.compression {
+            Some(algorithm) => compression::compress(algorithm, buf)?.into(),
+            None => buf.into(),
+        })
+    }
+
+    /// The entry of `buf`, stored on disk as `stored`.
+    fn entry_for(&self, buf: &[u8], stored: &[u8]) -> EntryInfo {
+        let mut info = EntryInfo::new(stored.len() as u64);
+        info.uncompressed_size = buf.len() as u64;
+        if self.verify_checksums {
+            info.checksum = Some(*blake3::hash(buf).as_bytes());
+        }
+        info
+    }
+
+    /// Accounts for a freshly written file. With dedup, the entry takes no
+    /// room if another key already accounts for its content.
+    fn account(&self, key: &str, mut info: EntryInfo, hash: Option<Vec<u8>>) {
+        let dedup = self
+            .dedup
+            .as_ref()
+            .map(|dedup| dedup.lock().expect("dedup lock poisoned"));
+        if let (Some(mut dedup), Some(hash)) = (dedup, hash) {
+            let keys = dedup.by_hash.entry(hash.clone()).or_default();
+            info.deduplicated = !keys.is_empty();
+            keys.push(key.to_owned());
+            dedup.by_key.insert(key.to_owned(), hash);
+            // Still holding the index, so that the entry cannot be handed the
+            // accounting before it is in the LRU.
+            self.lru().insert(key.to_owned(), info);
+        } else {
+            self.lru().insert(key.to_owned(), info);
+        }
+    }
+
+    /// Drops `key` from the dedup index once its file is gone. If it was
+    /// accounting for content other keys still link to, the next one takes
+    /// over.
+    fn forget_content(&self, key: &str) {
+        let Some(dedup) = &self.dedup else {
+            return;
+        };
+        let mut dedup = dedup.lock().expect("dedup lock poisoned");
+        let Some(hash) = dedup.by_key.remove(key) else {
+            return;
+        };
+        let Some(keys) = dedup.by_hash.get_mut(&hash) else {
+            return;
+        };
+        let was_accounting = keys.first().is_some_and(|first| first == key);
+        keys.retain(|k| k != key);
+        let next = keys.first().cloned();
+        let Some(next) = next else {
+            dedup.by_hash.remove(&hash);
+            return;
+        };
+        if was_accounting {
+            let mut lru = self.lru();
+            if let Some(mut info) = lru.remove(&next) {
+                info.deduplicated = false;
+                lru.insert(next, info);
+            }
+        }
+    }
+
+    fn next_tmp_path(&self) -> PathBuf {
+        let next = self.next_tmp_file.fetch_add(1, Ordering::Relaxed);
+        self.root.join(TMP_DIR).join(next.to_string())
+    }
+
+    /// Removes `key` from the cache and the disk and stops announcing it.
+    /// Returns whether it was cached; a key already being evicted or removed
+    /// counts as gone.
+    ///
+    /// The entry stays in the LRU until its file is deleted, so that its room
+    /// cannot be taken by an insert before then, and is left alone if the
+    /// file cannot be deleted.
+    pub async fn remove<S: AsRef<str>>(&self, key: S) -> Result<bool> {
+        {
+            let lru = self.lru();
+            let mut serving = self.serving.state();
+            if !lru.contains_key(key.as_ref()) || serving.evicting.contains(key.as_ref()) {
+                return Ok(false);
+            }
+            serving.evicting.insert(key.as_ref().to_owned());
+            self.reserved.removals.fetch_add(1, Ordering::AcqRel);
+        }
+
+        let path = self.path(key.as_ref());
+        let removed = match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+        {
+            let mut lru = self.lru();
+            self.serving.state().evicting.remove(key.as_ref());
+            if removed.is_ok() {
+                lru.remove(key.as_ref());
+            }
+        }
+        self.reserved.removals_over(1);
+        removed.with_context(|| format!("removing {} for key {}", path.display(), key.as_ref()))?;
+        self.forget_content(key.as_ref());
+        self.unsynced().remove(key.as_ref());
+        self.notifier.removed(key.as_ref().to_owned()).await?;
+        Ok(true)
+    }
+
+    /// Every cached key with its entry, from least to most recently used, as
+    /// listed in a single pass over the LRU.
+    pub fn entries(&self) -> Vec<(String, EntryInfo)> {
+        self.lru()
+            .iter()
+            .map(|(key, info)| (key.clone(), *info))
+            .collect()
+    }
+
+    /// Cached keys starting with `prefix`, from least to most recently used.
+    pub fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.lru()
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Removes every key starting with `prefix`, returning how many were
+    /// removed.
+    pub async fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        let mut removed = 0;
+        for key in self.keys_with_prefix(prefix) {
+            if self.remove(&key).await? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Evicts up to [`DiskCache::set_files_to_evict`] files, returning how
+    /// many are gone.
+    ///
+    /// The picked entries stay in the LRU until their files are deleted, so
+    /// that their room cannot be taken by an insert before then, and those
+    /// whose file cannot be deleted stay where they were, still the first to
+    /// go.
+    async fn evict(&self) -> Result<usize> {
+        let files_to_evict = {
+            let lru = self.lru();
+            // Held until the picked keys are marked as evicting, so that no
+            // read of them can start in between.
+            let mut serving = self.serving.state();
+            let picked = {
+                let candidates = lru
+                    .iter()
+                    .filter(|(key, info)| {
+                        !info.pinned
+                            && !serving.reads.contains_key(key.as_str())
+                            && !serving.evicting.contains(key.as_str())
+                    })
+                    .map(|(key, info)| (key.clone(), *info));
+                // LRU order is what the underlying cache keeps anyway, least
+                // recently used first.
+                match self.eviction_policy {
+                    EvictionPolicy::Lru => candidates
+                        .take(self.files_to_evict as usize)
+                        .map(|(key, _)| key)
+                        .collect::<Vec<_>>(),
+                    policy => {
+                        let mut ordered = policy.order(candidates.collect(), self.ttl);
+                        ordered.truncate(self.files_to_evict as usize);
+                        ordered
+                    }
+                }
+            };
+            serving.evicting.extend(picked.iter().cloned());
+            self.reserved
+                .removals
+                .fetch_add(picked.len() as u64, Ordering::AcqRel);
+            picked
+                .into_iter()
+                .map(|key| {
+                    let path = self.path(&key);
+                    (key, path)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let deleted = stream::iter(files_to_evict)
+            .map(|(key, path)| async move {
+                let deleted = match tokio::fs::remove_file(path).await {
+                    Ok(()) => true,
+                    Err(e) => e.kind() == std::io::ErrorKind::NotFound,
+                };
+                (key, deleted)
+            })
+            .buffer_unordered(MAX_CONCURRENT_REMOVALS)
+            .collect::<Vec<_>>()
+            .await;
+
+        let removals = deleted.len() as u64;
+        let removed = {
+            let mut lru = self.lru();
+            let mut serving = self.serving.state();
+            deleted
+                .into_iter()
+                .filter_map(|(key, deleted)| {
+                    serving.evicting.remove(&key);
+                    let info = if deleted { lru.remove(&key) } else { None };
+                    info.map(|info| (key, info))
+                })
+                .collect::<Vec<_>>()
+        };
+        self.reserved.removals_over(removals);
+        let evicted = removed.len();
+        for (key, info) in &removed {
+            self.forget_content(key);
+            if let Some(on_evict) = &self.on_evict {
+                on_evict(key, info.size);
+            }
+        }
+        // Every removal is notified of, even after one failed.
+        stream::iter(removed)
+            .map(|(key, _)| self.notifier.removed(key))
+            .buffer_unordered(MAX_CONCURRENT_REMOVALS)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<()>>()?;
+
+        Ok(evicted)
+    }
+}
+
+impl<N, H: BuildHasher> Drop for DiskCache<N, H> {
+    /// Hands what the cache held back to the shared capacity.
+    fn drop(&mut self) {
+        if let Some(share) = &mut self.share {
+            let reported = *share.reported.get_mut();
+            share.resize(reported, 0);
+            // Nothing is held anymore, but the reserved bytes still count.
+            share.shared.charge(share.reserved, 0);
+        }
+    }
+}
+
+/// Identifies the file behind `path`, so that hardlinks to it count once.
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata, _: &Path) -> (u64, u64, Option<PathBuf>) {
+    use std::os::unix::fs::MetadataExt;
+
+    (metadata.dev(), metadata.ino(), None)
+}
+
+/// Hardlinks cannot be told apart without inode numbers, so every path
+/// counts.
+#[cfg(not(unix))]
+fn file_id(_: &std::fs::Metadata, path: &Path) -> (u64, u64, Option<PathBuf>) {
+    (0, 0, Some(path.to_owned()))
+}
+
+fn is_expired(info: &EntryInfo, ttl: Option<Duration>) -> bool {
+    ttl.is_some_and(|ttl| info.inserted_at.elapsed().is_ok_and(|age| age >= ttl))
+}
+
+async fn write_file(path: &Path, buf: &[u8], durable: bool) -> std::io::Result<()> {
+    if !durable {
+        return tokio::fs::write(path, buf).await;
+    }
+    let mut file = tokio::fs::File::create(path).await?;
+    file.write_all(buf).await?;
+    file.sync_all().await
+}
+
+async fn checksum_reader<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<[u8; 32]> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(*hasher.finalize().as_bytes());
+        }
+        hasher.update(&buf[..read]);
+    }
+}
+
+/// Fsyncs a directory so that the entries created in it are durable.
+async fn sync_dir(dir: &Path) -> Result<()> {
+    async { tokio::fs::File::open(dir).await?.sync_all().await }
+        .await
+        .with_context(|| format!("syncing directory {}", dir.display()))
 }
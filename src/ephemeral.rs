@@ -0,0 +1,101 @@
+//! Files served to peers from memory, never written to disk, see
+//! [`crate::Disca::add_ephemeral`].
+
+use std::{
+    collections::hash_map::RandomState,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use anyhow::{bail, Result};
+use sccache::lru_disk_cache::{LruCache, Meter};
+
+/// Counts the bytes of content held.
+struct ContentMeter;
+
+impl<K> Meter<K, Vec<u8>> for ContentMeter {
+    type Measure = usize;
+
+    fn measure<Q: ?Sized>(&self, _: &Q, value: &Vec<u8>) -> Self::Measure
+    where
+        K: std::borrow::Borrow<Q>,
+    {
+        value.len()
+    }
+}
+
+type Files = LruCache<String, Vec<u8>, RandomState, ContentMeter>;
+
+/// The ephemeral files of a node, least recently served first out once
+/// they outgrow their capacity. Clones share the files.
+#[derive(Clone)]
+pub(crate) struct EphemeralFiles {
+    files: Arc<Mutex<Files>>,
+}
+
+impl EphemeralFiles {
+    pub(crate) fn new(capacity: u64) -> Self {
+        Self {
+            files: Arc::new(Mutex::new(LruCache::with_meter(capacity, ContentMeter))),
+        }
+    }
+
+    fn files(&self) -> MutexGuard<'_, Files> {
+        self.files.lock().expect("ephemeral files lock poisoned")
+    }
+
+    /// Holds `content` as `key`, replacing what it held before. Returns the
+    /// keys dropped to make room, which are no longer served.
+    pub(crate) fn insert(&self, key: &str, content: Vec<u8>) -> Result<Vec<String>> {
+        let mut files = self.files();
+        if content.len() as u64 > files.capacity() {
+            bail!(
+                "{} bytes do not fit in the {} bytes held in memory for ephemeral files",
+                content.len(),
+                files.capacity()
+            );
+        }
+        files.remove(key);
+        let mut dropped = Vec::new();
+        while files.size() + content.len() as u64 > files.capacity() {
+            match files.remove_lru() {
+                Some((key, _)) => dropped.push(key),
+                None => break,
+            }
+        }
+        files.insert(key.to_owned(), content);
+        Ok(dropped)
+    }
+
+    /// Changes how many bytes of content are held. Returns the keys dropped
+    /// to fit, which are no longer served.
+    pub(crate) fn set_capacity(&self, capacity: u64) -> Vec<String> {
+        let mut files = self.files();
+        let mut dropped = Vec::new();
+        while files.size() > capacity {
+            match files.remove_lru() {
+                Some((key, _)) => dropped.push(key),
+                None => break,
+            }
+        }
+        files.set_capacity(capacity);
+        dropped
+    }
+
+    /// The content of `key`, which counts as it being served.
+    pub(crate) fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.files().get(key).cloned()
+    }
+
+    pub(crate) fn remove(&self, key: &str) -> Option<Vec<u8>> {
+        self.files().remove(key)
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        self.files().contains_key(key)
+    }
+
+    /// Bytes of content held.
+    pub(crate) fn size(&self) -> u64 {
+        self.files().size()
+    }
+}
@@ -0,0 +1,30 @@
+use std::fmt;
+
+/// Failures callers may want to tell apart from the rest, found by
+/// downcasting the [`anyhow::Error`] returned, e.g.
+/// `error.downcast_ref::<DiscaError>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DiscaError {
+    /// The task running the network side of the node is gone, most likely
+    /// because it panicked. Nothing that needs the network works anymore;
+    /// the node has to be restarted.
+    EventLoopDead,
+    /// Peers cannot reach the node, so files added would not be announced,
+    /// see [`crate::NetworkConfig::require_reachable_to_announce`].
+    Unreachable,
+}
+
+impl fmt::Display for DiscaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscaError::EventLoopDead => write!(f, "the disca event loop is no longer running"),
+            DiscaError::Unreachable => write!(
+                f,
+                "peers cannot reach this node, so it does not announce files"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DiscaError {}
@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use libp2p::{kad::QueryId, request_response::RequestId, Multiaddr, PeerId};
+
+use crate::Reachability;
+
+/// How many events a slow subscriber can fall behind before it starts
+/// missing them.
+pub(crate) const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Something that happened inside a node, delivered to everyone who called
+/// [`crate::Disca::subscribe`]. Events are sent without waiting for
+/// subscribers, so the node never blocks on them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DiscaEvent {
+    /// Identify or mDNS told us about a peer and its address was added to
+    /// the routing table.
+    PeerDiscovered { peer_id: PeerId, addr: Multiaddr },
+    /// A cached file was evicted to make room, freeing `size` bytes.
+    Evicted { key: String, size: u64 },
+    /// AutoNAT came to a new conclusion about whether peers can dial us.
+    ReachabilityChanged { reachability: Reachability },
+    /// A get of `key` missed the cached providers and started a DHT lookup,
+    /// the one libp2p's Kademlia logs as `query_id`.
+    ProvidersLookupStarted { key: String, query_id: QueryId },
+    /// The lookup `query_id` for `key` came to an end, after `duration`,
+    /// having sent `requests` requests to other peers and found `providers`
+    /// providers. Ends as soon as one provider is found.
+    ProvidersLookupFinished {
+        key: String,
+        query_id: QueryId,
+        duration: Duration,
+        requests: u32,
+        providers: usize,
+    },
+    /// A get of `key` asked `peer_id` for the file, in the request libp2p
+    /// logs as `request_id`. Sent for every provider tried, in order.
+    FileRequested {
+        key: String,
+        peer_id: PeerId,
+        request_id: RequestId,
+    },
+    /// The answer to the request of `peer_id` for `key` was ready after the
+    /// request was over, e.g. because the peer timed out or went away, and
+    /// was dropped.
+    ResponseDropped { key: String, peer_id: PeerId },
+}
@@ -1,143 +1,968 @@
-use std::collections::HashSet;
+use std::{
+    borrow::BorrowMut,
+    collections::{HashMap, HashSet, VecDeque},
+    num::NonZeroUsize,
+    ops::Range,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::FileProvider;
-use anyhow::Result;
+use crate::{
+    chunking::{self, Manifest},
+    codec::{self, FileCodec},
+    command::{CommandReply, CommandRequest},
+    compression::{self, CompressionAlgorithm},
+    config::{KadMode, NetworkConfig, ProvideRetry},
+    error::DiscaError,
+    events::{DiscaEvent, EVENT_CHANNEL_CAPACITY},
+    listen_addr,
+    metrics::LatencyRecorder,
+    reputation::{Outcome, Reputation},
+    resume::PartialFile,
+    selector::{ProviderInfo, ProviderSelector},
+    signing::{self, ContentSignature, HashAlgorithm},
+    FileProvider,
+};
+use anyhow::{bail, Context, Result};
 use dashmap::DashMap;
-use either::Either;
 use futures::StreamExt;
 use libp2p::{
+    autonat, connection_limits,
+    core::multiaddr::Protocol,
     identify,
-    kad::{self, QueryId, QueryResult},
+    identity::Keypair,
+    kad::{self, store::RecordStore, QueryId, QueryResult},
+    mdns, ping,
     request_response::{self, Message, ProtocolSupport, RequestId},
-    swarm::{NetworkBehaviour, SwarmEvent},
-    Multiaddr, PeerId, StreamProtocol, Swarm, SwarmBuilder,
+    swarm::{
+        behaviour::toggle::Toggle, dial_opts::DialOpts, ConnectionId, NetworkBehaviour, SwarmEvent,
+    },
+    Multiaddr, PeerId, Swarm, SwarmBuilder,
 };
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use tokio::{io, select};
+use tokio::select;
 
 #[derive(Debug, Clone)]
 pub struct FileSharingP2P {
     command_sender: tokio::sync::mpsc::UnboundedSender<Command>,
     peer_id: PeerId,
     addr: Multiaddr,
+    listen_addrs: Arc<RwLock<Vec<Multiaddr>>>,
+    reachability: Arc<RwLock<Reachability>>,
+    connected_peers: tokio::sync::watch::Receiver<usize>,
+    reputation: Reputation,
+    keypair: Keypair,
+    hash_algorithm: HashAlgorithm,
+    signatures: Arc<DashMap<String, ContentSignature>>,
+    events: tokio::sync::broadcast::Sender<DiscaEvent>,
+    local_fallback: bool,
+    /// After which the transport times requests out, whatever the request
+    /// timeout was changed to since.
+    transport_request_timeout: Duration,
+    /// Where the event loop records how long provider lookups take.
+    pub(crate) latencies: LatencyRecorder,
+    /// See [`NetworkConfig::require_reachable_to_announce`].
+    require_reachable: Arc<AtomicBool>,
+}
+
+/// The result of looking a file up on the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GetOutcome<T> {
+    /// A provider returned the file.
+    Found(T),
+    /// Nobody is advertising the key.
+    NoProviders,
+    /// Some peers advertise the key, but none of them returned it.
+    ProvidersHadNothing { peers: Vec<PeerId> },
+    /// Every provider failed part way through sending the file, and
+    /// [`NetworkConfig::allow_partial`] is on. Never cached.
+    Partial(PartialContent),
+}
+
+/// What came of a file fetched in chunks or ranges before the fetch failed,
+/// see [`NetworkConfig::allow_partial`]. Chunks were checked against the
+/// manifest, but the content as a whole was neither checked against its
+/// signature nor passed to [`NetworkConfig::on_fetch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialContent {
+    /// The file as far as it came, with the bytes that did not zeroed.
+    pub content: Vec<u8>,
+    /// The ranges of `content` that did not come, in order.
+    pub missing: Vec<Range<u64>>,
+    /// Whether the file goes on past `content`, as when a fetch in ranges
+    /// broke off: how long it is then is unknown.
+    pub truncated: bool,
+}
+
+impl PartialContent {
+    /// Number of bytes that came.
+    pub fn received(&self) -> u64 {
+        let missing: u64 = self
+            .missing
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum();
+        self.content.len() as u64 - missing
+    }
+}
+
+impl<T> GetOutcome<T> {
+    pub fn into_option(self) -> Option<T> {
+        match self {
+            GetOutcome::Found(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// Checks or rewrites fetched content before it is handed over, see
+/// [`NetworkConfig::on_fetch`]. Clones share the function.
+#[derive(Clone)]
+pub struct FetchInterceptor(Arc<InterceptFn>);
+
+type InterceptFn = dyn Fn(&str, Vec<u8>) -> Result<Vec<u8>> + Send + Sync;
+
+impl FetchInterceptor {
+    /// `f` is called with the key and content of every file fetched, and
+    /// returns the content to keep or an error to reject it.
+    pub fn new(f: impl Fn(&str, Vec<u8>) -> Result<Vec<u8>> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for FetchInterceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FetchInterceptor")
+    }
+}
+
+/// Decides which file requests are served, from the peer asking and the
+/// token it sent along, if any, see [`NetworkConfig::token_validator`].
+/// Clones share the function.
+#[derive(Clone)]
+pub struct TokenValidator(Arc<ValidateFn>);
+
+type ValidateFn = dyn Fn(&PeerId, Option<&str>) -> bool + Send + Sync;
+
+impl TokenValidator {
+    /// `f` is called with the peer and token of every file request, and
+    /// returns whether to serve it.
+    pub fn new(f: impl Fn(&PeerId, Option<&str>) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for TokenValidator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TokenValidator")
+    }
+}
+
+/// A snapshot of the local Kademlia routing table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KadStats {
+    /// Peers across all buckets.
+    pub known_peers: usize,
+    /// Occupancy of every non-empty bucket.
+    pub buckets: Vec<BucketStats>,
+}
+
+impl KadStats {
+    /// Whether the routing table knows any peer at all. Provider lookups on a
+    /// cold table cannot find anything, regardless of who holds the key.
+    pub fn is_warm(&self) -> bool {
+        self.known_peers > 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketStats {
+    /// Base 2 logarithm of the distances covered by the bucket.
+    pub index: u32,
+    pub entries: usize,
+    /// Whether a peer is waiting for a slot in the full bucket.
+    pub has_pending: bool,
+}
+
+/// Whether other nodes can connect to this one, as determined by AutoNAT
+/// dial-backs from connected peers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Reachability {
+    /// Not enough peers have tried to dial us yet.
+    #[default]
+    Unknown,
+    /// Peers could dial us at this address.
+    Public(Multiaddr),
+    /// Peers could not dial us, e.g. because we are behind a NAT.
+    Private,
+}
+
+impl From<autonat::NatStatus> for Reachability {
+    fn from(status: autonat::NatStatus) -> Self {
+        match status {
+            autonat::NatStatus::Public(addr) => Reachability::Public(addr),
+            autonat::NatStatus::Private => Reachability::Private,
+            autonat::NatStatus::Unknown => Reachability::Unknown,
+        }
+    }
+}
+
+/// How much of the network a node can use, see
+/// [`FileSharingP2P::network_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkStatus {
+    /// Connected to at least one peer.
+    Online,
+    /// Listening, but not connected to any peer, so that gets only find
+    /// what is cached locally.
+    Isolated,
+    /// Not listening on any address, or the event loop is gone.
+    Offline,
+}
+
+/// What a peer could tell about how this node is reached from the outside.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelfTestReport {
+    /// Whether the peer could dial this node back, `None` if it gave no
+    /// verdict in time.
+    pub reachable: Option<bool>,
+    /// The address the peer sees this node's connections coming from.
+    pub observed_addr: Option<Multiaddr>,
+    /// Round-trip time of a ping to the peer.
+    pub rtt: Option<Duration>,
 }
 
+/// A request for a file, as found in [`FileSharingBehaviourEvent`]s.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-struct FileRequest {
-    path: String,
+pub struct FileRequest {
+    pub(crate) path: String,
+    /// Only these bytes of the file are wanted. Signatures cover whole files,
+    /// so ranged responses come without one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) range: Option<Range<u64>>,
+    /// Compressions the content may come in, besides none at all, which is
+    /// always accepted. Ignored for ranges.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) accept_encodings: Vec<CompressionAlgorithm>,
+    /// See [`NetworkConfig::auth_token`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) auth_token: Option<String>,
 }
 
+/// The answer to a [`FileRequest`].
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-struct FileResponse {
-    content: Option<Vec<u8>>,
+pub struct FileResponse {
+    pub(crate) content: Option<Vec<u8>>,
+    #[serde(default)]
+    pub(crate) signature: Option<ContentSignature>,
+    /// How `content` is compressed, one of the accepted encodings. The
+    /// signature covers the content decompressed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) encoding: Option<CompressionAlgorithm>,
+    /// Whether `content` is the manifest of the file rather than the file,
+    /// see [`NetworkConfig::chunking`]. The signature covers the file.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) manifest: bool,
+    /// Whether the file was left out as it does not fit in a response, see
+    /// [`NetworkConfig::fetch_in_ranges`]. The signature still comes along.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) too_large: bool,
+    /// Whether the request was turned down for its token, see
+    /// [`NetworkConfig::token_validator`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) denied: bool,
 }
 
+/// The protocols a node runs: file exchange, Kademlia for finding
+/// providers, identify, connection limits, ping, AutoNAT and, if enabled,
+/// mDNS.
+///
+/// [`FileSharingP2P::with_config`] runs it in a swarm of its own. To share a
+/// swarm with other protocols instead, see [`FileSharingP2P::embedded`].
 #[derive(NetworkBehaviour)]
-struct Behaviour {
-    request_response: request_response::cbor::Behaviour<FileRequest, FileResponse>,
+pub struct FileSharingBehaviour {
+    request_response: request_response::Behaviour<FileCodec>,
     kademlia: kad::Behaviour<kad::store::MemoryStore>,
     identify: identify::Behaviour,
+    connection_limits: connection_limits::Behaviour,
+    ping: ping::Behaviour,
+    autonat: autonat::Behaviour,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+}
+
+impl FileSharingBehaviour {
+    /// Sets the protocols up as configured. `keypair` has to be the identity
+    /// of the swarm the behaviour runs in.
+    pub fn new(config: &NetworkConfig, keypair: &Keypair) -> Result<Self> {
+        let peer_id = keypair.public().to_peer_id();
+        let mut request_response_config = request_response::Config::default();
+        request_response_config.set_request_timeout(config.request_timeout);
+        let identify = &config.identify;
+        let limits = &config.connection_limits;
+        let mut kad_config = kad::Config::default();
+        kad_config
+            .set_parallelism(NonZeroUsize::new(config.kad_parallelism.max(1)).expect("at least 1"));
+        let mut behaviour = Self {
+            kademlia: kad::Behaviour::with_config(
+                peer_id,
+                kad::store::MemoryStore::new(peer_id),
+                kad_config,
+            ),
+            request_response: request_response::Behaviour::with_codec(
+                FileCodec::new(config.wire_format),
+                [(config.wire_format.protocol(), ProtocolSupport::Full)],
+                request_response_config,
+            ),
+            identify: identify::Behaviour::new(
+                identify::Config::new(identify.protocol_version.clone(), keypair.public())
+                    .with_agent_version(identify.agent_version.clone())
+                    .with_interval(identify.interval)
+                    .with_push_listen_addr_updates(identify.push_listen_addr_updates)
+                    .with_cache_size(identify.cache_size),
+            ),
+            connection_limits: connection_limits::Behaviour::new(
+                connection_limits::ConnectionLimits::default()
+                    .with_max_established_incoming(limits.max_established_incoming)
+                    .with_max_established_outgoing(limits.max_established_outgoing)
+                    .with_max_pending_incoming(limits.max_pending_incoming)
+                    .with_max_pending_outgoing(limits.max_pending_outgoing),
+            ),
+            ping: ping::Behaviour::default(),
+            autonat: autonat::Behaviour::new(
+                peer_id,
+                autonat::Config {
+                    // Private deployments are a common use case.
+                    only_global_ips: false,
+                    ..Default::default()
+                },
+            ),
+            mdns: config
+                .mdns
+                .then(|| mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id))
+                .transpose()
+                .context("starting mDNS")?
+                .into(),
+        };
+        for (parent, addr) in parent_peers(config)? {
+            behaviour.request_response.add_address(&parent, addr);
+        }
+        for (peer, addr) in peer_addrs(&config.seed_peers, "seed")? {
+            behaviour.kademlia.add_address(&peer, addr);
+        }
+        let mode = match config.kad_mode {
+            _ if config.read_only => kad::Mode::Client,
+            KadMode::Server | KadMode::Auto => kad::Mode::Server,
+            KadMode::Client => kad::Mode::Client,
+        };
+        behaviour.kademlia.set_mode(Some(mode));
+        Ok(behaviour)
+    }
+}
+
+/// A behaviour containing a [`FileSharingBehaviour`], e.g. the behaviour of
+/// an application embedding disca. Implemented for anything that borrows
+/// one, including [`FileSharingBehaviour`] itself.
+pub trait Host: NetworkBehaviour + BorrowMut<FileSharingBehaviour> {}
+
+impl<B: NetworkBehaviour + BorrowMut<FileSharingBehaviour>> Host for B {}
+
+trait SwarmExt {
+    fn disca(&mut self) -> &mut FileSharingBehaviour;
+}
+
+impl<B: Host> SwarmExt for Swarm<B> {
+    fn disca(&mut self) -> &mut FileSharingBehaviour {
+        self.behaviour_mut().borrow_mut()
+    }
+}
+
+/// The addresses of every peer in the routing table, ending with its id.
+fn known_peers<B: Host>(swarm: &mut Swarm<B>) -> Vec<Multiaddr> {
+    let mut addrs = Vec::new();
+    for bucket in swarm.disca().kademlia.kbuckets() {
+        for entry in bucket.iter() {
+            let peer = *entry.node.key.preimage();
+            for addr in entry.node.value.iter() {
+                let mut addr = addr.clone();
+                if !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+                    addr.push(Protocol::P2p(peer));
+                }
+                addrs.push(addr);
+            }
+        }
+    }
+    addrs
+}
+
+/// The peers configured as parents, with the address to reach them at.
+fn parent_peers(config: &NetworkConfig) -> Result<Vec<(PeerId, Multiaddr)>> {
+    peer_addrs(&config.parent_peers, "parent")
+}
+
+/// The peers `addrs` end with, with the address to reach them at. `what`
+/// kind of peers they are goes in the error.
+fn peer_addrs(addrs: &[Multiaddr], what: &str) -> Result<Vec<(PeerId, Multiaddr)>> {
+    addrs
+        .iter()
+        .map(|addr| match addr.iter().last() {
+            Some(Protocol::P2p(peer)) => Ok((peer, addr.clone())),
+            _ => Err(anyhow::anyhow!(
+                "{} peer address {} does not end with /p2p/<peer id>",
+                what,
+                addr
+            )),
+        })
+        .collect()
 }
 
 impl FileSharingP2P {
-    pub async fn new<T: FileProvider + Send + 'static + Sync>(
+    pub async fn new<T: FileProvider + Clone + Send + Sync + 'static>(
         addr: Multiaddr,
         file_provider: T,
     ) -> Result<Self> {
-        let mut swarm = SwarmBuilder::with_new_identity()
+        let config = NetworkConfig {
+            listen_addrs: vec![addr],
+            ..Default::default()
+        };
+        Self::with_config(config, file_provider).await
+    }
+
+    pub async fn with_config<T: FileProvider + Clone + Send + Sync + 'static>(
+        config: NetworkConfig,
+        file_provider: T,
+    ) -> Result<Self> {
+        let keypair = config
+            .keypair
+            .clone()
+            .unwrap_or_else(Keypair::generate_ed25519);
+        let behaviour = FileSharingBehaviour::new(&config, &keypair)?;
+        let (mut file_sharing, mut event_loop) =
+            Self::parts(&config, keypair.clone(), file_provider)?;
+        if config.local_fallback {
+            event_loop.reconnect = Some(Reconnect {
+                listen_addrs: config.listen_addrs.clone(),
+                bootstrap_peers: config.bootstrap_peers.clone(),
+                next_attempt: Instant::now() + RECONNECT_INTERVAL,
+                offline: false,
+            });
+        }
+        let swarm = SwarmBuilder::with_existing_identity(keypair)
             .with_tokio()
-            .with_quic()
-            .with_behaviour(|key| Behaviour {
-                kademlia: kad::Behaviour::new(
-                    key.public().to_peer_id(),
-                    kad::store::MemoryStore::new(key.public().to_peer_id()),
-                ),
-                request_response: request_response::cbor::Behaviour::new(
-                    [(
-                        StreamProtocol::new("/file-exchange/1"),
-                        ProtocolSupport::Full,
-                    )],
-                    request_response::Config::default(),
-                ),
-                identify: identify::Behaviour::new(identify::Config::new(
-                    "disca/v1".to_string(),
-                    key.public(),
-                )),
+            // `with_quic_config` is only offered once TCP is skipped, which
+            // libp2p keeps to itself.
+            .with_other_transport(|keypair| {
+                libp2p::quic::tokio::Transport::new(
+                    config.quic.apply(libp2p::quic::Config::new(keypair)),
+                )
             })?
+            .with_behaviour(|_| behaviour)?
             .build();
+        tokio::spawn(event_loop.run(swarm));
 
-        let peer_id = swarm.local_peer_id().clone();
-        swarm
-            .behaviour_mut()
-            .kademlia
-            .set_mode(Some(kad::Mode::Server));
+        if config.listen_addrs.is_empty() {
+            bail!("no listen address configured");
+        }
+        for addr in config.listen_addrs {
+            let listening = file_sharing
+                .listen_on(addr.clone())
+                .await
+                .with_context(|| format!("listening on {}", addr));
+            match listening {
+                Ok(addr) if file_sharing.addr.is_empty() => file_sharing.addr = addr,
+                Ok(_) => {}
+                // The event loop tries again later.
+                Err(_) if config.local_fallback => {}
+                Err(e) => return Err(e),
+            }
+        }
+        // Queued all at once, the event loop keeps the dials in flight bounded.
+        let dialed =
+            futures::future::try_join_all(config.bootstrap_peers.into_iter().map(|addr| {
+                let mut file_sharing = file_sharing.clone();
+                async move { file_sharing.add_peer(addr).await }
+            }))
+            .await;
+        if let Err(e) = dialed {
+            if !config.local_fallback {
+                return Err(e);
+            }
+        }
 
-        let (command_sender, command_receiver) = tokio::sync::mpsc::unbounded_channel();
+        Ok(file_sharing)
+    }
 
-        tokio::spawn(async move {
-            let mut event_loop = EventLoop {
-                swarm,
-                command_receiver,
-                file_provider,
-                pending_start_providing: Default::default(),
-                pending_get_providers: Default::default(),
-                pending_get_file: Default::default(),
-                pending_start_listening: Default::default(),
-            };
-            event_loop.run().await;
-        });
+    /// Sets disca up inside a swarm owned by the application, next to its
+    /// own protocols, instead of in a swarm of its own.
+    ///
+    /// The returned behaviour has to become part of the swarm's behaviour,
+    /// which lends it out through [`BorrowMut`] (see [`Host`]), and the swarm
+    /// has to use the keypair from `config` as its identity, which is
+    /// therefore required. The application then drives disca from its event
+    /// loop with the returned [`FileSharingDriver`]. It also listens and
+    /// dials on the swarm itself: the listen addresses and bootstrap peers in
+    /// `config` are ignored, and [`FileSharingP2P::addr`] stays empty, unlike
+    /// [`FileSharingP2P::listen_addrs`].
+    pub fn embedded<T: FileProvider + Clone + Send + 'static>(
+        config: NetworkConfig,
+        file_provider: T,
+    ) -> Result<(Self, FileSharingBehaviour, FileSharingDriver<T>)> {
+        let keypair = config
+            .keypair
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("embedding needs the keypair of the swarm"))?;
+        let behaviour = FileSharingBehaviour::new(&config, &keypair)?;
+        let (file_sharing, event_loop) = Self::parts(&config, keypair, file_provider)?;
+        Ok((file_sharing, behaviour, FileSharingDriver { event_loop }))
+    }
 
-        let (addr_sender, addr_receiver) = tokio::sync::oneshot::channel();
-        command_sender.send(Command::StartListening {
-            addr: addr.clone(),
-            sender: addr_sender,
-        })?;
-        let addr = addr_receiver.await??;
+    /// A handle and the event loop it talks to, not running yet.
+    fn parts<T: FileProvider + Clone + Send + 'static>(
+        config: &NetworkConfig,
+        keypair: Keypair,
+        file_provider: T,
+    ) -> Result<(Self, EventLoop<T>)> {
+        let parent_peers = parent_peers(config)?
+            .into_iter()
+            .map(|(parent, _)| parent)
+            .collect();
+        let (command_sender, command_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (done_sender, done_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let listen_addrs: Arc<RwLock<Vec<Multiaddr>>> = Default::default();
+        let reachability: Arc<RwLock<Reachability>> = Default::default();
+        let (connected_peers_sender, connected_peers) = tokio::sync::watch::channel(0);
+        let reputation = Reputation::default();
+        let signatures: Arc<DashMap<String, ContentSignature>> = Default::default();
+        let (events, _) = tokio::sync::broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let latencies = LatencyRecorder::default();
 
-        Ok(FileSharingP2P {
+        let event_loop = EventLoop {
+            command_receiver,
+            sweep: None,
+            file_provider,
+            pending_start_providing: Default::default(),
+            provide_retries: Default::default(),
+            pending_get_providers: Default::default(),
+            lookups: Default::default(),
+            waiting_for_providers: Default::default(),
+            pending_exists: Default::default(),
+            pending_get_file: Default::default(),
+            hedged: Default::default(),
+            request_timeout: config.request_timeout,
+            pending_start_listening: Default::default(),
+            listen_addrs: listen_addrs.clone(),
+            reachability: reachability.clone(),
+            listeners: Default::default(),
+            provider_cache: Default::default(),
+            provider_cache_ttl: config.provider_cache_ttl,
+            signatures: signatures.clone(),
+            checker: Checker {
+                signatures: signatures.clone(),
+                trusted_publishers: Arc::new(config.trusted_publishers.iter().copied().collect()),
+                on_fetch: config.on_fetch.clone(),
+            },
+            parent_peers,
+            // Kept in client mode whatever AutoNAT finds.
+            kad_mode: if config.read_only {
+                KadMode::Client
+            } else {
+                config.kad_mode
+            },
+            provider_selector: config.provider_selector.clone(),
+            rtts: Default::default(),
+            connected_peers: connected_peers_sender,
+            reputation: reputation.clone(),
+            events: events.clone(),
+            latencies: latencies.clone(),
+            observed_addrs: Default::default(),
+            pending_self_tests: Default::default(),
+            unannounced: None,
+            require_reachable: config.require_reachable_to_announce,
+            held_back: Default::default(),
+            accept_encodings: config.accept_encodings.clone(),
+            dial_queue: Default::default(),
+            dials_in_flight: Default::default(),
+            max_concurrent_dials: config.max_concurrent_dials.max(1),
+            reconnect: None,
+            chunking: config.chunking.is_some(),
+            fetch_in_ranges: config.fetch_in_ranges,
+            offload: config.offload,
+            allow_partial: config.allow_partial,
+            partial_dir: config.partial_dir.clone().unwrap_or_else(|| {
+                std::env::temp_dir()
+                    .join("disca")
+                    .join(keypair.public().to_peer_id().to_string())
+            }),
+            hedge_count: config.hedge_count.max(1),
+            provide_retry: config.provide_retry.clone(),
+            read_only: config.read_only,
+            auth_token: config.auth_token.clone(),
+            token_validator: config.token_validator.clone(),
+            done_sender,
+            done_receiver,
+        };
+        let file_sharing = FileSharingP2P {
             command_sender,
-            peer_id,
-            addr,
-        })
+            peer_id: keypair.public().to_peer_id(),
+            addr: Multiaddr::empty(),
+            listen_addrs,
+            reachability,
+            connected_peers,
+            reputation,
+            keypair,
+            hash_algorithm: config.hash_algorithm,
+            signatures,
+            events,
+            local_fallback: config.local_fallback,
+            transport_request_timeout: config.request_timeout,
+            latencies,
+            require_reachable: Arc::new(AtomicBool::new(config.require_reachable_to_announce)),
+        };
+        Ok((file_sharing, event_loop))
     }
 
-    pub async fn add_file(&self, path: String) -> Result<()> {
-        let (sender, receiver) = tokio::sync::oneshot::channel();
-        self.command_sender
-            .send(Command::AddFile { path, sender })?;
-        receiver.await?
+    pub async fn add_file(&self, path: impl Into<String>) -> Result<()> {
+        let path = path.into();
+        self.request(|sender| Command::AddFile { path, sender })
+            .await?
     }
 
-    pub async fn remove_file(&self, path: String) -> Result<()> {
-        let (sender, receiver) = tokio::sync::oneshot::channel();
-        self.command_sender
-            .send(Command::RemoveFile { path, sender })?;
-        receiver.await?
+    pub async fn remove_file(&self, path: impl Into<String>) -> Result<()> {
+        let path = path.into();
+        self.request(|sender| Command::RemoveFile { path, sender })
+            .await?
     }
 
-    pub async fn get_file(&mut self, path: String) -> Result<Option<Vec<u8>>> {
-        let (sender, receiver) = tokio::sync::oneshot::channel();
-        self.command_sender
-            .send(Command::GetFile { path, sender })?;
-        receiver.await?
+    pub async fn get_file(&mut self, path: impl Into<String>) -> Result<Option<Vec<u8>>> {
+        Ok(self.fetch_file(path).await?.into_option())
+    }
+
+    pub async fn fetch_file(&mut self, path: impl Into<String>) -> Result<GetOutcome<Vec<u8>>> {
+        let path = path.into();
+        self.request(|sender| Command::GetFile { path, sender })
+            .await?
+    }
+
+    /// Whether any peer announces `path`, without fetching it. Providers
+    /// cached from a recent lookup answer right away; otherwise this returns
+    /// as soon as the DHT lookup finds a provider other than this node. A
+    /// lookup that runs into the Kademlia query timeout before finding one
+    /// counts as nobody having the key. Parent peers are not asked, as they
+    /// announce nothing.
+    pub async fn exists(&self, path: impl Into<String>) -> Result<bool> {
+        let path = path.into();
+        self.request(|sender| Command::Exists { path, sender })
+            .await?
+    }
+
+    /// Stops announcing every file, e.g. to drain the node before shutting it
+    /// down: peers stop finding it as a provider, while it keeps its files
+    /// and still answers requests. Announcements other peers already stored
+    /// are not withdrawn and only go away once they expire. Files added in
+    /// the meantime are not announced either, until
+    /// [`FileSharingP2P::announce_all`].
+    pub async fn unannounce_all(&self) -> Result<()> {
+        self.request(|sender| Command::UnannounceAll { sender })
+            .await?
+    }
+
+    /// Announces again every file held back since
+    /// [`FileSharingP2P::unannounce_all`]. Returns once the announcements
+    /// started, without waiting for them to reach the network.
+    pub async fn announce_all(&self) -> Result<()> {
+        self.request(|sender| Command::AnnounceAll { sender })
+            .await?
     }
 
+    /// Asks `peer` for `path` directly, without looking the providers up on
+    /// the DHT first, for when it is known to have the file. A peer this node
+    /// is not connected to is dialed at the addresses learned from the DHT;
+    /// the fetch fails if there are none or it cannot be reached. Parent
+    /// peers are not asked.
+    pub async fn fetch_file_from(
+        &mut self,
+        peer: PeerId,
+        path: impl Into<String>,
+    ) -> Result<GetOutcome<Vec<u8>>> {
+        let path = path.into();
+        self.request(|sender| Command::GetFileFrom {
+            peer,
+            path: path.clone(),
+            sender,
+        })
+        .await?
+        .with_context(|| format!("fetching {} from {}", path, peer))
+    }
+
+    /// Dials `addr`, returning once the dial started. Dials beyond
+    /// [`NetworkConfig::max_concurrent_dials`] wait in line first.
     pub async fn add_peer(&mut self, addr: Multiaddr) -> Result<()> {
+        self.request(|sender| Command::AddPeer { addr, sender })
+            .await?
+    }
+
+    /// Makes `request` of the event loop, like the method of the same name
+    /// would, for requests that arrived as data, e.g. from another process
+    /// sharing this node. See [`crate::CommandClient`] for the other end.
+    pub async fn call(&self, request: CommandRequest) -> Result<CommandReply> {
+        let mut file_sharing = self.clone();
+        Ok(match request {
+            CommandRequest::AddFile { path } => {
+                self.add_file(path).await?;
+                CommandReply::Done
+            }
+            CommandRequest::RemoveFile { path } => {
+                self.remove_file(path).await?;
+                CommandReply::Done
+            }
+            CommandRequest::GetFile { path } => {
+                CommandReply::File(file_sharing.fetch_file(path).await?)
+            }
+            CommandRequest::GetFileFrom { peer, path } => {
+                CommandReply::File(file_sharing.fetch_file_from(peer, path).await?)
+            }
+            CommandRequest::Exists { path } => CommandReply::Exists(self.exists(path).await?),
+            CommandRequest::UnannounceAll => {
+                self.unannounce_all().await?;
+                CommandReply::Done
+            }
+            CommandRequest::AnnounceAll => {
+                self.announce_all().await?;
+                CommandReply::Done
+            }
+            CommandRequest::AddPeer { addr } => {
+                file_sharing.add_peer(addr).await?;
+                CommandReply::Done
+            }
+            CommandRequest::StartListening { addr } => {
+                CommandReply::Listening(self.listen_on(addr).await?)
+            }
+            CommandRequest::StopListening { addr } => {
+                self.stop_listening(addr).await?;
+                CommandReply::Done
+            }
+            CommandRequest::KadStats => CommandReply::KadStats(self.kad_stats().await?),
+            CommandRequest::KnownPeers => CommandReply::Peers(self.known_peers().await?),
+            CommandRequest::SelfTest { peer } => {
+                CommandReply::SelfTest(self.self_test(peer).await?)
+            }
+        })
+    }
+
+    /// Hands the event loop the command built around a reply channel by
+    /// `command` and waits for the reply.
+    async fn request<R>(
+        &self,
+        command: impl FnOnce(tokio::sync::oneshot::Sender<R>) -> Command,
+    ) -> Result<R, DiscaError> {
         let (sender, receiver) = tokio::sync::oneshot::channel();
         self.command_sender
-            .send(Command::AddPeer { addr, sender })?;
-        receiver.await?
+            .send(command(sender))
+            .map_err(|_| DiscaError::EventLoopDead)?;
+        // The event loop drops every reply channel when it dies.
+        receiver.await.map_err(|_| DiscaError::EventLoopDead)
+    }
+
+    /// Takes on the settings of `config` that can change while the node
+    /// runs: the request timeout, which can only be lowered, the provider
+    /// cache time to live, the trusted publishers, the accepted encodings,
+    /// the dial limit, whether files are fetched in ranges or served off
+    /// the event loop, whether partial content is handed over and whether
+    /// announcing requires being reachable. The others are ignored, see
+    /// [`crate::Disca::reconfigure`] for applying a whole configuration.
+    pub async fn reconfigure(&self, config: &NetworkConfig) -> Result<()> {
+        self.check_reconfigure(config)?;
+        self.require_reachable
+            .store(config.require_reachable_to_announce, Ordering::Relaxed);
+        let config = Box::new(config.clone());
+        Ok(self
+            .request(|sender| Command::Reconfigure { config, sender })
+            .await?)
+    }
+
+    /// Fails if [`FileSharingP2P::reconfigure`] cannot take on `config`.
+    pub(crate) fn check_reconfigure(&self, config: &NetworkConfig) -> Result<()> {
+        if config.request_timeout > self.transport_request_timeout {
+            bail!(
+                "request_timeout can only be lowered while the node runs, as the transport \
+                 times requests out after the {:?} it started with",
+                self.transport_request_timeout
+            );
+        }
+        Ok(())
+    }
+
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<DiscaEvent> {
+        self.events.subscribe()
+    }
+
+    pub(crate) fn events(&self) -> tokio::sync::broadcast::Sender<DiscaEvent> {
+        self.events.clone()
+    }
+
+    /// Signs `content` as published by this node, so the signature is served
+    /// alongside the file from now on.
+    pub fn sign_file(&self, key: &str, content: &[u8]) -> Result<()> {
+        let signature = ContentSignature::sign(&self.keypair, self.hash_algorithm, key, content)?;
+        self.signatures.insert(key.to_owned(), signature);
+        Ok(())
+    }
+
+    /// Starts listening on an additional address, returning the first concrete
+    /// address it was bound to.
+    pub async fn listen_on(&self, addr: Multiaddr) -> Result<Multiaddr> {
+        self.request(|sender| Command::StartListening { addr, sender })
+            .await?
+    }
+
+    /// Stops listening on `addr`, which can be either the address passed to
+    /// [`FileSharingP2P::listen_on`] or one of the concrete addresses it bound.
+    /// Established connections are kept.
+    pub async fn stop_listening(&self, addr: Multiaddr) -> Result<()> {
+        self.request(|sender| Command::StopListening { addr, sender })
+            .await?
+    }
+
+    /// Asks `peer` to dial this node back and measures the round trip to it.
+    /// Gives up after [`SELF_TEST_TIMEOUT`], reporting what it learnt so far.
+    pub async fn self_test(&self, peer: PeerId) -> Result<SelfTestReport> {
+        self.request(|sender| Command::SelfTest { peer, sender })
+            .await?
+    }
+
+    pub async fn kad_stats(&self) -> Result<KadStats> {
+        Ok(self.request(|sender| Command::KadStats { sender }).await?)
+    }
+
+    /// Every peer in the routing table, as its addresses ending with
+    /// `/p2p/<peer id>`, e.g. to save them for
+    /// [`NetworkConfig::seed_peers`] on the next start.
+    pub async fn known_peers(&self) -> Result<Vec<Multiaddr>> {
+        Ok(self
+            .request(|sender| Command::KnownPeers { sender })
+            .await?)
+    }
+
+    /// How well `peer` served our fetches lately. Starts at zero, grows with
+    /// every file received from the peer and shrinks with every request it
+    /// had nothing for, failed or answered with content that does not
+    /// verify, the latter the most. Scores decay back towards zero over
+    /// time. Peers with a negative score are asked for files after the
+    /// others, and those far below zero only when no other provider is known.
+    pub fn peer_score(&self, peer: &PeerId) -> f64 {
+        self.reputation.score(peer)
+    }
+
+    /// Number of peers with at least one open connection.
+    pub fn connected_peers(&self) -> usize {
+        *self.connected_peers.borrow()
+    }
+
+    /// Whether the node is listening and connected to peers right now.
+    pub fn network_status(&self) -> NetworkStatus {
+        if self.command_sender.is_closed() || self.listen_addrs().is_empty() {
+            NetworkStatus::Offline
+        } else if self.connected_peers() == 0 {
+            NetworkStatus::Isolated
+        } else {
+            NetworkStatus::Online
+        }
+    }
+
+    /// Whether network failures are to be taken as misses, see
+    /// [`NetworkConfig::local_fallback`].
+    pub(crate) fn local_fallback(&self) -> bool {
+        self.local_fallback
+    }
+
+    /// Waits until at least `min_peers` peers are connected and the routing
+    /// table knows at least one peer, so that provider lookups can find
+    /// something. Fails if that takes longer than `timeout`.
+    pub async fn wait_ready(&self, min_peers: usize, timeout: Duration) -> Result<()> {
+        let mut connected_peers = self.connected_peers.clone();
+        let ready = async {
+            loop {
+                if *connected_peers.borrow_and_update() >= min_peers
+                    && self.kad_stats().await?.is_warm()
+                {
+                    return Ok(());
+                }
+                // Peers enter the routing table some time after connecting,
+                // without any notification, so the table is polled as well.
+                tokio::select! {
+                    changed = connected_peers.changed() => changed?,
+                    _ = tokio::time::sleep(READY_POLL_INTERVAL) => {}
+                }
+            }
+        };
+        tokio::time::timeout(timeout, ready).await.map_err(|_| {
+            anyhow::anyhow!(
+                "fewer than {} peers connected or an empty routing table after {:?}",
+                min_peers,
+                timeout
+            )
+        })?
     }
 
     pub fn peer_id(&self) -> &PeerId {
         &self.peer_id
     }
 
+    /// The first concrete address the node was bound to. When listening on
+    /// port 0 this carries the port chosen by the OS.
+    /// Empty if none could be bound at startup, see
+    /// [`NetworkConfig::local_fallback`].
     pub fn addr(&self) -> &Multiaddr {
         &self.addr
     }
+
+    /// Every concrete address the node is currently listening on, e.g. one per
+    /// interface when bound to an unspecified address.
+    pub fn listen_addrs(&self) -> Vec<Multiaddr> {
+        self.listen_addrs
+            .read()
+            .expect("listen_addrs lock poisoned")
+            .clone()
+    }
+
+    pub fn reachability(&self) -> Reachability {
+        self.reachability
+            .read()
+            .expect("reachability lock poisoned")
+            .clone()
+    }
+
+    /// Fails with [`DiscaError::Unreachable`] if files added now would not
+    /// be announced, see [`NetworkConfig::require_reachable_to_announce`].
+    pub fn check_reachable(&self) -> Result<()> {
+        if self.require_reachable.load(Ordering::Relaxed)
+            && self.reachability() == Reachability::Private
+        {
+            return Err(DiscaError::Unreachable.into());
+        }
+        Ok(())
+    }
 }
 
+/// How often the event loop looks for fetches nobody waits for anymore.
+const ABANDONED_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a node falling back to local only tries to listen and to reach
+/// its bootstrap peers again, see [`NetworkConfig::local_fallback`].
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often [`FileSharingP2P::wait_ready`] looks at the routing table.
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long [`FileSharingP2P::self_test`] waits for the peer's verdict.
+pub const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+type FetchSender = tokio::sync::oneshot::Sender<Result<GetOutcome<Vec<u8>>>>;
+
 #[derive(Debug)]
 enum Command {
     AddFile {
@@ -150,7 +975,22 @@ enum Command {
     },
     GetFile {
         path: String,
-        sender: tokio::sync::oneshot::Sender<Result<Option<Vec<u8>>>>,
+        sender: FetchSender,
+    },
+    Exists {
+        path: String,
+        sender: tokio::sync::oneshot::Sender<Result<bool>>,
+    },
+    UnannounceAll {
+        sender: tokio::sync::oneshot::Sender<Result<()>>,
+    },
+    AnnounceAll {
+        sender: tokio::sync::oneshot::Sender<Result<()>>,
+    },
+    GetFileFrom {
+        peer: PeerId,
+        path: String,
+        sender: FetchSender,
     },
     AddPeer {
         addr: Multiaddr,
@@ -160,228 +1000,2697 @@ enum Command {
         sender: tokio::sync::oneshot::Sender<Result<Multiaddr>>,
         addr: Multiaddr,
     },
+    StopListening {
+        addr: Multiaddr,
+        sender: tokio::sync::oneshot::Sender<Result<()>>,
+    },
+    KadStats {
+        sender: tokio::sync::oneshot::Sender<KadStats>,
+    },
+    KnownPeers {
+        sender: tokio::sync::oneshot::Sender<Vec<Multiaddr>>,
+    },
+    SelfTest {
+        peer: PeerId,
+        sender: tokio::sync::oneshot::Sender<Result<SelfTestReport>>,
+    },
+    Reconfigure {
+        config: Box<NetworkConfig>,
+        sender: tokio::sync::oneshot::Sender<()>,
+    },
 }
 
-struct EventLoop<T> {
-    swarm: Swarm<Behaviour>,
-    file_provider: T,
-    command_receiver: tokio::sync::mpsc::UnboundedReceiver<Command>,
-    pending_start_providing: DashMap<QueryId, tokio::sync::oneshot::Sender<Result<()>>>,
-    pending_get_providers: DashMap<QueryId, tokio::sync::oneshot::Sender<Result<Option<Vec<u8>>>>>,
-    pending_get_file: DashMap<RequestId, tokio::sync::oneshot::Sender<Result<Option<Vec<u8>>>>>,
-    pending_start_listening: DashMap<
-        libp2p::core::transport::ListenerId,
-        tokio::sync::oneshot::Sender<Result<Multiaddr>>,
-    >,
+struct PendingFetch {
+    key: String,
+    remaining: Vec<PeerId>,
+    had_nothing: Vec<PeerId>,
+    last_error: Option<anyhow::Error>,
+    parents_tried: bool,
+    sender: FetchSender,
+    /// Set while the file comes in chunks from the current provider.
+    chunked: Option<ChunkedFetch>,
+    /// Set while the file comes in ranges from the current provider.
+    ranged: Option<RangedFetch>,
+    /// The provider asked last and when its response is due.
+    awaiting: Option<(PeerId, Instant)>,
+    /// The most that came from any provider given up on part way, see
+    /// [`NetworkConfig::allow_partial`].
+    partial: Option<PartialContent>,
+    /// Requests for the file sent to other providers at the same time as the
+    /// one awaited, still to be answered, see [`NetworkConfig::hedge_count`].
+    hedged: Vec<(RequestId, PeerId)>,
+    /// The lookup that found the providers, which may still be finding more.
+    lookup: Option<QueryId>,
 }
 
-impl<T: FileProvider> EventLoop<T> {
-    pub(crate) async fn run(&mut self) {
-        loop {
-            select! {
-                command = self.command_receiver.recv() => {
-                    self.handle_command(command);
-                }
-                event = self.swarm.next() => {
-                    self.handle_event(event.expect("there should always be an event"));
-                }
-            }
+/// A provider lookup going on after it found the first providers, so that
+/// those found next are asked too, see [`NetworkConfig::hedge_count`].
+struct Lookup {
+    key: String,
+    /// Every provider found so far.
+    providers: HashSet<PeerId>,
+}
+
+/// A file being fetched in chunks, see [`NetworkConfig::chunking`].
+struct ChunkedFetch {
+    provider: PeerId,
+    manifest: Manifest,
+    /// Where each chunk starts in the file.
+    offsets: Vec<u64>,
+    /// Of the whole file, sent along with the manifest.
+    signature: Option<ContentSignature>,
+    /// Where the chunks are written as they come, so that they outlive the
+    /// fetch.
+    file: PartialFile,
+    /// Whether each chunk is in `file`, checked against the manifest.
+    received: Vec<bool>,
+    /// The chunks asked for last.
+    requested: Range<usize>,
+}
+
+/// A file too large for one response being fetched in ranges, see
+/// [`NetworkConfig::fetch_in_ranges`].
+struct RangedFetch {
+    provider: PeerId,
+    /// Of the whole file, sent along with the answer that it is too large.
+    signature: Option<ContentSignature>,
+    /// The content received so far.
+    content: Vec<u8>,
+}
+
+impl PendingFetch {
+    /// `providers` are asked in order.
+    fn new(key: String, mut providers: Vec<PeerId>, sender: FetchSender) -> Self {
+        // Providers are popped from the back.
+        providers.reverse();
+        Self {
+            key,
+            remaining: providers,
+            had_nothing: Vec::new(),
+            last_error: None,
+            parents_tried: false,
+            sender,
+            chunked: None,
+            ranged: None,
+            awaiting: None,
+            partial: None,
+            hedged: Vec::new(),
+            lookup: None,
         }
     }
 
-    fn handle_command(&mut self, command: Option<Command>) {
-        match command {
-            Some(Command::AddFile { path, sender }) => self.add_file(path, sender),
-            Some(Command::RemoveFile { path, sender }) => self.remove_file(path, sender),
-            Some(Command::GetFile { path, sender }) => self.get_providers(path, sender),
-            Some(Command::AddPeer { addr, sender }) => {
-                if let Err(e) = self.swarm.dial(addr.clone()) {
-                    sender.send(Err(e.into())).expect("send should work");
-                } else {
-                    sender.send(Ok(())).expect("send should work");
-                }
-            }
-            Some(Command::StartListening { sender, addr }) => self.start_listening(addr, sender),
-            None => {
-                return;
-            }
+    /// Ignores the requests sent alongside the one answered, putting their
+    /// providers back in line in case the answer does not do.
+    fn ignore_hedged(&mut self) {
+        for (_, provider) in self.hedged.drain(..).rev() {
+            self.remaining.push(provider);
         }
     }
 
-    fn start_listening(
-        &mut self,
-        addr: Multiaddr,
-        sender: tokio::sync::oneshot::Sender<Result<Multiaddr>>,
-    ) {
-        match self.swarm.listen_on(addr) {
-            Ok(listener_id) => {
-                self.pending_start_listening.insert(listener_id, sender);
+    /// Keeps what came from the provider given up on, unless more came from
+    /// one before it.
+    fn keep_partial(&mut self) {
+        let partial = match (self.chunked.take(), self.ranged.take()) {
+            (Some(chunked), _) => chunked.partial(),
+            (_, Some(ranged)) => ranged.partial(),
+            _ => return,
+        };
+        let kept = self.partial.as_ref().map_or(0, PartialContent::received);
+        if partial.received() > kept {
+            self.partial = Some(partial);
+        }
+    }
+}
+
+impl ChunkedFetch {
+    /// Picks up the chunks that came on an earlier try of the file, and
+    /// writes those of the others found in local files.
+    fn open<T: FileProvider>(&mut self, file_provider: &mut T) -> Written {
+        self.received = match self.file.open(&self.manifest, &self.offsets) {
+            Ok(received) => received,
+            Err(e) => return Written::Failed(e),
+        };
+        for (i, chunk) in self.manifest.chunks.iter().enumerate() {
+            if self.received[i] {
+                continue;
             }
-            Err(e) => {
-                sender.send(Err(e.into())).expect("send should work");
+            let Some(content) = file_provider
+                .get_chunk(&chunk.hash)
+                .filter(|content| self.manifest.algorithm.hash(content) == chunk.hash)
+            else {
+                continue;
+            };
+            if let Err(e) =
+                self.file
+                    .write(&self.manifest, &self.offsets, [(i, content.as_slice())])
+            {
+                return Written::Failed(e);
             }
+            self.received[i] = true;
         }
+        Written::Done
     }
 
-    fn add_file(&mut self, path: String, sender: tokio::sync::oneshot::Sender<Result<()>>) {
-        let query_id = self
-            .swarm
-            .behaviour_mut()
-            .kademlia
-            .start_providing(path.into_bytes().into());
-        match query_id {
-            Ok(query_id) => {
-                self.pending_start_providing.insert(query_id, sender);
-            }
-            Err(e) => {
-                sender.send(Err(e.into())).expect("send should work");
+    /// Checks the chunks asked for last, which `content` from `peer` holds
+    /// in a row, against the manifest, and writes those that match up to
+    /// the first that does not.
+    fn write_requested(&mut self, key: &str, peer: PeerId, content: &[u8]) -> Written {
+        let mut rest = content;
+        let mut matching = Vec::new();
+        let mut corrupt = None;
+        for i in self.requested.clone() {
+            let chunk = &self.manifest.chunks[i];
+            let valid = rest.len() as u64 >= chunk.len && {
+                let (content, _) = rest.split_at(chunk.len as usize);
+                self.manifest.algorithm.hash(content) == chunk.hash
+            };
+            if !valid {
+                corrupt = Some(anyhow::anyhow!(
+                    "chunk {} of {} from {} does not match the manifest",
+                    i,
+                    key,
+                    peer
+                ));
+                break;
             }
+            let (content, remaining) = rest.split_at(chunk.len as usize);
+            matching.push((i, content));
+            rest = remaining;
+        }
+        if let Err(e) = self
+            .file
+            .write(&self.manifest, &self.offsets, matching.iter().copied())
+        {
+            return Written::Failed(e);
         }
+        for (i, _) in matching {
+            self.received[i] = true;
+        }
+        corrupt.map_or(Written::Done, Written::Corrupt)
     }
 
-    fn remove_file(&mut self, path: String, sender: tokio::sync::oneshot::Sender<Result<()>>) {
-        self.swarm
-            .behaviour_mut()
-            .kademlia
-            .stop_providing(&path.into_bytes().into());
-        sender.send(Ok(())).expect("send should work");
+    /// Reads the chunks that came back from the partial file, on the event
+    /// loop, which is fine once when a fetch is given up on.
+    fn partial(self) -> PartialContent {
+        let mut content = Vec::new();
+        let mut missing: Vec<Range<u64>> = Vec::new();
+        for (i, reference) in self.manifest.chunks.iter().enumerate() {
+            let chunk = self.received[i]
+                .then(|| self.file.read(self.offsets[i], reference.len).ok())
+                .flatten();
+            match chunk {
+                Some(chunk) => content.extend(chunk),
+                None => {
+                    let start = content.len() as u64;
+                    let end = start + reference.len;
+                    match missing.last_mut() {
+                        Some(last) if last.end == start => last.end = end,
+                        _ => missing.push(start..end),
+                    }
+                    content.resize(end as usize, 0);
+                }
+            }
+        }
+        PartialContent {
+            content,
+            missing,
+            truncated: false,
+        }
     }
+}
 
-    fn get_providers(
-        &mut self,
-        path: String,
-        sender: tokio::sync::oneshot::Sender<Result<Option<Vec<u8>>>>,
-    ) {
-        let query_id = self
-            .swarm
-            .behaviour_mut()
-            .kademlia
-            .get_providers(path.into_bytes().into());
-        self.pending_get_providers.insert(query_id, sender);
+impl RangedFetch {
+    fn partial(self) -> PartialContent {
+        PartialContent {
+            content: self.content,
+            missing: Vec::new(),
+            truncated: true,
+        }
     }
+}
 
-    fn get_file(
-        &mut self,
-        key: String,
+struct PendingSelfTest {
+    report: SelfTestReport,
+    /// Whether the peer was already asked to dial us back.
+    probing: bool,
+    deadline: Instant,
+    sender: tokio::sync::oneshot::Sender<Result<SelfTestReport>>,
+}
+
+/// A file being announced, see [`NetworkConfig::provide_retry`].
+struct PendingProvide {
+    key: kad::RecordKey,
+    /// Counted from 1.
+    attempt: u32,
+    sender: tokio::sync::oneshot::Sender<Result<()>>,
+}
+
+/// What a node falling back to local only needs to get back on the network.
+struct Reconnect {
+    listen_addrs: Vec<Multiaddr>,
+    bootstrap_peers: Vec<Multiaddr>,
+    next_attempt: Instant,
+    /// Whether the node had no peer at the last attempt.
+    offline: bool,
+}
+
+struct EventLoop<T> {
+    /// Cloned for each request answered off the event loop.
+    file_provider: T,
+    command_receiver: tokio::sync::mpsc::UnboundedReceiver<Command>,
+    /// Created on first use, as that needs a runtime.
+    sweep: Option<tokio::time::Interval>,
+    pending_start_providing: DashMap<QueryId, PendingProvide>,
+    /// Files waiting to be announced again, forgotten once removed.
+    provide_retries: HashSet<kad::RecordKey>,
+    pending_get_providers: DashMap<QueryId, (String, FetchSender)>,
+    /// Lookups going on for more providers while their fetch runs.
+    lookups: HashMap<QueryId, Lookup>,
+    /// Fetches that asked every provider found so far, waiting for their
+    /// lookup to find more or end.
+    waiting_for_providers: HashMap<QueryId, PendingFetch>,
+    pending_exists: DashMap<QueryId, tokio::sync::oneshot::Sender<Result<bool>>>,
+    pending_get_file: DashMap<RequestId, PendingFetch>,
+    /// Requests sent alongside another for the same fetch, see
+    /// [`NetworkConfig::hedge_count`], to the request the fetch is kept
+    /// under in `pending_get_file`.
+    hedged: HashMap<RequestId, RequestId>,
+    request_timeout: Duration,
+    pending_start_listening: DashMap<
+        libp2p::core::transport::ListenerId,
+        tokio::sync::oneshot::Sender<Result<Multiaddr>>,
+    >,
+    listen_addrs: Arc<RwLock<Vec<Multiaddr>>>,
+    reachability: Arc<RwLock<Reachability>>,
+    listeners: DashMap<Multiaddr, libp2p::core::transport::ListenerId>,
+    provider_cache: DashMap<String, CachedProviders>,
+    provider_cache_ttl: Duration,
+    signatures: Arc<DashMap<String, ContentSignature>>,
+    checker: Checker,
+    parent_peers: Vec<PeerId>,
+    kad_mode: KadMode,
+    provider_selector: Arc<dyn ProviderSelector>,
+    /// Last ping round-trip time of every connected peer.
+    rtts: DashMap<PeerId, Duration>,
+    connected_peers: tokio::sync::watch::Sender<usize>,
+    reputation: Reputation,
+    events: tokio::sync::broadcast::Sender<DiscaEvent>,
+    latencies: LatencyRecorder,
+    /// The address each peer last told us it sees us at.
+    observed_addrs: DashMap<PeerId, Multiaddr>,
+    pending_self_tests: DashMap<PeerId, PendingSelfTest>,
+    accept_encodings: Vec<CompressionAlgorithm>,
+    /// Files held back from the DHT since the node was told to stop
+    /// announcing, `None` while it announces.
+    unannounced: Option<HashSet<kad::RecordKey>>,
+    /// See [`NetworkConfig::require_reachable_to_announce`].
+    require_reachable: bool,
+    /// Files held back from the DHT while peers cannot reach the node.
+    held_back: HashSet<kad::RecordKey>,
+    /// Dials waiting for one in flight to connect or fail.
+    dial_queue: VecDeque<(Multiaddr, tokio::sync::oneshot::Sender<Result<()>>)>,
+    dials_in_flight: HashSet<ConnectionId>,
+    max_concurrent_dials: usize,
+    /// Set when the node keeps trying to get back on the network.
+    reconnect: Option<Reconnect>,
+    /// Whether files are asked for their manifest first.
+    chunking: bool,
+    /// See [`NetworkConfig::fetch_in_ranges`].
+    fetch_in_ranges: bool,
+    /// See [`NetworkConfig::offload`].
+    offload: bool,
+    /// See [`NetworkConfig::allow_partial`].
+    allow_partial: bool,
+    /// See [`NetworkConfig::partial_dir`].
+    partial_dir: PathBuf,
+    /// See [`NetworkConfig::hedge_count`].
+    hedge_count: usize,
+    /// See [`NetworkConfig::provide_retry`].
+    provide_retry: ProvideRetry,
+    /// See [`NetworkConfig::read_only`].
+    read_only: bool,
+    /// See [`NetworkConfig::auth_token`].
+    auth_token: Option<String>,
+    /// See [`NetworkConfig::token_validator`].
+    token_validator: Option<TokenValidator>,
+    /// Where work run off the event loop hands its result back.
+    done_sender: tokio::sync::mpsc::UnboundedSender<Work>,
+    done_receiver: tokio::sync::mpsc::UnboundedReceiver<Work>,
+}
+
+struct CachedProviders {
+    providers: HashSet<PeerId>,
+    expires_at: Instant,
+}
+
+/// Something for a [`FileSharingDriver`] to do, see
+/// [`FileSharingDriver::next_work`].
+#[derive(Debug)]
+pub struct DriverWork(Work);
+
+enum Work {
+    Command(Command),
+    /// Forget abandoned fetches, expired self tests, expired providers and
+    /// old scores.
+    Sweep,
+    /// Send the answer to the request of `peer` for `key`, read off the
+    /// event loop.
+    Respond {
+        peer: PeerId,
+        key: String,
+        channel: request_response::ResponseChannel<FileResponse>,
+        response: FileResponse,
+    },
+    /// Go on with a fetch whose file was checked off the event loop.
+    Checked {
+        peer: PeerId,
+        pending: Box<PendingFetch>,
+        checked: Checked,
+    },
+    /// Go on with a chunked fetch whose chunks were looked up, checked and
+    /// written off the event loop.
+    Chunked {
+        pending: Box<PendingFetch>,
+        chunked: ChunkedFetch,
+        written: Written,
+    },
+    /// Announce a file again, after announcing it failed.
+    Provide(PendingProvide),
+}
+
+impl std::fmt::Debug for Work {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Work::Command(command) => f.debug_tuple("Command").field(command).finish(),
+            Work::Sweep => f.write_str("Sweep"),
+            Work::Respond { peer, key, .. } => f
+                .debug_struct("Respond")
+                .field("peer", peer)
+                .field("key", key)
+                .finish_non_exhaustive(),
+            Work::Checked { peer, pending, .. } => f
+                .debug_struct("Checked")
+                .field("peer", peer)
+                .field("key", &pending.key)
+                .finish_non_exhaustive(),
+            Work::Chunked {
+                pending, chunked, ..
+            } => f
+                .debug_struct("Chunked")
+                .field("peer", &chunked.provider)
+                .field("key", &pending.key)
+                .finish_non_exhaustive(),
+            Work::Provide(provide) => f
+                .debug_struct("Provide")
+                .field("key", &provide.key)
+                .field("attempt", &provide.attempt)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Runs disca inside a swarm owned by the application, see
+/// [`FileSharingP2P::embedded`].
+///
+/// The application's event loop waits on [`FileSharingDriver::next_work`]
+/// next to the swarm and hands the driver what either produces:
+///
+/// ```ignore
+/// loop {
+///     select! {
+///         work = driver.next_work() => driver.handle_work(&mut swarm, work),
+///         event = swarm.select_next_some() => match event {
+///             SwarmEvent::Behaviour(MyBehaviourEvent::Disca(event)) => {
+///                 driver.handle_behaviour_event(&mut swarm, event)
+///             }
+///             SwarmEvent::Behaviour(event) => handle_my_event(event),
+///             event => driver.handle_swarm_event(&mut swarm, event),
+///         },
+///     }
+/// }
+/// ```
+pub struct FileSharingDriver<T> {
+    event_loop: EventLoop<T>,
+}
+
+impl<T: FileProvider + Clone + Send + 'static> FileSharingDriver<T> {
+    /// Waits for a command from a [`FileSharingP2P`] or for periodic
+    /// housekeeping. Cancel safe, so it can be raced against the swarm.
+    pub async fn next_work(&mut self) -> DriverWork {
+        self.event_loop.next_work().await
+    }
+
+    pub fn handle_work<B: Host>(&mut self, swarm: &mut Swarm<B>, work: DriverWork) {
+        self.event_loop.handle_work(swarm, work);
+    }
+
+    pub fn handle_behaviour_event<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        event: FileSharingBehaviourEvent,
+    ) {
+        self.event_loop.handle_behaviour_event(swarm, event);
+    }
+
+    /// Has to see every event of the swarm that is not a behaviour event, as
+    /// disca keeps track of listen addresses and connections.
+    pub fn handle_swarm_event<B: Host, TEv, E>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        event: SwarmEvent<TEv, E>,
+    ) {
+        self.event_loop.handle_swarm_event(swarm, event);
+    }
+}
+
+impl<T: FileProvider + Clone + Send + 'static> EventLoop<T> {
+    async fn run(mut self, mut swarm: Swarm<FileSharingBehaviour>) {
+        loop {
+            select! {
+                work = self.next_work() => self.handle_work(&mut swarm, work),
+                event = swarm.next() => {
+                    match event.expect("there should always be an event") {
+                        SwarmEvent::Behaviour(event) => self.handle_behaviour_event(&mut swarm, event),
+                        event => self.handle_swarm_event(&mut swarm, event),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits for a command, for work done off the event loop or for the
+    /// next periodic sweep. Cancel safe.
+    async fn next_work(&mut self) -> DriverWork {
+        let sweep = self
+            .sweep
+            .get_or_insert_with(|| tokio::time::interval(ABANDONED_SWEEP_INTERVAL));
+        select! {
+            Some(command) = self.command_receiver.recv() => DriverWork(Work::Command(command)),
+            Some(work) = self.done_receiver.recv() => DriverWork(work),
+            _ = sweep.tick() => DriverWork(Work::Sweep),
+        }
+    }
+
+    fn handle_work<B: Host>(&mut self, swarm: &mut Swarm<B>, DriverWork(work): DriverWork) {
+        match work {
+            Work::Command(command) => self.handle_command(swarm, command),
+            Work::Sweep => {
+                self.drop_abandoned_fetches(swarm);
+                self.expire_requests(swarm);
+                self.finish_expired_self_tests(swarm);
+                self.forget_expired_providers();
+                self.reputation.prune();
+                self.reconnect(swarm);
+            }
+            Work::Respond {
+                peer,
+                key,
+                channel,
+                response,
+            } => {
+                // Fails when the request is over in the meantime, e.g.
+                // because the requester timed out or went away, which only
+                // the subscribers need to hear about.
+                let sent = swarm
+                    .disca()
+                    .request_response
+                    .send_response(channel, response);
+                if sent.is_err() {
+                    let _ = self
+                        .events
+                        .send(DiscaEvent::ResponseDropped { key, peer_id: peer });
+                }
+            }
+            Work::Checked {
+                peer,
+                pending,
+                checked,
+            } => self.checked(swarm, peer, *pending, checked),
+            Work::Chunked {
+                pending,
+                chunked,
+                written,
+            } => self.chunks_written(swarm, *pending, chunked, written),
+            Work::Provide(provide) => {
+                if self.provide_retries.remove(&provide.key) {
+                    self.start_providing(swarm, provide);
+                } else {
+                    // Removed while waiting, so there is nothing to announce.
+                    let _ = provide.sender.send(Ok(()));
+                }
+            }
+        }
+    }
+
+    /// Runs `work` on the blocking threads of the runtime, leaving the event
+    /// loop to networking, or right away if [`NetworkConfig::offload`] is
+    /// off. Either way the work it returns is handled by the event loop
+    /// next.
+    fn offload(&self, work: impl FnOnce() -> Work + Send + 'static) {
+        let done = self.done_sender.clone();
+        // The receiver lives as long as the event loop.
+        if self.offload {
+            tokio::task::spawn_blocking(move || {
+                let _ = done.send(work());
+            });
+        } else {
+            let _ = done.send(work());
+        }
+    }
+
+    /// Forgets lookups and fetches whose caller went away, e.g. because the
+    /// `get` future was dropped, and stops their Kademlia queries.
+    fn drop_abandoned_fetches<B: Host>(&mut self, swarm: &mut Swarm<B>) {
+        let abandoned_queries = self
+            .pending_get_providers
+            .iter()
+            .filter(|entry| entry.value().1.is_closed())
+            .map(|entry| *entry.key())
+            .chain(
+                self.pending_exists
+                    .iter()
+                    .filter(|entry| entry.value().is_closed())
+                    .map(|entry| *entry.key()),
+            )
+            .collect::<Vec<_>>();
+        for id in abandoned_queries {
+            self.pending_get_providers.remove(&id);
+            self.pending_exists.remove(&id);
+            if let Some(mut query) = swarm.disca().kademlia.query_mut(&id) {
+                query.finish();
+            }
+        }
+        // Requests already sent cannot be recalled, their response is ignored.
+        let mut abandoned_lookups = Vec::new();
+        self.pending_get_file.retain(|_, pending| {
+            let abandoned = pending.sender.is_closed();
+            if abandoned {
+                abandoned_lookups.extend(pending.lookup);
+            }
+            !abandoned
+        });
+        for id in abandoned_lookups {
+            self.stop_lookup(swarm, id);
+        }
+        let abandoned_waits = self
+            .waiting_for_providers
+            .iter()
+            .filter(|(_, pending)| pending.sender.is_closed())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in abandoned_waits {
+            self.waiting_for_providers.remove(&id);
+            self.stop_lookup(swarm, id);
+        }
+        self.hedged
+            .retain(|_, request_id| self.pending_get_file.contains_key(request_id));
+    }
+
+    /// Drops the providers cached for keys past their time to live, which
+    /// would be looked up again anyway, so that keys looked up once do not
+    /// stay in the cache for good.
+    fn forget_expired_providers(&self) {
+        let now = Instant::now();
+        self.provider_cache
+            .retain(|_, cached| cached.expires_at > now);
+    }
+
+    /// Fails the file requests whose response is overdue. The transport
+    /// times requests out by itself, so this only comes first once the
+    /// request timeout was lowered, see [`FileSharingP2P::reconfigure`].
+    fn expire_requests<B: Host>(&mut self, swarm: &mut Swarm<B>) {
+        let now = Instant::now();
+        let expired = self
+            .pending_get_file
+            .iter()
+            .filter(|entry| {
+                entry
+                    .value()
+                    .awaiting
+                    .is_some_and(|(_, deadline)| deadline <= now)
+            })
+            .map(|entry| *entry.key())
+            .collect::<Vec<_>>();
+        for request_id in expired {
+            let Some((_, mut pending)) = self.pending_get_file.remove(&request_id) else {
+                continue;
+            };
+            if let Some((peer, _)) = pending.awaiting {
+                self.reputation.record(peer, Outcome::Failed);
+            }
+            pending.last_error = Some(request_response::OutboundFailure::Timeout.into());
+            self.try_next_provider(swarm, pending);
+        }
+    }
+
+    fn handle_command<B: Host>(&mut self, swarm: &mut Swarm<B>, command: Command) {
+        match command {
+            Command::AddFile { path, sender } => self.add_file(swarm, path, sender),
+            Command::RemoveFile { path, sender } => self.remove_file(swarm, path, sender),
+            Command::GetFile { path, sender } => self.get_providers(swarm, path, sender),
+            Command::Exists { path, sender } => self.exists(swarm, path, sender),
+            Command::UnannounceAll { sender } => {
+                self.unannounce_all(swarm);
+                let _ = sender.send(Ok(()));
+            }
+            Command::AnnounceAll { sender } => {
+                let _ = sender.send(self.announce_all(swarm));
+            }
+            Command::GetFileFrom { peer, path, sender } => {
+                self.try_next_provider(
+                    swarm,
+                    PendingFetch {
+                        parents_tried: true,
+                        ..PendingFetch::new(path, vec![peer], sender)
+                    },
+                );
+            }
+            Command::AddPeer { addr, sender } => {
+                self.dial_queue.push_back((addr, sender));
+                self.start_dials(swarm);
+            }
+            Command::StartListening { sender, addr } => self.start_listening(swarm, addr, sender),
+            Command::StopListening { addr, sender } => self.stop_listening(swarm, addr, sender),
+            Command::KadStats { sender } => {
+                // The caller may have given up already, e.g. `wait_ready`
+                // timing out.
+                let _ = sender.send(self.kad_stats(swarm));
+            }
+            Command::KnownPeers { sender } => {
+                // The caller may have given up already.
+                let _ = sender.send(known_peers(swarm));
+            }
+            Command::SelfTest { peer, sender } => self.start_self_test(swarm, peer, sender),
+            Command::Reconfigure { config, sender } => {
+                self.reconfigure(swarm, *config);
+                let _ = sender.send(());
+            }
+        }
+    }
+
+    /// Takes on the settings of `config` that can change while the node
+    /// runs, see [`FileSharingP2P::reconfigure`].
+    fn reconfigure<B: Host>(&mut self, swarm: &mut Swarm<B>, config: NetworkConfig) {
+        self.request_timeout = config.request_timeout;
+        self.provider_cache_ttl = config.provider_cache_ttl;
+        self.checker.trusted_publishers = Arc::new(config.trusted_publishers.into_iter().collect());
+        self.accept_encodings = config.accept_encodings;
+        self.max_concurrent_dials = config.max_concurrent_dials.max(1);
+        self.fetch_in_ranges = config.fetch_in_ranges;
+        self.offload = config.offload;
+        self.allow_partial = config.allow_partial;
+        self.hedge_count = config.hedge_count.max(1);
+        self.provide_retry = config.provide_retry;
+        self.auth_token = config.auth_token;
+        self.require_reachable = config.require_reachable_to_announce;
+        self.apply_reachability(swarm);
+        // Dials waiting in line may fit under a higher limit.
+        self.start_dials(swarm);
+    }
+
+    fn start_listening<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        addr: Multiaddr,
+        sender: tokio::sync::oneshot::Sender<Result<Multiaddr>>,
+    ) {
+        match swarm.listen_on(addr.clone()) {
+            Ok(listener_id) => {
+                self.listeners.insert(addr, listener_id);
+                self.pending_start_listening.insert(listener_id, sender);
+            }
+            Err(e) => {
+                let _ = sender.send(Err(e.into()));
+            }
+        }
+    }
+
+    fn kad_stats<B: Host>(&mut self, swarm: &mut Swarm<B>) -> KadStats {
+        let buckets = swarm
+            .disca()
+            .kademlia
+            .kbuckets()
+            .map(|bucket| BucketStats {
+                index: bucket.range().0.ilog2().unwrap_or(0),
+                entries: bucket.num_entries(),
+                has_pending: bucket.has_pending(),
+            })
+            .collect::<Vec<_>>();
+        KadStats {
+            known_peers: buckets.iter().map(|bucket| bucket.entries).sum(),
+            buckets,
+        }
+    }
+
+    fn start_self_test<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        peer: PeerId,
+        sender: tokio::sync::oneshot::Sender<Result<SelfTestReport>>,
+    ) {
+        if !swarm.is_connected(&peer) {
+            if let Err(e) = swarm.dial(peer) {
+                let _ = sender.send(Err(e.into()));
+                return;
+            }
+        }
+        self.pending_self_tests.insert(
+            peer,
+            PendingSelfTest {
+                report: SelfTestReport::default(),
+                probing: false,
+                deadline: Instant::now() + SELF_TEST_TIMEOUT,
+                sender,
+            },
+        );
+        self.probe_for_self_test(swarm, peer);
+    }
+
+    /// Asks `peer` to dial us back at the address it observed, once it told
+    /// us that address and if a self-test with it is pending.
+    fn probe_for_self_test<B: Host>(&mut self, swarm: &mut Swarm<B>, peer: PeerId) {
+        let Some(observed) = self.observed_addrs.get(&peer).map(|addr| addr.clone()) else {
+            return;
+        };
+        let Some(mut test) = self.pending_self_tests.get_mut(&peer) else {
+            return;
+        };
+        if test.probing {
+            return;
+        }
+        test.probing = true;
+        test.report.observed_addr = Some(observed.clone());
+        drop(test);
+
+        let autonat = &mut swarm.disca().autonat;
+        autonat.add_server(peer, None);
+        autonat.probe_address(observed);
+    }
+
+    /// Updates the self-test with `peer`, if any, and answers it once both
+    /// the dial-back verdict and a round-trip time are known.
+    fn update_self_test<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        peer: PeerId,
+        update: impl FnOnce(&mut SelfTestReport),
+    ) {
+        let Some(mut test) = self.pending_self_tests.get_mut(&peer) else {
+            return;
+        };
+        update(&mut test.report);
+        let done = test.report.reachable.is_some() && test.report.rtt.is_some();
+        drop(test);
+        if done {
+            self.finish_self_test(swarm, peer);
+        }
+    }
+
+    fn finish_self_test<B: Host>(&mut self, swarm: &mut Swarm<B>, peer: PeerId) {
+        if let Some((_, test)) = self.pending_self_tests.remove(&peer) {
+            swarm.disca().autonat.remove_server(&peer);
+            let _ = test.sender.send(Ok(test.report));
+        }
+    }
+
+    /// Answers self-tests that ran out of time with what they learnt so far.
+    fn finish_expired_self_tests<B: Host>(&mut self, swarm: &mut Swarm<B>) {
+        let now = Instant::now();
+        let expired = self
+            .pending_self_tests
+            .iter()
+            .filter(|entry| entry.deadline <= now || entry.sender.is_closed())
+            .map(|entry| *entry.key())
+            .collect::<Vec<_>>();
+        for peer in expired {
+            self.finish_self_test(swarm, peer);
+        }
+    }
+
+    fn stop_listening<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        addr: Multiaddr,
+        sender: tokio::sync::oneshot::Sender<Result<()>>,
+    ) {
+        let Some(listener_id) = self.listeners.get(&addr).map(|entry| *entry.value()) else {
+            let _ = sender.send(Err(anyhow::anyhow!("not listening on {addr}")));
+            return;
+        };
+        self.listeners.retain(|_, id| *id != listener_id);
+        swarm.remove_listener(listener_id);
+        let _ = sender.send(Ok(()));
+    }
+
+    fn add_file<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        path: String,
+        sender: tokio::sync::oneshot::Sender<Result<()>>,
+    ) {
+        self.start_providing(
+            swarm,
+            PendingProvide {
+                key: path.into_bytes().into(),
+                attempt: 1,
+                sender,
+            },
+        );
+    }
+
+    /// Announces a file, unless files are held back from the DHT for now or
+    /// the node is read-only.
+    fn start_providing<B: Host>(&mut self, swarm: &mut Swarm<B>, provide: PendingProvide) {
+        if self.read_only {
+            let _ = provide.sender.send(Ok(()));
+            return;
+        }
+        if let Some(unannounced) = &mut self.unannounced {
+            unannounced.insert(provide.key);
+            let _ = provide.sender.send(Ok(()));
+            return;
+        }
+        if self.holding_back() {
+            self.held_back.insert(provide.key);
+            let _ = provide.sender.send(Ok(()));
+            return;
+        }
+        match swarm.disca().kademlia.start_providing(provide.key.clone()) {
+            Ok(query_id) => {
+                self.pending_start_providing.insert(query_id, provide);
+            }
+            // The store is full, which waiting does not change.
+            Err(e) => {
+                let _ = provide.sender.send(Err(e.into()));
+            }
+        }
+    }
+
+    /// Announces a file again after a while if attempts are left, see
+    /// [`NetworkConfig::provide_retry`], or fails adding it.
+    fn provide_failed(&mut self, provide: PendingProvide, error: kad::AddProviderError) {
+        if provide.attempt >= self.provide_retry.max_attempts {
+            let _ = provide.sender.send(Err(error.into()));
+            return;
+        }
+        // Nobody waits for the announcement any more.
+        if provide.sender.is_closed() {
+            return;
+        }
+        let backoff = self.provide_retry.backoff(provide.attempt);
+        self.provide_retries.insert(provide.key.clone());
+        let done = self.done_sender.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(backoff).await;
+            // The receiver lives as long as the event loop.
+            let _ = done.send(Work::Provide(PendingProvide {
+                attempt: provide.attempt + 1,
+                ..provide
+            }));
+        });
+    }
+
+    fn remove_file<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        path: String,
+        sender: tokio::sync::oneshot::Sender<Result<()>>,
+    ) {
+        self.signatures.remove(&path);
+        self.provide_retries.remove(&kad::RecordKey::new(&path));
+        if let Some(unannounced) = &mut self.unannounced {
+            unannounced.remove(&kad::RecordKey::new(&path));
+        }
+        self.held_back.remove(&kad::RecordKey::new(&path));
+        swarm
+            .disca()
+            .kademlia
+            .stop_providing(&path.into_bytes().into());
+        let _ = sender.send(Ok(()));
+    }
+
+    /// Starts queued dials while fewer than the maximum are in flight.
+    fn start_dials<B: Host>(&mut self, swarm: &mut Swarm<B>) {
+        while self.dials_in_flight.len() < self.max_concurrent_dials {
+            let Some((addr, sender)) = self.dial_queue.pop_front() else {
+                return;
+            };
+            let dial = DialOpts::unknown_peer_id().address(addr).build();
+            let connection_id = dial.connection_id();
+            let result = swarm.dial(dial).map_err(anyhow::Error::from);
+            if result.is_ok() {
+                self.dials_in_flight.insert(connection_id);
+            }
+            // The caller may have gone away while the dial was queued.
+            let _ = sender.send(result);
+        }
+    }
+
+    /// Listens again if every listener is gone and dials the bootstrap peers
+    /// again if no peer is connected, at most every [`RECONNECT_INTERVAL`].
+    /// Once back among peers, announces every file again, as announcements
+    /// made in the meantime reached nobody.
+    fn reconnect<B: Host>(&mut self, swarm: &mut Swarm<B>) {
+        let Some(reconnect) = &mut self.reconnect else {
+            return;
+        };
+        if reconnect.next_attempt > Instant::now() {
+            return;
+        }
+        reconnect.next_attempt = Instant::now() + RECONNECT_INTERVAL;
+
+        if self.listeners.is_empty() {
+            for addr in &reconnect.listen_addrs {
+                // Tried again at the next attempt.
+                if let Ok(listener_id) = swarm.listen_on(addr.clone()) {
+                    self.listeners.insert(addr.clone(), listener_id);
+                }
+            }
+        }
+        if *self.connected_peers.borrow() > 0 {
+            if std::mem::take(&mut reconnect.offline) {
+                let provided = swarm
+                    .disca()
+                    .kademlia
+                    .store_mut()
+                    .provided()
+                    .map(|record| record.key.clone())
+                    .collect::<Vec<_>>();
+                for key in provided {
+                    // Only fails when the record store is full, which
+                    // announcing anew does not change.
+                    let _ = swarm.disca().kademlia.start_providing(key);
+                }
+            }
+            return;
+        }
+        reconnect.offline = true;
+        if self.dials_in_flight.is_empty() {
+            for addr in &reconnect.bootstrap_peers {
+                // Nobody waits for these dials.
+                let (sender, _) = tokio::sync::oneshot::channel();
+                self.dial_queue.push_back((addr.clone(), sender));
+            }
+            self.start_dials(swarm);
+        }
+    }
+
+    fn unannounce_all<B: Host>(&mut self, swarm: &mut Swarm<B>) {
+        let kademlia = &mut swarm.disca().kademlia;
+        let provided = kademlia
+            .store_mut()
+            .provided()
+            .map(|record| record.key.clone())
+            .collect::<Vec<_>>();
+        for key in &provided {
+            kademlia.stop_providing(key);
+        }
+        self.unannounced
+            .get_or_insert_with(HashSet::new)
+            .extend(provided);
+    }
+
+    fn announce_all<B: Host>(&mut self, swarm: &mut Swarm<B>) -> Result<()> {
+        let Some(unannounced) = self.unannounced.take() else {
+            return Ok(());
+        };
+        if self.holding_back() {
+            self.held_back.extend(unannounced);
+            return Ok(());
+        }
+        for key in unannounced {
+            swarm.disca().kademlia.start_providing(key)?;
+        }
+        Ok(())
+    }
+
+    /// Whether files are held back from the DHT for now, see
+    /// [`NetworkConfig::require_reachable_to_announce`].
+    fn holding_back(&self) -> bool {
+        self.require_reachable
+            && *self
+                .reachability
+                .read()
+                .expect("reachability lock poisoned")
+                == Reachability::Private
+    }
+
+    /// Stops announcing every file while peers cannot reach the node, and
+    /// announces the files held back again once they can, or reachability is
+    /// no longer required.
+    fn apply_reachability<B: Host>(&mut self, swarm: &mut Swarm<B>) {
+        let kademlia = &mut swarm.disca().kademlia;
+        if self.holding_back() {
+            let provided = kademlia
+                .store_mut()
+                .provided()
+                .map(|record| record.key.clone())
+                .collect::<Vec<_>>();
+            for key in &provided {
+                kademlia.stop_providing(key);
+            }
+            self.held_back.extend(provided);
+            return;
+        }
+        for key in std::mem::take(&mut self.held_back) {
+            match &mut self.unannounced {
+                Some(unannounced) => {
+                    unannounced.insert(key);
+                }
+                // Only fails once the store is full, and it had room for the
+                // key before.
+                None => {
+                    let _ = kademlia.start_providing(key);
+                }
+            }
+        }
+    }
+
+    fn get_providers<B: Host>(&mut self, swarm: &mut Swarm<B>, path: String, sender: FetchSender) {
+        let cached = self
+            .provider_cache
+            .get(&path)
+            .filter(|cached| cached.expires_at > Instant::now())
+            .map(|cached| cached.providers.clone());
+        if let Some(providers) = cached {
+            self.get_file(swarm, path, providers, None, sender);
+            return;
+        }
+
+        let query_id = swarm
+            .disca()
+            .kademlia
+            .get_providers(path.clone().into_bytes().into());
+        // Nobody listening is fine.
+        let _ = self.events.send(DiscaEvent::ProvidersLookupStarted {
+            key: path.clone(),
+            query_id,
+        });
+        self.pending_get_providers.insert(query_id, (path, sender));
+    }
+
+    /// Records how long the provider lookup `id` for `key` took.
+    fn lookup_finished(&self, key: &str, id: QueryId, stats: &kad::QueryStats, providers: usize) {
+        let duration = stats.duration().unwrap_or_default();
+        self.latencies.provider_lookup(duration);
+        let _ = self.events.send(DiscaEvent::ProvidersLookupFinished {
+            key: key.to_owned(),
+            query_id: id,
+            duration,
+            requests: stats.num_requests(),
+            providers,
+        });
+    }
+
+    fn exists<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        path: String,
+        sender: tokio::sync::oneshot::Sender<Result<bool>>,
+    ) {
+        let cached = self
+            .provider_cache
+            .get(&path)
+            .is_some_and(|cached| cached.expires_at > Instant::now());
+        if cached {
+            let _ = sender.send(Ok(true));
+            return;
+        }
+        let query_id = swarm
+            .disca()
+            .kademlia
+            .get_providers(path.into_bytes().into());
+        self.pending_exists.insert(query_id, sender);
+    }
+
+    /// Answers a pending [`FileSharingP2P::exists`] from the progress of its
+    /// lookup.
+    fn exists_progressed<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        id: QueryId,
+        result: kad::GetProvidersResult,
+    ) {
+        let Some((_, sender)) = self.pending_exists.remove(&id) else {
+            return;
+        };
+        let exists = match result {
+            Ok(kad::GetProvidersOk::FoundProviders { key, mut providers }) => {
+                providers.remove(swarm.local_peer_id());
+                if providers.is_empty() {
+                    self.pending_exists.insert(id, sender);
+                    return;
+                }
+                if let Some(mut query) = swarm.disca().kademlia.query_mut(&id) {
+                    query.finish();
+                }
+                if let Ok(key) = String::from_utf8(key.to_vec()) {
+                    self.cache_providers(key, providers);
+                }
+                Ok(true)
+            }
+            Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => Ok(false),
+            Err(kad::GetProvidersError::Timeout { .. }) => Ok(false),
+        };
+        let _ = sender.send(exists);
+    }
+
+    /// Ends the lookup of the providers of `path` and fetches it from them.
+    fn providers_found<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        id: QueryId,
+        stats: &kad::QueryStats,
+        path: String,
         providers: HashSet<PeerId>,
-        sender: tokio::sync::oneshot::Sender<Result<Option<Vec<u8>>>>,
+        sender: FetchSender,
     ) {
-        if let Some(provider) = providers.iter().next() {
-            let request_id = self
-                .swarm
-                .behaviour_mut()
-                .request_response
-                .send_request(provider, FileRequest { path: key });
-            self.pending_get_file.insert(request_id, sender);
+        if let Some(mut query) = swarm.disca().kademlia.query_mut(&id) {
+            query.finish();
+        }
+        self.lookup_finished(&path, id, stats, providers.len());
+        if !providers.is_empty() {
+            self.cache_providers(path.clone(), providers.clone());
+        }
+        self.get_file(swarm, path, providers, None, sender);
+    }
+
+    fn cache_providers(&mut self, key: String, providers: HashSet<PeerId>) {
+        if self.provider_cache_ttl.is_zero() {
+            return;
+        }
+        let jitter = self
+            .provider_cache_ttl
+            .mul_f64(rand::thread_rng().gen_range(0.0..0.1));
+        self.provider_cache.insert(
+            key,
+            CachedProviders {
+                providers,
+                expires_at: Instant::now() + self.provider_cache_ttl + jitter,
+            },
+        );
+    }
+
+    /// Fetches `key` from `providers`, found by `lookup` if it goes on
+    /// finding more.
+    fn get_file<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        key: String,
+        providers: HashSet<PeerId>,
+        lookup: Option<QueryId>,
+        sender: FetchSender,
+    ) {
+        if providers.is_empty() && self.parent_peers.is_empty() {
+            // The caller may have given up on the get already.
+            let _ = sender.send(Ok(GetOutcome::NoProviders));
+            return;
+        }
+        let providers = self.order_providers(&key, providers);
+        self.try_next_provider(
+            swarm,
+            PendingFetch {
+                lookup,
+                ..PendingFetch::new(key, providers, sender)
+            },
+        );
+    }
+
+    /// `providers` of `key` in the order they are to be asked in.
+    fn order_providers(&self, key: &str, providers: HashSet<PeerId>) -> Vec<PeerId> {
+        let mut providers = providers
+            .into_iter()
+            .map(|peer_id| ProviderInfo {
+                peer_id,
+                rtt: self.rtts.get(&peer_id).map(|rtt| *rtt),
+                score: self.reputation.score(&peer_id),
+            })
+            .collect::<Vec<_>>();
+        self.provider_selector.order(key, &mut providers);
+        self.reputation.demote(&mut providers);
+        providers
+            .into_iter()
+            .map(|provider| provider.peer_id)
+            .collect()
+    }
+
+    /// Hands the providers the lookup `id` found next to its fetch: asked
+    /// right away alongside the request out if the fetch is still waiting
+    /// for its first answer and fewer than [`NetworkConfig::hedge_count`]
+    /// requests are out, and after those found before otherwise.
+    fn more_providers_found<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        id: QueryId,
+        mut providers: HashSet<PeerId>,
+    ) {
+        let Some(lookup) = self.lookups.get_mut(&id) else {
+            return;
+        };
+        providers.retain(|provider| !lookup.providers.contains(provider));
+        if providers.is_empty() {
+            return;
+        }
+        lookup.providers.extend(&providers);
+        let key = lookup.key.clone();
+        // Popped from the back, after those already in line.
+        let mut found = self.order_providers(&key, providers);
+        found.reverse();
+        if let Some(mut pending) = self.waiting_for_providers.remove(&id) {
+            pending.remaining = found;
+            self.try_next_provider(swarm, pending);
+            return;
+        }
+        // Not found while its content is being checked.
+        let Some(request_id) = self
+            .pending_get_file
+            .iter()
+            .find(|entry| entry.value().lookup == Some(id))
+            .map(|entry| *entry.key())
+        else {
+            return;
+        };
+        let Some((_, mut pending)) = self.pending_get_file.remove(&request_id) else {
+            return;
+        };
+        pending.remaining.splice(0..0, found);
+        if pending.chunked.is_none() && pending.ranged.is_none() {
+            let request = self.first_request(&pending.key);
+            while pending.hedged.len() + 1 < self.hedge_count {
+                let Some(hedge) = pending.remaining.pop() else {
+                    break;
+                };
+                let hedged = self.send_request(swarm, hedge, request.clone(), &pending.key);
+                pending.hedged.push((hedged, hedge));
+                self.hedged.insert(hedged, request_id);
+            }
+        }
+        self.pending_get_file.insert(request_id, pending);
+    }
+
+    /// Records the end of a lookup that went on while its fetch ran, and
+    /// moves on with the fetch if it was waiting for more providers.
+    fn lookup_over<B: Host>(&mut self, swarm: &mut Swarm<B>, id: QueryId, stats: &kad::QueryStats) {
+        let Some(lookup) = self.lookups.remove(&id) else {
+            return;
+        };
+        self.lookup_finished(&lookup.key, id, stats, lookup.providers.len());
+        self.cache_providers(lookup.key, lookup.providers);
+        if let Some(pending) = self.waiting_for_providers.remove(&id) {
+            self.try_next_provider(swarm, pending);
+        }
+    }
+
+    /// Ends a lookup going on for more providers once its fetch no longer
+    /// needs them. It is recorded as over once Kademlia reports it so.
+    fn stop_lookup<B: Host>(&mut self, swarm: &mut Swarm<B>, id: QueryId) {
+        if let Some(mut query) = swarm.disca().kademlia.query_mut(&id) {
+            query.finish();
+        }
+    }
+
+    /// The request a fetch starts with, for the manifest of the file or the
+    /// whole file.
+    fn first_request(&self, key: &str) -> FileRequest {
+        let path = if self.chunking {
+            chunking::manifest_path(key)
         } else {
-            sender.send(Ok(None)).expect("send should work");
+            key.to_owned()
+        };
+        FileRequest {
+            range: None,
+            path,
+            accept_encodings: self.accept_encodings.clone(),
+            auth_token: None,
+        }
+    }
+
+    /// Sends the request to the next untried providers, falling back to the
+    /// parent peers once every provider has been asked, and resolves the fetch
+    /// when nobody is left. Waits for the providers asked at the same time
+    /// instead, if any is still to answer.
+    fn try_next_provider<B: Host>(&mut self, swarm: &mut Swarm<B>, mut pending: PendingFetch) {
+        if pending.sender.is_closed() {
+            if let Some(id) = pending.lookup {
+                self.stop_lookup(swarm, id);
+            }
+            return;
+        }
+        if let Some((request_id, provider)) = pending.hedged.pop() {
+            // Asked at the same time, so due at the same time.
+            let deadline = pending
+                .awaiting
+                .map_or_else(|| Instant::now() + self.request_timeout, |(_, due)| due);
+            pending.awaiting = Some((provider, deadline));
+            for (hedged, _) in &pending.hedged {
+                self.hedged.insert(*hedged, request_id);
+            }
+            self.pending_get_file.insert(request_id, pending);
+            return;
+        }
+        if pending.remaining.is_empty() {
+            if let Some(id) = pending.lookup.filter(|id| self.lookups.contains_key(id)) {
+                self.waiting_for_providers.insert(id, pending);
+                return;
+            }
+        }
+        if pending.remaining.is_empty() && !pending.parents_tried {
+            pending.parents_tried = true;
+            pending.remaining = self.parent_peers.iter().rev().copied().collect();
         }
+        if let Some(provider) = pending.remaining.pop() {
+            // Chunks are each checked against the manifest, so the next
+            // provider picks up where the last one stopped. So does a range,
+            // as long as the whole file can be checked once it is in.
+            if let Some(mut chunked) = pending.chunked.take() {
+                chunked.provider = provider;
+                self.request_chunks(swarm, pending, chunked);
+                return;
+            }
+            if let Some(mut ranged) = pending.ranged.take() {
+                if ranged.signature.is_some()
+                    || HashAlgorithm::parse_content_key(&pending.key).is_some()
+                {
+                    ranged.provider = provider;
+                    self.request_range(swarm, pending, ranged);
+                    return;
+                }
+                if self.allow_partial {
+                    pending.ranged = Some(ranged);
+                    pending.keep_partial();
+                }
+            }
+            let request = self.first_request(&pending.key);
+            while pending.hedged.len() + 1 < self.hedge_count {
+                let Some(hedge) = pending.remaining.pop() else {
+                    break;
+                };
+                let request_id = self.send_request(swarm, hedge, request.clone(), &pending.key);
+                pending.hedged.push((request_id, hedge));
+            }
+            let hedged = pending
+                .hedged
+                .iter()
+                .map(|(request_id, _)| *request_id)
+                .collect::<Vec<_>>();
+            let request_id = self.send_file_request(swarm, provider, request, pending);
+            for hedged in hedged {
+                self.hedged.insert(hedged, request_id);
+            }
+            return;
+        }
+
+        if self.allow_partial {
+            pending.keep_partial();
+        }
+        // None of the cached providers had the file, so look again next time.
+        self.provider_cache.remove(&pending.key);
+        let result = match (pending.partial, pending.last_error) {
+            (Some(partial), _) => Ok(GetOutcome::Partial(partial)),
+            (None, Some(e)) if pending.had_nothing.is_empty() => Err(e),
+            (None, None) if pending.had_nothing.is_empty() => Ok(GetOutcome::NoProviders),
+            _ => Ok(GetOutcome::ProvidersHadNothing {
+                peers: pending.had_nothing,
+            }),
+        };
+        let _ = pending.sender.send(result);
     }
 
-    fn handle_event(
+    /// Handles the events of the swarm itself, those of the behaviour go to
+    /// [`EventLoop::handle_behaviour_event`]. Generic over the behaviour and
+    /// the connection handler errors, which are never looked at and whose
+    /// types depend on the swarm disca runs in.
+    fn handle_swarm_event<B: Host, TEv, E>(
         &mut self,
-        event: SwarmEvent<BehaviourEvent, Either<Either<void::Void, io::Error>, io::Error>>,
+        swarm: &mut Swarm<B>,
+        event: SwarmEvent<TEv, E>,
     ) {
+        if let SwarmEvent::ConnectionEstablished { connection_id, .. }
+        | SwarmEvent::OutgoingConnectionError { connection_id, .. } = &event
+        {
+            if self.dials_in_flight.remove(connection_id) {
+                self.start_dials(swarm);
+            }
+        }
         match event {
             SwarmEvent::NewListenAddr {
                 listener_id,
                 address,
             } => {
+                self.listen_addrs
+                    .write()
+                    .expect("listen_addrs lock poisoned")
+                    .push(address.clone());
+                self.listeners.insert(address.clone(), listener_id);
+                if let Some((_, sender)) = self.pending_start_listening.remove(&listener_id) {
+                    let _ = sender.send(Ok(address));
+                }
+            }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                self.listen_addrs
+                    .write()
+                    .expect("listen_addrs lock poisoned")
+                    .retain(|a| a != &address);
+                self.listeners.remove(&address);
+            }
+            SwarmEvent::ListenerClosed {
+                listener_id,
+                addresses,
+                reason,
+            } => {
+                self.listen_addrs
+                    .write()
+                    .expect("listen_addrs lock poisoned")
+                    .retain(|a| !addresses.contains(a));
+                self.listeners.retain(|_, id| *id != listener_id);
                 if let Some((_, sender)) = self.pending_start_listening.remove(&listener_id) {
-                    sender.send(Ok(address)).expect("send should work");
+                    let error = match reason {
+                        Ok(()) => anyhow::anyhow!("listener closed before binding an address"),
+                        Err(e) => e.into(),
+                    };
+                    let _ = sender.send(Err(error));
                 }
             }
-            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
-                info,
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                self.rtts.remove(&peer_id);
+                self.connected_peers
+                    .send_modify(|peers| *peers = peers.saturating_sub(1));
+            }
+            SwarmEvent::ConnectionEstablished {
+                num_established, ..
+            } if num_established.get() == 1 => {
+                self.connected_peers.send_modify(|peers| *peers += 1);
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer),
+                error,
                 ..
-            })) => {
+            } if self.pending_self_tests.contains_key(&peer) && !swarm.is_connected(&peer) => {
+                if let Some((_, test)) = self.pending_self_tests.remove(&peer) {
+                    let _ = test.sender.send(Err(error.into()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_behaviour_event<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        event: FileSharingBehaviourEvent,
+    ) {
+        match event {
+            FileSharingBehaviourEvent::Identify(identify::Event::Received { info, .. }) => {
                 let peer_id = info.public_key.to_peer_id();
-                let addr = info.listen_addrs.first().unwrap().clone();
-                self.swarm
-                    .behaviour_mut()
-                    .kademlia
-                    .add_address(&peer_id, addr.clone());
+                self.observed_addrs
+                    .insert(peer_id, info.observed_addr.clone());
+                self.probe_for_self_test(swarm, peer_id);
+                // Parents belong to another network, keep them out of ours.
+                if self.parent_peers.contains(&peer_id) {
+                    return;
+                }
+                // Addresses nobody could dial would only crowd out those
+                // that work.
+                for addr in listen_addr::routable(&info.listen_addrs, &info.observed_addr) {
+                    let update = swarm.disca().kademlia.add_address(&peer_id, addr.clone());
+                    if matches!(update, kad::RoutingUpdate::Success) {
+                        // Nobody listening is fine.
+                        let _ = self
+                            .events
+                            .send(DiscaEvent::PeerDiscovered { peer_id, addr });
+                    }
+                }
             }
-            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
-                kad::Event::OutboundQueryProgressed {
-                    id,
-                    result: QueryResult::StartProviding(result),
-                    ..
-                },
-            )) => {
-                if let Some((_, sender)) = self.pending_start_providing.remove(&id) {
-                    sender
-                        .send(result.map(|_| ()).map_err(|e| e.into()))
-                        .expect("send should work");
+            FileSharingBehaviourEvent::Mdns(mdns::Event::Discovered(peers)) => {
+                for (peer_id, addr) in peers {
+                    if self.parent_peers.contains(&peer_id) {
+                        continue;
+                    }
+                    let update = swarm.disca().kademlia.add_address(&peer_id, addr.clone());
+                    // Connecting right away gets identify and the readiness
+                    // of the node going, as add_peer would. Peers already
+                    // connected or being dialed are left alone.
+                    let _ = swarm.dial(
+                        DialOpts::peer_id(peer_id)
+                            .addresses(vec![addr.clone()])
+                            .build(),
+                    );
+                    if matches!(update, kad::RoutingUpdate::Success) {
+                        let _ = self
+                            .events
+                            .send(DiscaEvent::PeerDiscovered { peer_id, addr });
+                    }
+                }
+            }
+            FileSharingBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                id,
+                result: QueryResult::StartProviding(result),
+                ..
+            }) => {
+                if let Some((_, provide)) = self.pending_start_providing.remove(&id) {
+                    match result {
+                        Ok(_) => {
+                            let _ = provide.sender.send(Ok(()));
+                        }
+                        Err(e) => self.provide_failed(provide, e),
+                    }
                 }
             }
-            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
-                kad::Event::OutboundQueryProgressed {
-                    id,
-                    result: QueryResult::GetProviders(result),
-                    ..
-                },
-            )) => {
-                if let Some((_, sender)) = self.pending_get_providers.remove(&id) {
+            FileSharingBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                id,
+                result: QueryResult::GetProviders(result),
+                stats,
+                ..
+            }) => {
+                if self.pending_exists.contains_key(&id) {
+                    self.exists_progressed(swarm, id, result);
+                } else if self.lookups.contains_key(&id) {
                     match result {
-                        Ok(kad::GetProvidersOk::FoundProviders { key, providers }) => {
-                            let key =
-                                String::from_utf8(key.to_vec()).expect("key should be valid utf8");
-                            self.get_file(key.into(), providers, sender);
+                        Ok(kad::GetProvidersOk::FoundProviders { mut providers, .. }) => {
+                            providers.remove(swarm.local_peer_id());
+                            self.more_providers_found(swarm, id, providers);
+                        }
+                        Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) | Err(_) => {
+                            self.lookup_over(swarm, id, &stats)
+                        }
+                    }
+                } else if let Some((_, (path, sender))) = self.pending_get_providers.remove(&id) {
+                    match result {
+                        // The record key is whatever the network sent back and
+                        // need not be UTF-8, so stick to the key we asked for.
+                        Ok(kad::GetProvidersOk::FoundProviders { mut providers, .. }) => {
+                            // Our own provider records are part of the results,
+                            // but asking ourselves is pointless.
+                            providers.remove(swarm.local_peer_id());
+                            if providers.is_empty() {
+                                self.pending_get_providers.insert(id, (path, sender));
+                                return;
+                            }
+                            // Look on for more providers to ask alongside
+                            // these, asking each as soon as it is found.
+                            if self.hedge_count > 1 {
+                                self.lookups.insert(
+                                    id,
+                                    Lookup {
+                                        key: path.clone(),
+                                        providers: providers.clone(),
+                                    },
+                                );
+                                self.get_file(swarm, path, providers, Some(id), sender);
+                                return;
+                            }
+                            self.providers_found(swarm, id, &stats, path, providers, sender);
                         }
                         Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord {
                             closest_peers: _,
                         }) => {
-                            sender.send(Ok(None)).expect("send should work");
+                            self.providers_found(swarm, id, &stats, path, HashSet::new(), sender);
+                        }
+                        Err(e) if !self.parent_peers.is_empty() => {
+                            self.lookup_finished(&path, id, &stats, 0);
+                            self.try_next_provider(
+                                swarm,
+                                PendingFetch {
+                                    last_error: Some(e.into()),
+                                    ..PendingFetch::new(path, Vec::new(), sender)
+                                },
+                            );
                         }
                         Err(e) => {
-                            sender.send(Err(e.into())).expect("send should work");
+                            self.lookup_finished(&path, id, &stats, 0);
+                            let _ = sender.send(Err(e.into()));
                         }
                     }
                 }
             }
-            SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
-                request_response::Event::Message { peer: _, message },
-            )) => match message {
+            FileSharingBehaviourEvent::RequestResponse(request_response::Event::Message {
+                peer,
+                message,
+            }) => match message {
                 Message::Request {
                     request_id: _,
                     request,
                     channel,
                 } => {
-                    self.handle_request(request, channel);
+                    self.handle_request(peer, request, channel);
                 }
                 Message::Response {
                     request_id,
                     response,
                 } => {
-                    self.handle_response(request_id, response);
+                    self.handle_response(swarm, peer, request_id, response);
+                }
+            },
+            FileSharingBehaviourEvent::RequestResponse(
+                request_response::Event::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                },
+            ) => {
+                if let Some(mut pending) = self.take_fetch(request_id) {
+                    self.reputation.record(peer, Outcome::Failed);
+                    pending.last_error = Some(error.into());
+                    self.try_next_provider(swarm, pending);
+                }
+            }
+            FileSharingBehaviourEvent::Ping(ping::Event {
+                peer,
+                result: Ok(rtt),
+                ..
+            }) => {
+                self.rtts.insert(peer, rtt);
+                self.update_self_test(swarm, peer, |report| report.rtt = Some(rtt));
+            }
+            FileSharingBehaviourEvent::Autonat(autonat::Event::OutboundProbe(probe)) => match probe
+            {
+                autonat::OutboundProbeEvent::Response { peer, .. } => {
+                    self.update_self_test(swarm, peer, |report| report.reachable = Some(true));
+                }
+                autonat::OutboundProbeEvent::Error {
+                    peer: Some(peer),
+                    error: autonat::OutboundProbeError::Response(_),
+                    ..
+                } => {
+                    self.update_self_test(swarm, peer, |report| report.reachable = Some(false));
                 }
+                _ => {}
             },
+            FileSharingBehaviourEvent::Autonat(autonat::Event::StatusChanged { new, .. }) => {
+                let reachability = Reachability::from(new);
+                // Unreachable nodes would only fill the routing tables of
+                // others with entries nobody can use.
+                match reachability {
+                    _ if self.kad_mode != KadMode::Auto => {}
+                    Reachability::Public(_) => {
+                        swarm.disca().kademlia.set_mode(Some(kad::Mode::Server));
+                    }
+                    Reachability::Private => {
+                        swarm.disca().kademlia.set_mode(Some(kad::Mode::Client));
+                    }
+                    Reachability::Unknown => {}
+                }
+                *self
+                    .reachability
+                    .write()
+                    .expect("reachability lock poisoned") = reachability.clone();
+                self.apply_reachability(swarm);
+                let _ = self
+                    .events
+                    .send(DiscaEvent::ReachabilityChanged { reachability });
+            }
             _ => {}
         }
     }
 
+    /// Reads the response off the event loop, see [`EventLoop::offload`].
     fn handle_request(
-        &mut self,
+        &self,
+        peer: PeerId,
         request: FileRequest,
         channel: request_response::ResponseChannel<FileResponse>,
     ) {
-        let file_content = self.file_provider.get_file(request.path);
-        self.swarm
-            .behaviour_mut()
-            .request_response
-            .send_response(
+        if self.read_only {
+            let response = FileResponse {
+                content: None,
+                signature: None,
+                encoding: None,
+                manifest: false,
+                too_large: false,
+                denied: false,
+            };
+            // The receiver lives as long as the event loop.
+            let _ = self.done_sender.send(Work::Respond {
+                peer,
+                key: request.path,
                 channel,
+                response,
+            });
+            return;
+        }
+        let mut file_provider = self.file_provider.clone();
+        let signatures = self.signatures.clone();
+        let token_validator = self.token_validator.clone();
+        self.offload(move || {
+            let allowed = token_validator
+                .is_none_or(|validator| (validator.0)(&peer, request.auth_token.as_deref()));
+            let key = request.path.clone();
+            let response = if allowed {
+                respond(&mut file_provider, &signatures, peer, request)
+            } else {
                 FileResponse {
-                    content: file_content,
+                    content: None,
+                    signature: None,
+                    encoding: None,
+                    manifest: false,
+                    too_large: false,
+                    denied: true,
+                }
+            };
+            Work::Respond {
+                peer,
+                key,
+                channel,
+                response,
+            }
+        });
+    }
+
+    fn handle_response<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        peer: PeerId,
+        request_id: RequestId,
+        response: FileResponse,
+    ) {
+        if let Some(mut pending) = self.take_fetch(request_id) {
+            // The first provider with the file wins.
+            if response.too_large || response.content.is_some() {
+                pending.ignore_hedged();
+            }
+            if response.denied {
+                pending.last_error = Some(anyhow::anyhow!(
+                    "{} did not accept the token sent for {}",
+                    peer,
+                    pending.key
+                ));
+                self.try_next_provider(swarm, pending);
+                return;
+            }
+            if response.too_large {
+                self.too_large(swarm, peer, pending, response.signature);
+                return;
+            }
+            if let Some(ranged) = pending.ranged.take() {
+                self.range_received(swarm, pending, ranged, response.content);
+                return;
+            }
+            if let Some(chunked) = pending.chunked.take() {
+                self.chunks_received(swarm, peer, pending, chunked, response.content);
+                return;
+            }
+            if response.manifest {
+                self.manifest_received(swarm, peer, pending, response);
+                return;
+            }
+            match response.content {
+                Some(content) => self.content_received(
+                    peer,
+                    pending,
+                    content,
+                    response.encoding,
+                    response.signature,
+                ),
+                None => self.had_nothing(swarm, peer, pending),
+            }
+        }
+    }
+
+    /// The fetch that `request_id` was sent for, either the request it
+    /// awaits or one sent alongside, see [`NetworkConfig::hedge_count`].
+    /// Requests sent alongside and still out are in its `hedged` again.
+    fn take_fetch(&mut self, request_id: RequestId) -> Option<PendingFetch> {
+        let pending = match self.pending_get_file.remove(&request_id) {
+            Some((_, pending)) => pending,
+            None => {
+                let awaited = self.hedged.remove(&request_id)?;
+                let (_, mut pending) = self.pending_get_file.remove(&awaited)?;
+                pending.hedged.retain(|(hedged, _)| *hedged != request_id);
+                if let Some((provider, _)) = pending.awaiting {
+                    pending.hedged.push((awaited, provider));
+                }
+                pending
+            }
+        };
+        for (hedged, _) in &pending.hedged {
+            self.hedged.remove(hedged);
+        }
+        Some(pending)
+    }
+
+    /// Checks the whole content of a fetched file off the event loop, see
+    /// [`EventLoop::checked`].
+    fn content_received(
+        &self,
+        peer: PeerId,
+        pending: PendingFetch,
+        content: Vec<u8>,
+        encoding: Option<CompressionAlgorithm>,
+        signature: Option<ContentSignature>,
+    ) {
+        let checker = self.checker.clone();
+        self.offload(move || {
+            let checked = checker.check(&pending.key, peer, content, encoding, signature);
+            Work::Checked {
+                peer,
+                pending: Box::new(pending),
+                checked,
+            }
+        });
+    }
+
+    /// Hands a checked file over, or moves on to the next provider if it did
+    /// not pass.
+    fn checked<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        peer: PeerId,
+        mut pending: PendingFetch,
+        checked: Checked,
+    ) {
+        match checked {
+            Checked::Passed(content) => {
+                self.reputation.record(peer, Outcome::Served);
+                if let Some(id) = pending.lookup {
+                    self.stop_lookup(swarm, id);
+                }
+                let _ = pending.sender.send(Ok(GetOutcome::Found(content)));
+            }
+            Checked::Rejected(e) => {
+                self.reputation.record(peer, Outcome::Served);
+                pending.last_error = Some(e);
+                self.try_next_provider(swarm, pending);
+            }
+            Checked::Corrupt(e) => self.corrupt(swarm, peer, pending, e),
+            Checked::Failed(e) => {
+                pending.last_error = Some(e);
+                self.try_next_provider(swarm, pending);
+            }
+        }
+    }
+
+    fn had_nothing<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        peer: PeerId,
+        mut pending: PendingFetch,
+    ) {
+        self.reputation.record(peer, Outcome::HadNothing);
+        pending.had_nothing.push(peer);
+        self.try_next_provider(swarm, pending);
+    }
+
+    fn corrupt<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        peer: PeerId,
+        mut pending: PendingFetch,
+        error: anyhow::Error,
+    ) {
+        self.reputation.record(peer, Outcome::Corrupt);
+        pending.last_error = Some(error);
+        self.try_next_provider(swarm, pending);
+    }
+
+    /// Starts fetching a file in chunks from the provider that sent its
+    /// manifest, taking the chunks at hand from local files.
+    fn manifest_received<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        peer: PeerId,
+        pending: PendingFetch,
+        response: FileResponse,
+    ) {
+        let manifest = match response.content.as_deref().map(Manifest::decode) {
+            Some(Ok(manifest)) => manifest,
+            Some(Err(e)) => {
+                let e = e.context(format!("manifest of {} from {}", pending.key, peer));
+                self.corrupt(swarm, peer, pending, e);
+                return;
+            }
+            None => {
+                self.had_nothing(swarm, peer, pending);
+                return;
+            }
+        };
+        // Reading, hashing and writing the chunks at hand can take a while,
+        // so it happens off the event loop like answering requests.
+        let mut file_provider = self.file_provider.clone();
+        let file = PartialFile::new(&self.partial_dir, &pending.key);
+        self.offload(move || {
+            let mut chunked = ChunkedFetch {
+                provider: peer,
+                offsets: manifest.offsets(),
+                manifest,
+                signature: response.signature,
+                file,
+                received: Vec::new(),
+                requested: 0..0,
+            };
+            let written = chunked.open(&mut file_provider);
+            Work::Chunked {
+                pending: Box::new(pending),
+                chunked,
+                written,
+            }
+        });
+    }
+
+    /// Goes on with a chunked fetch once the chunks at hand were checked and
+    /// written, or with the next provider if they could not be.
+    fn chunks_written<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        mut pending: PendingFetch,
+        chunked: ChunkedFetch,
+        written: Written,
+    ) {
+        match written {
+            Written::Done => self.request_chunks(swarm, pending, chunked),
+            Written::Corrupt(e) => {
+                let provider = chunked.provider;
+                pending.chunked = Some(chunked);
+                self.corrupt(swarm, provider, pending, e);
+            }
+            Written::Failed(e) => {
+                // The next provider starts over, sending the manifest again.
+                pending.last_error = Some(e);
+                self.try_next_provider(swarm, pending);
+            }
+        }
+    }
+
+    /// Asks the provider for the next run of missing chunks, or puts the
+    /// file together and hands it over once none is missing.
+    fn request_chunks<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        mut pending: PendingFetch,
+        mut chunked: ChunkedFetch,
+    ) {
+        let Some(first) = chunked.received.iter().position(|received| !received) else {
+            let checker = self.checker.clone();
+            self.offload(move || {
+                let peer = chunked.provider;
+                let checked = match chunked.file.commit(&chunked.manifest, &chunked.offsets) {
+                    Ok(content) => {
+                        let checked =
+                            checker.check(&pending.key, peer, content, None, chunked.signature);
+                        // Whatever the verdict, the file is not needed anymore.
+                        chunked.file.remove();
+                        checked
+                    }
+                    Err(e) => Checked::Failed(e),
+                };
+                Work::Checked {
+                    peer,
+                    pending: Box::new(pending),
+                    checked,
+                }
+            });
+            return;
+        };
+        let mut end = first + 1;
+        let mut len = chunked.manifest.chunks[first].len;
+        while end < chunked.received.len()
+            && !chunked.received[end]
+            && len + chunked.manifest.chunks[end].len <= chunking::MAX_CHUNK_REQUEST
+        {
+            len += chunked.manifest.chunks[end].len;
+            end += 1;
+        }
+        let start = chunked.offsets[first];
+        let request = FileRequest {
+            path: pending.key.clone(),
+            range: Some(start..start + len),
+            accept_encodings: Vec::new(),
+            auth_token: None,
+        };
+        let provider = chunked.provider;
+        chunked.requested = first..end;
+        pending.chunked = Some(chunked);
+        self.send_file_request(swarm, provider, request, pending);
+    }
+
+    /// Starts fetching a file that does not fit in a response in ranges from
+    /// the provider that said so, unless that is off or the file already
+    /// comes in parts.
+    fn too_large<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        peer: PeerId,
+        mut pending: PendingFetch,
+        signature: Option<ContentSignature>,
+    ) {
+        if !self.fetch_in_ranges || pending.chunked.is_some() || pending.ranged.is_some() {
+            pending.last_error = Some(anyhow::anyhow!(
+                "{} from {} does not fit in a response",
+                pending.key,
+                peer
+            ));
+            self.try_next_provider(swarm, pending);
+            return;
+        }
+        let ranged = RangedFetch {
+            provider: peer,
+            signature,
+            content: Vec::new(),
+        };
+        self.request_range(swarm, pending, ranged);
+    }
+
+    /// Asks the provider for the range following the content received.
+    fn request_range<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        mut pending: PendingFetch,
+        ranged: RangedFetch,
+    ) {
+        let start = ranged.content.len() as u64;
+        let request = FileRequest {
+            path: pending.key.clone(),
+            range: Some(start..start + codec::RANGE_SIZE_MAXIMUM),
+            accept_encodings: Vec::new(),
+            auth_token: None,
+        };
+        let provider = ranged.provider;
+        pending.ranged = Some(ranged);
+        self.send_file_request(swarm, provider, request, pending);
+    }
+
+    /// Asks `provider` for the file of `pending`, or part of it, and keeps
+    /// the fetch until the response comes or the request times out.
+    fn send_file_request<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        provider: PeerId,
+        request: FileRequest,
+        mut pending: PendingFetch,
+    ) -> RequestId {
+        let request_id = self.send_request(swarm, provider, request, &pending.key);
+        pending.awaiting = Some((provider, Instant::now() + self.request_timeout));
+        self.pending_get_file.insert(request_id, pending);
+        request_id
+    }
+
+    /// Sends `request` to `provider` along with the token of the node, if
+    /// any, see [`NetworkConfig::auth_token`].
+    fn send_request<B: Host>(
+        &self,
+        swarm: &mut Swarm<B>,
+        provider: PeerId,
+        mut request: FileRequest,
+        key: &str,
+    ) -> RequestId {
+        request.auth_token = self.auth_token.clone();
+        let request_id = swarm
+            .disca()
+            .request_response
+            .send_request(&provider, request);
+        let _ = self.events.send(DiscaEvent::FileRequested {
+            key: key.to_owned(),
+            peer_id: provider,
+            request_id,
+        });
+        request_id
+    }
+
+    /// Adds a range to the content received, checking the whole file once a
+    /// range falls short, which only the last one does.
+    fn range_received<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        mut pending: PendingFetch,
+        mut ranged: RangedFetch,
+        content: Option<Vec<u8>>,
+    ) {
+        // Evicted since it said it was too large.
+        let Some(content) = content else {
+            let provider = ranged.provider;
+            pending.ranged = Some(ranged);
+            self.had_nothing(swarm, provider, pending);
+            return;
+        };
+        let last = (content.len() as u64) < codec::RANGE_SIZE_MAXIMUM;
+        ranged.content.extend(content);
+        if last {
+            self.content_received(
+                ranged.provider,
+                pending,
+                ranged.content,
+                None,
+                ranged.signature,
+            );
+        } else {
+            self.request_range(swarm, pending, ranged);
+        }
+    }
+
+    /// Checks a run of chunks against the manifest and goes on with the rest.
+    fn chunks_received<B: Host>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        peer: PeerId,
+        mut pending: PendingFetch,
+        mut chunked: ChunkedFetch,
+        content: Option<Vec<u8>>,
+    ) {
+        // Evicted since it sent the manifest.
+        let Some(content) = content else {
+            pending.chunked = Some(chunked);
+            self.had_nothing(swarm, peer, pending);
+            return;
+        };
+        self.offload(move || {
+            let written = chunked.write_requested(&pending.key, peer, &content);
+            Work::Chunked {
+                pending: Box::new(pending),
+                chunked,
+                written,
+            }
+        });
+    }
+}
+
+/// The answer to `request` from `peer`, from `file_provider`.
+fn respond<T: FileProvider>(
+    file_provider: &mut T,
+    signatures: &DashMap<String, ContentSignature>,
+    peer: PeerId,
+    mut request: FileRequest,
+) -> FileResponse {
+    file_provider.requested_by(peer);
+    if let Some(key) = chunking::manifest_key(&request.path) {
+        let key = key.to_owned();
+        if let Some(manifest) = file_provider.get_manifest(key.clone()) {
+            let signature = signatures
+                .get(&key)
+                .map(|signature| signature.value().clone());
+            return FileResponse {
+                content: Some(manifest),
+                signature,
+                encoding: None,
+                manifest: true,
+                too_large: false,
+                denied: false,
+            };
+        }
+        // Sent whole instead, sparing the requester another round trip.
+        request.path = key;
+    }
+    let (content, signature, encoding) = match request.range {
+        Some(range) => (
+            file_provider.get_file_range(request.path, range),
+            None,
+            None,
+        ),
+        None => {
+            let signature = signatures
+                .get(&request.path)
+                .map(|signature| signature.value().clone());
+            match file_provider.get_file_encoded(request.path, &request.accept_encodings) {
+                Some((content, encoding)) => (Some(content), signature, encoding),
+                None => (None, None, None),
+            }
+        }
+    };
+    FileResponse {
+        content,
+        signature,
+        encoding,
+        manifest: false,
+        too_large: false,
+        denied: false,
+    }
+}
+
+/// How a fetched file fared once checked.
+enum Checked {
+    Passed(Vec<u8>),
+    /// Not what the provider should have sent.
+    Corrupt(anyhow::Error),
+    /// Turned down by [`NetworkConfig::on_fetch`].
+    Rejected(anyhow::Error),
+    /// Could not be put together here, e.g. for a disk error.
+    Failed(anyhow::Error),
+}
+
+/// What came of checking and writing the chunks of a chunked fetch, see
+/// [`Work::Chunked`].
+enum Written {
+    /// Every chunk at hand that matches the manifest is written.
+    Done,
+    /// A chunk the provider sent does not match the manifest. Those before it
+    /// are written.
+    Corrupt(anyhow::Error),
+    /// The chunks could not be written.
+    Failed(anyhow::Error),
+}
+
+/// What checking fetched files takes, apart from the event loop so that it
+/// can run off it. Clones share the signatures.
+#[derive(Clone)]
+struct Checker {
+    signatures: Arc<DashMap<String, ContentSignature>>,
+    trusted_publishers: Arc<HashSet<PeerId>>,
+    on_fetch: Option<FetchInterceptor>,
+}
+
+impl Checker {
+    /// Decompresses `content` of `key` from `peer` and checks it.
+    fn check(
+        &self,
+        key: &str,
+        peer: PeerId,
+        content: Vec<u8>,
+        encoding: Option<CompressionAlgorithm>,
+        signature: Option<ContentSignature>,
+    ) -> Checked {
+        let content = match encoding {
+            Some(encoding) => match compression::decompress(encoding, &content) {
+                Ok(content) => content,
+                Err(e) => {
+                    return Checked::Corrupt(
+                        anyhow::Error::new(e)
+                            .context(format!("decompressing {} from {}", key, peer)),
+                    )
+                }
+            },
+            None => content,
+        };
+        if let Err(e) = self.check_signature(key, &content, signature) {
+            return Checked::Corrupt(e);
+        }
+        match self.intercept(key, content) {
+            Ok(content) => Checked::Passed(content),
+            Err(e) => {
+                Checked::Rejected(e.context(format!("{} from {} rejected on fetch", key, peer)))
+            }
+        }
+    }
+
+    /// Runs the content of `key` through the interceptor, if any. Content it
+    /// changes is no longer what the publisher signed, so its signature is
+    /// not served along with it.
+    fn intercept(&self, key: &str, content: Vec<u8>) -> Result<Vec<u8>> {
+        let Some(on_fetch) = &self.on_fetch else {
+            return Ok(content);
+        };
+        let original = blake3::hash(&content);
+        let content = (on_fetch.0)(key, content)?;
+        if blake3::hash(&content) != original {
+            self.signatures.remove(key);
+        }
+        Ok(content)
+    }
+
+    /// Verifies the signature a provider sent along with `content`, keeping it
+    /// so that it is served again from this node. Content fetched by hash is
+    /// checked against the hash instead.
+    fn check_signature(
+        &self,
+        key: &str,
+        content: &[u8],
+        signature: Option<ContentSignature>,
+    ) -> Result<()> {
+        if let Some(verified) = signing::verify_content_key(key, content) {
+            return verified;
+        }
+        match signature {
+            Some(signature) => {
+                signature.verify(key, content, &self.trusted_publishers)?;
+                self.signatures.insert(key.to_owned(), signature);
+                Ok(())
+            }
+            None if self.trusted_publishers.is_empty() => Ok(()),
+            None => anyhow::bail!("{} is not signed by a trusted publisher", key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryFileProvider;
+
+    /// The event loop of a node and its swarm, neither running, so that the
+    /// test drives the event loop by hand.
+    fn idle_node(
+        config: &NetworkConfig,
+    ) -> (EventLoop<MemoryFileProvider>, Swarm<FileSharingBehaviour>) {
+        let keypair = Keypair::generate_ed25519();
+        let behaviour = FileSharingBehaviour::new(config, &keypair).unwrap();
+        let (_, event_loop) =
+            FileSharingP2P::parts(config, keypair.clone(), MemoryFileProvider::new()).unwrap();
+        let swarm = SwarmBuilder::with_existing_identity(keypair)
+            .with_tokio()
+            .with_other_transport(|keypair| {
+                libp2p::quic::tokio::Transport::new(libp2p::quic::Config::new(keypair))
+            })
+            .unwrap()
+            .with_behaviour(|_| behaviour)
+            .unwrap()
+            .build();
+        (event_loop, swarm)
+    }
+
+    #[tokio::test]
+    async fn providers_under_a_non_utf8_key_are_not_cached() {
+        let (mut event_loop, mut swarm) = idle_node(&NetworkConfig::default());
+        let id = swarm
+            .disca()
+            .kademlia
+            .get_providers(kad::RecordKey::new(&"key"));
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        event_loop.pending_exists.insert(id, sender);
+
+        event_loop.exists_progressed(
+            &mut swarm,
+            id,
+            Ok(kad::GetProvidersOk::FoundProviders {
+                key: kad::RecordKey::new(&[0xff, 0xfe]),
+                providers: HashSet::from([PeerId::random()]),
+            }),
+        );
+
+        assert!(receiver.await.unwrap().unwrap());
+        assert!(event_loop.provider_cache.is_empty());
+    }
+
+    /// A sender whose receiver was dropped, as when the caller of a command
+    /// gave up on it.
+    fn gone<T>() -> tokio::sync::oneshot::Sender<T> {
+        tokio::sync::oneshot::channel().0
+    }
+
+    /// Hands swarm events to the event loop up to the first about a listener
+    /// getting an address, returning true, or closing, returning false.
+    async fn handle_listener_event(
+        event_loop: &mut EventLoop<MemoryFileProvider>,
+        swarm: &mut Swarm<FileSharingBehaviour>,
+    ) -> bool {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let event = swarm.select_next_some().await;
+                let listening = match event {
+                    SwarmEvent::NewListenAddr { .. } => Some(true),
+                    SwarmEvent::ListenerClosed { .. } => Some(false),
+                    _ => None,
+                };
+                event_loop.handle_swarm_event(swarm, event);
+                if let Some(listening) = listening {
+                    return listening;
+                }
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn commands_whose_caller_is_gone_are_answered_quietly() {
+        let (mut event_loop, mut swarm) = idle_node(&NetworkConfig::default());
+        let loopback: Multiaddr = "/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap();
+        event_loop.handle_command(
+            &mut swarm,
+            Command::RemoveFile {
+                path: "key".to_owned(),
+                sender: gone(),
+            },
+        );
+        event_loop.handle_command(
+            &mut swarm,
+            Command::StopListening {
+                addr: "/ip4/127.0.0.1/udp/1/quic-v1".parse().unwrap(),
+                sender: gone(),
+            },
+        );
+        for addr in ["/memory/1".parse().unwrap(), loopback.clone()] {
+            event_loop.handle_command(
+                &mut swarm,
+                Command::StartListening {
+                    addr,
+                    sender: gone(),
                 },
-            )
-            .expect("send should work");
+            );
+        }
+        assert!(handle_listener_event(&mut event_loop, &mut swarm).await);
+
+        // Closed before it got an address.
+        event_loop.handle_command(
+            &mut swarm,
+            Command::StartListening {
+                addr: loopback.clone(),
+                sender: gone(),
+            },
+        );
+        event_loop.handle_command(
+            &mut swarm,
+            Command::StopListening {
+                addr: loopback,
+                sender: gone(),
+            },
+        );
+        assert!(!handle_listener_event(&mut event_loop, &mut swarm).await);
     }
 
-    fn handle_response(&mut self, request_id: RequestId, response: FileResponse) {
-        if let Some((_, sender)) = self.pending_get_file.remove(&request_id) {
-            sender.send(Ok(response.content)).expect("send should work");
+    /// Drives an idle node for good.
+    async fn run(
+        event_loop: &mut EventLoop<MemoryFileProvider>,
+        swarm: &mut Swarm<FileSharingBehaviour>,
+    ) {
+        loop {
+            select! {
+                work = event_loop.next_work() => event_loop.handle_work(swarm, work),
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::Behaviour(event) => event_loop.handle_behaviour_event(swarm, event),
+                    event => event_loop.handle_swarm_event(swarm, event),
+                },
+            }
         }
     }
+
+    #[tokio::test]
+    async fn a_failed_announcement_is_retried_once_peers_connect() {
+        let (mut event_loop, mut swarm) = idle_node(&NetworkConfig {
+            provide_retry: ProvideRetry {
+                initial_backoff: Duration::from_millis(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let key = kad::RecordKey::new(&"key");
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        event_loop.start_providing(
+            &mut swarm,
+            PendingProvide {
+                key: key.clone(),
+                attempt: 1,
+                sender,
+            },
+        );
+        // Kademlia only gives up on a query after a minute, so the first
+        // attempt fails right away as it would without peers to reach.
+        let id = *event_loop
+            .pending_start_providing
+            .iter()
+            .next()
+            .unwrap()
+            .key();
+        let (_, provide) = event_loop.pending_start_providing.remove(&id).unwrap();
+        swarm.disca().kademlia.query_mut(&id).unwrap().finish();
+        event_loop.provide_failed(provide, kad::AddProviderError::Timeout { key });
+
+        let peer = FileSharingP2P::with_config(
+            NetworkConfig {
+                listen_addrs: vec!["/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap()],
+                ..Default::default()
+            },
+            MemoryFileProvider::new(),
+        )
+        .await
+        .unwrap();
+        swarm
+            .disca()
+            .kademlia
+            .add_address(peer.peer_id(), peer.addr().clone());
+        let announced = tokio::time::timeout(Duration::from_secs(30), async {
+            select! {
+                announced = receiver => announced.unwrap(),
+                _ = run(&mut event_loop, &mut swarm) => unreachable!(),
+            }
+        })
+        .await
+        .unwrap();
+        announced.unwrap();
+
+        // The peer stores the announcement once it got to it.
+        tokio::time::timeout(Duration::from_secs(30), async {
+            select! {
+                _ = async {
+                    while !peer.exists("key").await.unwrap() {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                } => {}
+                _ = run(&mut event_loop, &mut swarm) => unreachable!(),
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_last_failed_announcement_is_returned() {
+        let (mut event_loop, _swarm) = idle_node(&NetworkConfig::default());
+        let key = kad::RecordKey::new(&"key");
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+
+        event_loop.provide_failed(
+            PendingProvide {
+                key: key.clone(),
+                attempt: ProvideRetry::default().max_attempts,
+                sender,
+            },
+            kad::AddProviderError::Timeout { key },
+        );
+
+        assert!(receiver.await.unwrap().is_err());
+        assert!(event_loop.provide_retries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn providers_found_later_are_asked_right_away() {
+        let (mut event_loop, mut swarm) = idle_node(&NetworkConfig {
+            hedge_count: 2,
+            ..Default::default()
+        });
+        let id = swarm
+            .disca()
+            .kademlia
+            .get_providers(kad::RecordKey::new(&"key"));
+        let first = PeerId::random();
+        event_loop.lookups.insert(
+            id,
+            Lookup {
+                key: "key".to_owned(),
+                providers: HashSet::from([first]),
+            },
+        );
+        let (sender, _receiver) = tokio::sync::oneshot::channel();
+        event_loop.get_file(
+            &mut swarm,
+            "key".to_owned(),
+            HashSet::from([first]),
+            Some(id),
+            sender,
+        );
+
+        let later = PeerId::random();
+        event_loop.more_providers_found(&mut swarm, id, HashSet::from([first, later]));
+
+        let pending = event_loop.pending_get_file.iter().next().unwrap();
+        assert_eq!(pending.awaiting.map(|(provider, _)| provider), Some(first));
+        assert_eq!(pending.hedged.len(), 1);
+        assert_eq!(pending.hedged[0].1, later);
+        assert!(pending.remaining.is_empty());
+    }
+
+    /// Hands swarm events and work to the event loop until the answer to a
+    /// file request is ready, which is returned instead.
+    async fn until_respond(
+        event_loop: &mut EventLoop<MemoryFileProvider>,
+        swarm: &mut Swarm<FileSharingBehaviour>,
+    ) -> DriverWork {
+        tokio::time::timeout(Duration::from_secs(30), async {
+            loop {
+                select! {
+                    work = event_loop.next_work() => match work {
+                        DriverWork(Work::Respond { .. }) => return work,
+                        work => event_loop.handle_work(swarm, work),
+                    },
+                    event = swarm.select_next_some() => match event {
+                        SwarmEvent::Behaviour(event) => event_loop.handle_behaviour_event(swarm, event),
+                        event => event_loop.handle_swarm_event(swarm, event),
+                    },
+                }
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_response_for_a_requester_gone_is_dropped() {
+        let (mut event_loop, mut swarm) = idle_node(&NetworkConfig::default());
+        event_loop.file_provider.insert("key", b"content".to_vec());
+        let mut events = event_loop.events.subscribe();
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        event_loop.handle_command(
+            &mut swarm,
+            Command::StartListening {
+                addr: "/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap(),
+                sender,
+            },
+        );
+        assert!(handle_listener_event(&mut event_loop, &mut swarm).await);
+        let addr = receiver.await.unwrap().unwrap();
+        let provider = *swarm.local_peer_id();
+
+        let requester = FileSharingP2P::with_config(
+            NetworkConfig {
+                listen_addrs: vec!["/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap()],
+                ..Default::default()
+            },
+            MemoryFileProvider::new(),
+        )
+        .await
+        .unwrap();
+        let fetch = |mut requester: FileSharingP2P| {
+            let addr = addr.clone();
+            async move {
+                requester.add_peer(addr).await?;
+                requester.wait_ready(1, Duration::from_secs(30)).await?;
+                requester.fetch_file_from(provider, "key").await
+            }
+        };
+        let gone = tokio::spawn(fetch(requester.clone()));
+        let respond = until_respond(&mut event_loop, &mut swarm).await;
+
+        // The requester goes away while the answer is being read.
+        swarm.disconnect_peer_id(*requester.peer_id()).unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match swarm.select_next_some().await {
+                    SwarmEvent::ConnectionClosed { peer_id, .. }
+                        if peer_id == *requester.peer_id() =>
+                    {
+                        break
+                    }
+                    SwarmEvent::Behaviour(event) => {
+                        event_loop.handle_behaviour_event(&mut swarm, event)
+                    }
+                    event => event_loop.handle_swarm_event(&mut swarm, event),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        event_loop.handle_work(&mut swarm, respond);
+
+        let dropped = std::iter::from_fn(|| events.try_recv().ok())
+            .find(|event| matches!(event, DiscaEvent::ResponseDropped { .. }));
+        assert_eq!(
+            dropped,
+            Some(DiscaEvent::ResponseDropped {
+                key: "key".to_owned(),
+                peer_id: *requester.peer_id(),
+            })
+        );
+        assert!(!matches!(gone.await.unwrap(), Ok(GetOutcome::Found(_))));
+
+        // The node goes on serving.
+        let fetched = tokio::time::timeout(Duration::from_secs(30), async {
+            select! {
+                fetched = fetch(requester.clone()) => fetched.unwrap(),
+                _ = run(&mut event_loop, &mut swarm) => unreachable!(),
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(fetched, GetOutcome::Found(content) if content == b"content"));
+    }
 }
@@ -0,0 +1,101 @@
+//! How the keys of a cache become the paths of their files on disk.
+
+use std::{fmt::Debug, path::PathBuf};
+
+/// Directory under the root that [`ShardedKeys`] keeps its shards in.
+const SHARDS_DIR: &str = ".objects";
+
+/// Turns keys into paths of files under the root of a cache, separating the
+/// keys from how their files are laid out on disk.
+///
+/// Different keys have to map to different paths, and the same key always
+/// to the same path, also across restarts of the node. A path is either a
+/// single file name not starting with a `.`, or a path inside a directory
+/// whose name starts with a `.`, which is where a cache keeps everything but
+/// its files directly under the root. `.tmp` is taken by the cache itself.
+pub trait KeyMapper: Debug + Send + Sync {
+    /// The path of the file for `key`, relative to the root of the cache.
+    fn path(&self, key: &str) -> PathBuf;
+}
+
+/// Keeps keys as file names, escaping what cannot be part of a file name on
+/// some filesystem, or would make the name mean something else: `/`, `\`,
+/// `:`, `*`, `?`, `"`, `<`, `>`, `|`, control characters, a leading `.` and
+/// a trailing `.` or space become `%` and their byte in hex, as does `%`
+/// itself. Other keys are their own file name. Keys longer than the
+/// filesystem allows for a name still fail to be written, unlike with
+/// [`HashedKeys`]. The default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SanitizedKeys;
+
+impl KeyMapper for SanitizedKeys {
+    fn path(&self, key: &str) -> PathBuf {
+        // `%` alone is never the escape of anything.
+        if key.is_empty() {
+            return PathBuf::from("%");
+        }
+        let last = key.len() - 1;
+        let mut name = String::with_capacity(key.len());
+        for (i, c) in key.char_indices() {
+            let escaped = matches!(
+                c,
+                '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '%'
+            ) || c.is_ascii_control()
+                || (i == 0 && c == '.')
+                || (i == last && (c == '.' || c == ' '));
+            if escaped {
+                name.push_str(&format!("%{:02X}", c as u8));
+            } else {
+                name.push(c);
+            }
+        }
+        PathBuf::from(name)
+    }
+}
+
+impl SanitizedKeys {
+    /// The key that `name` is the file name of, if it is one.
+    pub(crate) fn key(name: &str) -> Option<String> {
+        if name == "%" {
+            return Some(String::new());
+        }
+        let mut key = Vec::with_capacity(name.len());
+        let mut bytes = name.bytes();
+        while let Some(byte) = bytes.next() {
+            if byte != b'%' {
+                key.push(byte);
+                continue;
+            }
+            let hex = [bytes.next()?, bytes.next()?];
+            key.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        }
+        String::from_utf8(key).ok()
+    }
+}
+
+/// Names the file of a key after the BLAKE3 hash of the key, in hex, so that
+/// any key makes a valid name of the same length. The files no longer tell
+/// which key they hold.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HashedKeys;
+
+impl KeyMapper for HashedKeys {
+    fn path(&self, key: &str) -> PathBuf {
+        PathBuf::from(blake3::hash(key.as_bytes()).to_hex().as_str())
+    }
+}
+
+/// Like [`HashedKeys`], but spreads the files over 256 directories named
+/// after the first byte of the hash, as git does with its objects, so that
+/// no directory holds so many files that listing or looking one up slows
+/// down. The directories are under `.objects` in the root.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ShardedKeys;
+
+impl KeyMapper for ShardedKeys {
+    fn path(&self, key: &str) -> PathBuf {
+        let hash = blake3::hash(key.as_bytes()).to_hex();
+        let (shard, name) = hash.split_at(2);
+        [SHARDS_DIR, shard, name].iter().collect()
+    }
+}
@@ -1,52 +1,400 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
 use async_trait::async_trait;
+use dashmap::DashMap;
+use futures::{StreamExt, TryStreamExt};
 
+mod chunking;
+mod codec;
+mod command;
+mod compression;
+mod config;
+#[cfg(feature = "control-socket")]
+pub mod control;
 mod disk_cache;
+mod ephemeral;
+mod error;
+mod events;
 mod file_sharing;
+mod key_mapper;
+mod limited;
+pub mod listen_addr;
+mod metrics;
+mod namespace;
+mod reputation;
+mod resume;
+mod selector;
+mod signing;
+#[cfg(feature = "testing")]
+pub mod testing;
 
-use anyhow::Result;
-pub use disk_cache::DiskCache;
-pub use file_sharing::FileSharingP2P;
+use anyhow::{Context, Result};
+use chunking::ChunkIndex;
+pub use codec::WireFormat;
+pub use command::{CommandClient, CommandReply, CommandRequest, CommandTransport};
+pub use compression::CompressionAlgorithm;
+pub use config::{
+    CacheConfig, ChunkingConfig, ConnectionLimitsConfig, DiscaBuilder, DiscaConfig, IdentifyConfig,
+    KadMode, NetworkConfig, ProvideRetry, QuicConfig,
+};
+pub use disk_cache::{
+    CacheLookup, DiskCache, EntryInfo, EvictionHook, EvictionPolicy, SharedCapacity,
+};
+use disk_cache::{ServeGuard, Serving};
+use ephemeral::EphemeralFiles;
+pub use error::DiscaError;
+pub use events::DiscaEvent;
+pub use file_sharing::{
+    BucketStats, DriverWork, FetchInterceptor, FileSharingBehaviour, FileSharingBehaviourEvent,
+    FileSharingDriver, FileSharingP2P, GetOutcome, Host, KadStats, NetworkStatus, PartialContent,
+    Reachability, SelfTestReport, TokenValidator, SELF_TEST_TIMEOUT,
+};
+pub use key_mapper::{HashedKeys, KeyMapper, SanitizedKeys, ShardedKeys};
 use libp2p::Multiaddr;
-use tokio::fs::File;
+pub use limited::{AccessLog, LimitedFileProvider, ServedFile};
+use metrics::LatencyRecorder;
+pub use metrics::{Latencies, LatencyHistogram};
+pub use namespace::Namespace;
+use namespace::NamespaceNotifier;
+pub use selector::{LowestRtt, ProviderInfo, ProviderSelector, RandomOrder, RoundRobin};
+pub use signing::{content_hash, ContentSignature, HashAlgorithm};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+use tokio_util::sync::CancellationToken;
+
+/// How long [`Disca::wait_for`] waits before looking a key up again, at
+/// first.
+pub const WAIT_FOR_MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long [`Disca::wait_for`] waits at most between two lookups of a key.
+pub const WAIT_FOR_MAX_INTERVAL: Duration = Duration::from_secs(5);
 
+/// Where a node reads the files its peers ask for. Requests are served by
+/// clones of the provider, several at once unless
+/// [`NetworkConfig::offload`] is off, so clones have to share the files.
 #[async_trait]
 pub trait FileProvider {
     fn get_file(&mut self, path: String) -> Option<Vec<u8>>;
+
+    /// Called on the clone serving a request, with the peer that made it,
+    /// before anything is read. Does nothing by default.
+    fn requested_by(&mut self, peer: libp2p::PeerId) {
+        let _ = peer;
+    }
+
+    /// The content of `path` for a peer that accepts it compressed with any
+    /// of `accept_encodings`, along with the compression it is in. Meant for
+    /// providers storing files compressed, which can then send them as they
+    /// are. By default, and whenever none of the encodings is at hand, the
+    /// content goes uncompressed, which every peer accepts.
+    fn get_file_encoded(
+        &mut self,
+        path: String,
+        accept_encodings: &[CompressionAlgorithm],
+    ) -> Option<(Vec<u8>, Option<CompressionAlgorithm>)> {
+        let _ = accept_encodings;
+        Some((self.get_file(path)?, None))
+    }
+
+    /// The bytes of `path` within `range`, for peers asking for part of a
+    /// file. A range running past the end of the file stops there, and one
+    /// starting past it is empty. By default the whole file is read and
+    /// sliced; providers that can read part of a file should do so instead.
+    fn get_file_range(&mut self, path: String, range: Range<u64>) -> Option<Vec<u8>> {
+        let content = self.get_file(path)?;
+        Some(content[clamp_range(range, content.len() as u64)].to_vec())
+    }
+
+    /// The list of chunks of `path`, for peers fetching it in chunks, see
+    /// [`NetworkConfig::chunking`]. By default there is none, and peers
+    /// asking for it get the whole file instead.
+    fn get_manifest(&mut self, path: String) -> Option<Vec<u8>> {
+        let _ = path;
+        None
+    }
+
+    /// A chunk with `hash` from any local file, so that fetching a file in
+    /// chunks only asks peers for those that cannot be had here. The content
+    /// is checked against the hash. None by default.
+    fn get_chunk(&mut self, hash: &[u8]) -> Option<Vec<u8>> {
+        let _ = hash;
+        None
+    }
 }
 
+/// Clamps `range` to a file of `len` bytes, as indices into its content.
+fn clamp_range(range: Range<u64>, len: u64) -> Range<usize> {
+    let end = range.end.min(len);
+    let start = range.start.min(end);
+    start as usize..end as usize
+}
+
+/// Copies a cached `file` to `writer`, see [`Disca::get_to_writer`].
+async fn copy_to_writer<W: AsyncWrite + Unpin>(mut file: File, writer: &mut W) -> Result<bool> {
+    tokio::io::copy(&mut file, writer)
+        .await
+        .context("writing the file to the writer")?;
+    writer.flush().await?;
+    Ok(true)
+}
+
+/// Writes fetched `content` to `writer`, see [`Disca::get_to_writer`].
+async fn write_to_writer<W: AsyncWrite + Unpin>(content: &[u8], writer: &mut W) -> Result<bool> {
+    writer
+        .write_all(content)
+        .await
+        .context("writing the file to the writer")?;
+    writer.flush().await?;
+    Ok(true)
+}
+
+/// Told by a [`DiskCache`] about the files it gains and loses. An error fails
+/// the cache operation that caused it, though the file it is about stays
+/// cached, or gone, all the same.
 #[async_trait]
 pub trait FileNotifier {
-    async fn added(&self, path: String);
-    async fn removed(&self, path: String);
+    async fn added(&self, path: String) -> Result<()>;
+    async fn removed(&self, path: String) -> Result<()>;
 }
 
 #[async_trait]
 impl FileNotifier for FileSharingP2P {
-    async fn added(&self, path: String) {
-        self.add_file(path).await.unwrap();
+    async fn added(&self, path: String) -> Result<()> {
+        let announced = self.add_file(path).await;
+        // The file is cached all the same, and announced again once the
+        // node is back on the network.
+        if !self.local_fallback() {
+            announced?;
+        }
+        Ok(())
     }
 
-    async fn removed(&self, path: String) {
-        self.remove_file(path).await.unwrap();
+    async fn removed(&self, path: String) -> Result<()> {
+        let unannounced = self.remove_file(path).await;
+        if !self.local_fallback() {
+            unannounced?;
+        }
+        Ok(())
     }
 }
 
 pub struct Disca {
     file_sharing: FileSharingP2P,
     disk_cache: DiskCache<FileSharingP2P>,
+    sign_published: bool,
+    max_concurrent_fetches: usize,
+    stale_while_revalidate: bool,
+    revalidating: HashSet<String>,
+    revalidated_sender: tokio::sync::mpsc::UnboundedSender<(String, Option<Vec<u8>>)>,
+    revalidated: tokio::sync::mpsc::UnboundedReceiver<(String, Option<Vec<u8>>)>,
+    hash_algorithm: HashAlgorithm,
+    index_by_hash: bool,
+    /// Content key of every indexed file to the key it is cached under.
+    by_hash: Arc<DashMap<String, String>>,
+    latencies: LatencyRecorder,
+    root: std::path::PathBuf,
+    /// What the node runs with, and namespaces are created with.
+    config: DiscaConfig,
+    namespaces: HashMap<String, Namespace>,
+    /// Shared with the file provider, so that it keeps the files of every
+    /// namespace from being evicted while it reads them.
+    namespace_serving: Arc<DashMap<String, Serving>>,
+    /// Shared with the file provider, which serves manifests and reads
+    /// chunks from it.
+    chunks: Option<ChunkIndex>,
+    /// Shared with the file provider, which serves them.
+    ephemeral: EphemeralFiles,
 }
 
+#[derive(Clone)]
 pub struct DiscaFileProvider {
     root: std::path::PathBuf,
+    key_mapper: Arc<dyn KeyMapper>,
+    by_hash: Arc<DashMap<String, String>>,
+    /// Keeps files from being evicted while they are read.
+    serving: Serving,
+    /// How the cache compresses its files, which are sent decompressed.
+    compression: Option<CompressionAlgorithm>,
+    namespace_serving: Arc<DashMap<String, Serving>>,
+    chunks: Option<ChunkIndex>,
+    /// Served before the files on disk.
+    ephemeral: EphemeralFiles,
+}
+
+impl DiscaFileProvider {
+    /// Where the file cached as `key` is, in a namespace if `key` is scoped
+    /// to one.
+    fn path(&self, key: &str) -> std::path::PathBuf {
+        match key.split_once('/') {
+            Some((namespace, key)) => self.root.join(namespace).join(self.key_mapper.path(key)),
+            None => self.root.join(self.key_mapper.path(key)),
+        }
+    }
+
+    /// Keeps the file cached as `key` from being evicted until the guard is
+    /// dropped, see [`Serving::start`].
+    fn start_serving(&self, key: &str) -> Option<ServeGuard> {
+        match key.split_once('/') {
+            Some((namespace, key)) => self.namespace_serving.get(namespace)?.start(key),
+            None => self.serving.start(key),
+        }
+    }
+
+    /// The key the file for `key` is cached under.
+    fn cached_as(&self, key: String) -> String {
+        match self.by_hash.get(&key) {
+            Some(cached_as) => cached_as.value().clone(),
+            None => key,
+        }
+    }
 }
 
 impl FileProvider for DiscaFileProvider {
     fn get_file(&mut self, path: String) -> Option<Vec<u8>> {
-        let path = self.root.join(path);
-        std::fs::read(path).ok()
+        if let Some(content) = self.ephemeral.get(&path) {
+            return Some(content);
+        }
+        let key = self.cached_as(path);
+        let _serving = self.start_serving(&key)?;
+        let content = std::fs::read(self.path(&key)).ok()?;
+        match self.compression {
+            Some(algorithm) => compression::decompress(algorithm, &content).ok(),
+            None => Some(content),
+        }
+    }
+
+    /// Sends the file as stored when the peer accepts the compression of the
+    /// cache, without decompressing it.
+    fn get_file_encoded(
+        &mut self,
+        path: String,
+        accept_encodings: &[CompressionAlgorithm],
+    ) -> Option<(Vec<u8>, Option<CompressionAlgorithm>)> {
+        match self.compression {
+            Some(algorithm)
+                if accept_encodings.contains(&algorithm) && !self.ephemeral.contains_key(&path) =>
+            {
+                let key = self.cached_as(path);
+                let _serving = self.start_serving(&key)?;
+                let content = std::fs::read(self.path(&key)).ok()?;
+                Some((content, Some(algorithm)))
+            }
+            _ => Some((self.get_file(path)?, None)),
+        }
+    }
+
+    /// Reads only the requested bytes, unless the file is compressed.
+    fn get_file_range(&mut self, path: String, range: Range<u64>) -> Option<Vec<u8>> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        if self.compression.is_some() || self.ephemeral.contains_key(&path) {
+            let content = self.get_file(path)?;
+            return Some(content[clamp_range(range, content.len() as u64)].to_vec());
+        }
+        let key = self.cached_as(path);
+        let _serving = self.start_serving(&key)?;
+        let mut file = std::fs::File::open(self.path(&key)).ok()?;
+        let range = clamp_range(range, file.metadata().ok()?.len());
+        file.seek(SeekFrom::Start(range.start as u64)).ok()?;
+        let mut content = Vec::with_capacity(range.len());
+        file.take(range.len() as u64)
+            .read_to_end(&mut content)
+            .ok()?;
+        Some(content)
+    }
+
+    /// Only files still cached have a manifest, as their chunks are read
+    /// from them.
+    fn get_manifest(&mut self, path: String) -> Option<Vec<u8>> {
+        let key = self.cached_as(path);
+        let manifest = self.chunks.as_ref()?.manifest(&key)?;
+        self.path(&key).is_file().then_some(manifest)
+    }
+
+    fn get_chunk(&mut self, hash: &[u8]) -> Option<Vec<u8>> {
+        let (key, range) = self.chunks.as_ref()?.location(hash)?;
+        self.get_file_range(key, range)
+    }
+}
+
+/// Serves files from memory instead of a directory, e.g. generated content
+/// or nodes without a disk. Clones share their files, so a clone kept after
+/// handing the provider to [`FileSharingP2P::with_config`] can still add and
+/// remove them.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFileProvider {
+    files: Arc<DashMap<String, Vec<u8>>>,
+}
+
+impl MemoryFileProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or overwrites a file, returning its previous content.
+    pub fn insert(&self, key: impl Into<String>, content: Vec<u8>) -> Option<Vec<u8>> {
+        self.files.insert(key.into(), content)
+    }
+
+    pub fn remove(&self, key: &str) -> Option<Vec<u8>> {
+        self.files.remove(key).map(|(_, content)| content)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.files.contains_key(key)
+    }
+}
+
+impl FileProvider for MemoryFileProvider {
+    fn get_file(&mut self, path: String) -> Option<Vec<u8>> {
+        self.files.get(&path).map(|content| content.value().clone())
+    }
+
+    fn get_file_range(&mut self, path: String, range: Range<u64>) -> Option<Vec<u8>> {
+        let content = self.files.get(&path)?;
+        Some(content[clamp_range(range, content.len() as u64)].to_vec())
     }
 }
 
+/// Reports evictions on the event channel of the node, and forgets the
+/// chunks of evicted files.
+fn eviction_event_hook(
+    events: tokio::sync::broadcast::Sender<DiscaEvent>,
+    chunks: Option<ChunkIndex>,
+) -> EvictionHook {
+    Box::new(move |key, size| {
+        if let Some(chunks) = &chunks {
+            chunks.forget(key);
+        }
+        let _ = events.send(DiscaEvent::Evicted {
+            key: key.to_owned(),
+            size,
+        });
+    })
+}
+
+/// Reports evictions from a namespace under the keys its files go by on the
+/// network.
+fn namespace_eviction_event_hook(
+    namespace: &str,
+    events: tokio::sync::broadcast::Sender<DiscaEvent>,
+) -> EvictionHook {
+    let namespace = namespace.to_owned();
+    Box::new(move |key, size| {
+        let _ = events.send(DiscaEvent::Evicted {
+            key: namespace::scoped(&namespace, key),
+            size,
+        });
+    })
+}
+
 impl Disca {
     pub async fn new<P: Into<std::path::PathBuf>>(
         root: P,
@@ -54,46 +402,1128 @@ impl Disca {
         capacity: u64,
         addr: Multiaddr,
     ) -> Result<Self> {
-        let root = root.into();
-        let file_sharing =
-            FileSharingP2P::new(addr, DiscaFileProvider { root: root.clone() }).await?;
+        Self::builder(root)
+            .files_to_evict(files_to_evict)
+            .capacity(capacity)
+            .listen_addr(addr)
+            .build()
+            .await
+    }
+
+    pub fn builder<P: Into<std::path::PathBuf>>(root: P) -> DiscaBuilder {
+        DiscaBuilder::new(root)
+    }
+
+    pub async fn with_config(config: DiscaConfig) -> Result<Self> {
+        let DiscaConfig {
+            root,
+            cache,
+            network,
+        } = config;
+        let sign_published = network.sign_published;
+        let max_concurrent_fetches = network.max_concurrent_fetches.max(1);
+        let hash_algorithm = network.hash_algorithm;
+        let index_by_hash = network.index_by_hash;
+        cache.validate()?;
+        if let Some(chunking) = &network.chunking {
+            chunking.validate()?;
+        }
+        let chunks = network
+            .chunking
+            .clone()
+            .map(|chunking| ChunkIndex::new(chunking, hash_algorithm));
+        // Checked before the network starts, so that a bad root does not leave
+        // a listening node behind.
+        tokio::fs::create_dir_all(&root)
+            .await
+            .with_context(|| format!("creating cache root {}", root.display()))?;
+        let by_hash = Arc::new(DashMap::new());
+        let ephemeral = EphemeralFiles::new(cache.ephemeral_capacity);
+        let serving = Serving::default();
+        let namespace_serving = Arc::new(DashMap::new());
+        let file_provider = DiscaFileProvider {
+            root: root.clone(),
+            key_mapper: cache.key_mapper.clone(),
+            by_hash: by_hash.clone(),
+            compression: cache.compress_at_rest,
+            serving: serving.clone(),
+            namespace_serving: namespace_serving.clone(),
+            chunks: chunks.clone(),
+            ephemeral: ephemeral.clone(),
+        };
+        let file_provider = LimitedFileProvider::new(file_provider)
+            .with_max_size(network.max_served_size)
+            .with_access_log(network.on_served.clone());
+        let mut fetching = network.clone();
+        fetching
+            .partial_dir
+            .get_or_insert_with(|| root.join(resume::PARTIAL_DIR));
+        let file_sharing = FileSharingP2P::with_config(fetching, file_provider).await?;
 
-        let disk_cache = DiskCache::new(root, files_to_evict, capacity, file_sharing.clone());
+        let disk_cache = DiskCache::new(
+            root.clone(),
+            cache.files_to_evict,
+            cache.capacity,
+            file_sharing.clone(),
+        )?
+        .with_durable(cache.durable)
+        .with_ttl(cache.ttl)
+        .with_eviction_policy(cache.eviction_policy)
+        .with_dedup(cache.dedup)
+        .with_verify_checksums(cache.verify_local_checksums)
+        .with_compression(cache.compress_at_rest)
+        .with_shared_capacity(cache.shared_capacity.clone())
+        .with_serving(serving)
+        .with_key_mapper(cache.key_mapper.clone())
+        .with_on_evict(eviction_event_hook(file_sharing.events(), chunks.clone()));
+        let (revalidated_sender, revalidated) = tokio::sync::mpsc::unbounded_channel();
+        let latencies = file_sharing.latencies.clone();
         Ok(Self {
             file_sharing,
             disk_cache,
+            sign_published,
+            max_concurrent_fetches,
+            stale_while_revalidate: cache.stale_while_revalidate,
+            revalidating: HashSet::new(),
+            revalidated_sender,
+            revalidated,
+            hash_algorithm,
+            index_by_hash,
+            by_hash,
+            latencies,
+            config: DiscaConfig {
+                root: root.clone(),
+                cache,
+                network,
+            },
+            root,
+            namespaces: HashMap::new(),
+            namespace_serving,
+            chunks,
+            ephemeral,
         })
     }
 
-    pub async fn get(&mut self, path: String) -> Result<Option<File>> {
-        let file = self.disk_cache.get(&path).await?;
-        if let Some(file) = file {
-            return Ok(Some(file));
+    /// Creates the namespace `name`, a cache of its own within this node, see
+    /// [`Namespace`]. Files are kept in the `name` directory under the root
+    /// and count towards `capacity`, or the capacity of the node's cache if
+    /// unset, as well as any shared capacity, of which the bytes reserved for
+    /// it in [`CacheConfig::namespace_reservations`] are then set aside for
+    /// it. The other cache settings are
+    /// those of the node, apart from dedup, time to live and indexing by
+    /// hash, which namespaces do without.
+    pub fn add_namespace(&mut self, name: &str, capacity: Option<u64>) -> Result<Namespace> {
+        namespace::validate_name(name)?;
+        if self.namespaces.contains_key(name) {
+            anyhow::bail!("namespace {} already exists", name);
+        }
+        let cache = &self.config.cache;
+        let capacity = capacity.unwrap_or(cache.capacity);
+        let reserved = cache
+            .namespace_reservations
+            .get(name)
+            .copied()
+            .unwrap_or_default();
+        if reserved > capacity {
+            anyhow::bail!(
+                "namespace {} has {} bytes reserved, more than its capacity of {} bytes",
+                name,
+                reserved,
+                capacity
+            );
+        }
+        let serving = Serving::default();
+        let disk_cache = DiskCache::new(
+            self.root.join(name),
+            cache.files_to_evict,
+            capacity,
+            NamespaceNotifier {
+                namespace: name.to_owned(),
+                file_sharing: self.file_sharing.clone(),
+            },
+        )?
+        .with_durable(cache.durable)
+        .with_eviction_policy(cache.eviction_policy)
+        .with_verify_checksums(cache.verify_local_checksums)
+        .with_compression(cache.compress_at_rest)
+        .with_shared_capacity(cache.shared_capacity.clone())
+        .with_reserved_capacity(reserved)
+        .with_serving(serving.clone())
+        .with_key_mapper(cache.key_mapper.clone())
+        .with_on_evict(namespace_eviction_event_hook(
+            name,
+            self.file_sharing.events(),
+        ));
+        let namespace = Namespace {
+            name: name.to_owned(),
+            cache: Arc::new(disk_cache),
+            file_sharing: self.file_sharing.clone(),
+            sign_published: self.sign_published,
+            latencies: self.latencies.clone(),
+        };
+        self.namespace_serving.insert(name.to_owned(), serving);
+        self.namespaces.insert(name.to_owned(), namespace.clone());
+        Ok(namespace)
+    }
+
+    /// The namespace `name`, if it was created.
+    pub fn namespace(&self, name: &str) -> Option<Namespace> {
+        self.namespaces.get(name).cloned()
+    }
+
+    pub async fn get(&mut self, path: &str) -> Result<Option<File>> {
+        Ok(self.get_outcome(path).await?.into_option())
+    }
+
+    /// Like [`Disca::get`], but on a miss tells apart "nobody advertises this
+    /// key" from "the advertised providers did not have it". With
+    /// [`NetworkConfig::allow_partial`], also hands over what came of a file
+    /// whose fetch failed part way, unless a stale copy is cached.
+    pub async fn get_outcome(&mut self, path: &str) -> Result<GetOutcome<File>> {
+        self.get_outcome_by(path, None).await
+    }
+
+    /// Like [`Disca::get`], but gives up on fetching from the network once
+    /// `budget` has passed and returns `None`, or the stale copy if there is
+    /// one. Unlike the request timeout this bounds the whole lookup, and the
+    /// abandoned fetch is cancelled rather than left running.
+    pub async fn get_with_deadline(
+        &mut self,
+        path: &str,
+        budget: Duration,
+    ) -> Result<Option<File>> {
+        let deadline = tokio::time::Instant::now() + budget;
+        Ok(self
+            .get_outcome_by(path, Some(deadline))
+            .await?
+            .into_option())
+    }
+
+    /// Writes the content of `path` to `writer` rather than handing over a
+    /// cached file, e.g. to stream it into a response body. Returns whether
+    /// the file was found; nothing is written otherwise. A cached or
+    /// ephemeral copy is copied over as is; a file fetched from the network
+    /// is written once its whole content has been checked, so that no corrupt
+    /// byte reaches `writer`, and is then dropped without being cached. Use
+    /// [`Disca::get_to_writer_cached`] to keep it.
+    pub async fn get_to_writer<W: AsyncWrite + Unpin>(
+        &mut self,
+        path: &str,
+        writer: &mut W,
+    ) -> Result<bool> {
+        namespace::check_key(path)?;
+        self.apply_revalidations().await?;
+
+        let started = Instant::now();
+        let lookup = self.disk_cache.lookup(path).await?;
+        if !matches!(lookup, CacheLookup::Missing) {
+            self.latencies.local_get(started.elapsed());
+        }
+        let stale = match lookup {
+            CacheLookup::Fresh(file) => return copy_to_writer(file, writer).await,
+            CacheLookup::Stale(file) if self.stale_while_revalidate => {
+                self.revalidate_in_background(path);
+                return copy_to_writer(file, writer).await;
+            }
+            CacheLookup::Stale(file) => Some(file),
+            CacheLookup::Missing => None,
+        };
+        if stale.is_none() {
+            if let Some(content) = self.ephemeral.get(path) {
+                return write_to_writer(&content, writer).await;
+            }
+        }
+
+        match self.fetch_by(path, None).await {
+            Ok(GetOutcome::Found(content)) => write_to_writer(&content, writer).await,
+            // A stale copy beats no copy when nobody has a newer one.
+            outcome => match stale {
+                Some(file) => copy_to_writer(file, writer).await,
+                None => outcome.map(|_| false),
+            },
+        }
+    }
+
+    /// Like [`Disca::get_to_writer`], but caches a file fetched from the
+    /// network as [`Disca::get`] does, writing it to `writer` from disk.
+    pub async fn get_to_writer_cached<W: AsyncWrite + Unpin>(
+        &mut self,
+        path: &str,
+        writer: &mut W,
+    ) -> Result<bool> {
+        match self.get(path).await? {
+            Some(file) => copy_to_writer(file, writer).await,
+            None => Ok(false),
+        }
+    }
+
+    async fn get_outcome_by(
+        &mut self,
+        path: &str,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<GetOutcome<File>> {
+        // Keys with a `/` belong to namespaces, see [`Disca::add_namespace`].
+        namespace::check_key(path)?;
+        self.apply_revalidations().await?;
+
+        let started = Instant::now();
+        let lookup = self.disk_cache.lookup(path).await?;
+        if !matches!(lookup, CacheLookup::Missing) {
+            self.latencies.local_get(started.elapsed());
+        }
+        match lookup {
+            CacheLookup::Fresh(file) => return Ok(GetOutcome::Found(file)),
+            CacheLookup::Stale(file) if self.stale_while_revalidate => {
+                self.revalidate_in_background(path);
+                return Ok(GetOutcome::Found(file));
+            }
+            CacheLookup::Stale(file) => {
+                // A stale copy beats no copy when nobody has a newer one.
+                if let Ok(GetOutcome::Found(content)) = self.fetch_by(path, deadline).await {
+                    self.cache_fetched(path, &content).await?;
+                    if let Some(file) = self.disk_cache.get(path).await? {
+                        return Ok(GetOutcome::Found(file));
+                    }
+                }
+                return Ok(GetOutcome::Found(file));
+            }
+            CacheLookup::Missing => {}
+        }
+
+        // Read locally, an ephemeral file becomes a regular cached one.
+        if let Some(content) = self.ephemeral.remove(path) {
+            self.cache_fetched(path, &content).await?;
+            if let Some(file) = self.disk_cache.get(path).await? {
+                return Ok(GetOutcome::Found(file));
+            }
+        }
+
+        match self.fetch_by(path, deadline).await? {
+            GetOutcome::Found(file_content) => {
+                self.cache_fetched(path, &file_content).await?;
+                let file = self.disk_cache.get(path).await?;
+                Ok(file.map_or(GetOutcome::NoProviders, GetOutcome::Found))
+            }
+            GetOutcome::NoProviders => Ok(GetOutcome::NoProviders),
+            GetOutcome::ProvidersHadNothing { peers } => {
+                Ok(GetOutcome::ProvidersHadNothing { peers })
+            }
+            // Never cached, as it would then be served as the whole file.
+            GetOutcome::Partial(partial) => Ok(GetOutcome::Partial(partial)),
+        }
+    }
+
+    /// Fetches `path` from the network, giving up once `deadline` passed. A
+    /// fetch that misses its deadline counts as finding no provider; dropping
+    /// it makes the event loop abandon the lookup.
+    async fn fetch_by(
+        &mut self,
+        path: &str,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<GetOutcome<Vec<u8>>> {
+        let started = Instant::now();
+        let fetch = self.file_sharing.fetch_file(path);
+        let outcome = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, fetch)
+                .await
+                .unwrap_or(Ok(GetOutcome::NoProviders)),
+            None => fetch.await,
+        };
+        self.latencies.network_fetch(started.elapsed());
+        match outcome {
+            Err(_) if self.file_sharing.local_fallback() => Ok(GetOutcome::NoProviders),
+            outcome => outcome,
+        }
+    }
+
+    /// Refetches a stale `key` without waiting for it. The result is picked up
+    /// by [`Disca::apply_revalidations`]; the stale copy is kept if nobody has
+    /// the file anymore.
+    fn revalidate_in_background(&mut self, key: &str) {
+        if !self.revalidating.insert(key.to_owned()) {
+            return;
+        }
+        let mut file_sharing = self.file_sharing.clone();
+        let revalidated = self.revalidated_sender.clone();
+        let key = key.to_owned();
+        tokio::spawn(async move {
+            let content = match file_sharing.fetch_file(key.clone()).await {
+                Ok(GetOutcome::Found(content)) => Some(content),
+                _ => None,
+            };
+            let _ = revalidated.send((key, content));
+        });
+    }
+
+    /// Replaces stale files with the content refetched in the background.
+    async fn apply_revalidations(&mut self) -> Result<()> {
+        while let Ok((key, content)) = self.revalidated.try_recv() {
+            self.revalidating.remove(&key);
+            if let Some(content) = content {
+                if self.disk_cache.contains_key(&key) {
+                    self.cache_fetched(&key, &content).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `key` can be had without fetching it: `true` right away if it
+    /// is cached here, otherwise whether any peer announces it, see
+    /// [`FileSharingP2P::exists`]. Cheaper than [`Disca::get`], as nothing is
+    /// transferred, but a peer announcing a key may still fail to serve it.
+    pub async fn exists(&self, key: &str) -> Result<bool> {
+        if self.disk_cache.contains_key(key) {
+            return Ok(true);
+        }
+        match self.file_sharing.exists(key).await {
+            Err(_) if self.file_sharing.local_fallback() => Ok(false),
+            exists => exists,
+        }
+    }
+
+    /// Waits until `key` is available, i.e. until [`Disca::exists`] says so,
+    /// e.g. to block until another node in the cluster produced it. Nodes do
+    /// not tell each other about new files, so the DHT is polled, every
+    /// [`WAIT_FOR_MIN_INTERVAL`] at first and backing off up to
+    /// [`WAIT_FOR_MAX_INTERVAL`] between lookups. Waits forever if nobody
+    /// ever provides the key; see [`Disca::wait_for_timeout`].
+    pub async fn wait_for(&self, key: &str) -> Result<()> {
+        let mut interval = WAIT_FOR_MIN_INTERVAL;
+        while !self.exists(key).await? {
+            tokio::time::sleep(interval).await;
+            interval = (interval * 2).min(WAIT_FOR_MAX_INTERVAL);
+        }
+        Ok(())
+    }
+
+    /// Like [`Disca::wait_for`], giving up after `timeout`. Returns whether
+    /// the key became available in time.
+    pub async fn wait_for_timeout(&self, key: &str, timeout: Duration) -> Result<bool> {
+        match tokio::time::timeout(timeout, self.wait_for(key)).await {
+            Ok(waited) => waited.map(|()| true),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Fetches `path` straight from `peer`, skipping the DHT lookup, e.g.
+    /// when an index kept elsewhere says where the file is. The file is
+    /// cached like a fetched one. `None` if the peer does not have it; an
+    /// error if it cannot be reached. See [`FileSharingP2P::fetch_file_from`].
+    pub async fn get_from_peer(
+        &mut self,
+        peer: libp2p::PeerId,
+        path: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        let started = Instant::now();
+        let outcome = self.file_sharing.fetch_file_from(peer, path).await;
+        self.latencies.network_fetch(started.elapsed());
+        let Some(content) = outcome?.into_option() else {
+            return Ok(None);
+        };
+        self.cache_fetched(path, &content).await?;
+        Ok(Some(content))
+    }
+
+    /// Returns the file for `key`, computing it with `f` only if it is neither
+    /// cached locally nor available from any peer. The computed content is
+    /// cached and announced like [`Disca::add`] does.
+    ///
+    /// If `f` fails its error is returned and nothing is cached. Since this
+    /// takes `&mut self`, concurrent callers on the same node are serialized
+    /// and `f` runs at most once per miss.
+    pub async fn get_or_insert_with<F, Fut>(&mut self, key: &str, f: F) -> Result<File>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<Vec<u8>>>,
+    {
+        if let Some(file) = self.get(key).await? {
+            return Ok(file);
+        }
+
+        let content = f().await?;
+        self.insert(key, &content).await?;
+        self.disk_cache
+            .get(key)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("{} was not kept in the cache after inserting it", key))
+    }
+
+    /// Fetches every key that is not cached yet from the network, keeping at
+    /// most `max_concurrent_fetches` fetches in flight. Keys that cannot be
+    /// fetched are skipped; returns how many were added to the cache.
+    pub async fn prefetch<I, S>(&mut self, keys: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.prefetch_cancellable(keys, &CancellationToken::new())
+            .await
+    }
+
+    /// Like [`Disca::prefetch`], but stops once `cancel` is cancelled, e.g.
+    /// on shutdown: no further key is fetched, the fetches in flight are
+    /// dropped along with what the event loop keeps for them, and how many
+    /// keys were added to the cache until then is returned.
+    pub async fn prefetch_cancellable<I, S>(
+        &mut self,
+        keys: I,
+        cancel: &CancellationToken,
+    ) -> Result<usize>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let missing = keys
+            .into_iter()
+            .map(Into::into)
+            .filter(|key| !self.disk_cache.contains_key(key))
+            .collect::<Vec<_>>();
+
+        let mut fetches = self.fetch_concurrently(missing);
+        let mut fetched = 0;
+        loop {
+            let next = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => break,
+                next = fetches.next() => next,
+            };
+            let Some((key, outcome)) = next else {
+                break;
+            };
+            if let Ok(GetOutcome::Found(content)) = outcome {
+                self.cache_fetched(&key, &content).await?;
+                fetched += 1;
+            }
+        }
+        Ok(fetched)
+    }
+
+    /// Gets many keys at once. Cached keys are looked up first, then the rest
+    /// are fetched from the network with at most `max_concurrent_fetches`
+    /// fetches in flight, instead of one after the other as separate calls to
+    /// [`Disca::get`] would.
+    ///
+    /// Results are in the order of `keys`. A key that cannot be fetched maps
+    /// to `None` rather than failing the whole batch; stale files whose
+    /// refetch fails are returned as they are.
+    pub async fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<(String, Option<File>)>> {
+        self.apply_revalidations().await?;
+
+        let mut results = Vec::with_capacity(keys.len());
+        let mut to_fetch = Vec::new();
+        for key in keys {
+            let started = Instant::now();
+            let lookup = self.disk_cache.lookup(&key).await?;
+            if !matches!(lookup, CacheLookup::Missing) {
+                self.latencies.local_get(started.elapsed());
+            }
+            let file = match lookup {
+                CacheLookup::Fresh(file) => Some(file),
+                CacheLookup::Stale(file) if self.stale_while_revalidate => {
+                    self.revalidate_in_background(&key);
+                    Some(file)
+                }
+                CacheLookup::Stale(file) => {
+                    to_fetch.push(key.clone());
+                    Some(file)
+                }
+                CacheLookup::Missing => {
+                    to_fetch.push(key.clone());
+                    None
+                }
+            };
+            results.push((key, file));
+        }
+        to_fetch.sort_unstable();
+        to_fetch.dedup();
+
+        let mut fetches = self.fetch_concurrently(to_fetch);
+        while let Some((key, outcome)) = fetches.next().await {
+            let Ok(GetOutcome::Found(content)) = outcome else {
+                continue;
+            };
+            self.cache_fetched(&key, &content).await?;
+            for (result_key, file) in results.iter_mut() {
+                if *result_key == key {
+                    if let Some(fetched) = self.disk_cache.get(&key).await? {
+                        *file = Some(fetched);
+                    }
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// Fetches `keys` from the network, keeping at most
+    /// `max_concurrent_fetches` fetches in flight, and yields each outcome as
+    /// soon as it is known.
+    fn fetch_concurrently(
+        &self,
+        keys: Vec<String>,
+    ) -> impl futures::Stream<Item = (String, Result<GetOutcome<Vec<u8>>>)> {
+        let file_sharing = self.file_sharing.clone();
+        let latencies = self.latencies.clone();
+        futures::stream::iter(keys)
+            .map(move |key| {
+                let mut file_sharing = file_sharing.clone();
+                let latencies = latencies.clone();
+                async move {
+                    let started = Instant::now();
+                    let outcome = file_sharing.fetch_file(key.clone()).await;
+                    latencies.network_fetch(started.elapsed());
+                    (key, outcome)
+                }
+            })
+            .buffer_unordered(self.max_concurrent_fetches)
+    }
+
+    /// Caches `content` under `key` and announces it. Takes `&self`, so a
+    /// node shared between tasks, e.g. through an `Arc`, can add many files
+    /// at once; their writes and announcements overlap.
+    pub async fn add(&self, key: &str, content: &[u8]) -> Result<()> {
+        self.insert(key, content).await
+    }
+
+    /// Overwrites the content cached under `key`, or adds it if there is
+    /// none; [`Disca::add`] leaves existing content alone.
+    ///
+    /// The new content goes to a temporary file renamed over the old one, so
+    /// readers holding or opening the file see either version in full, never
+    /// a mix. The size of the cache changes by the difference between the two,
+    /// evicting other files if the new content is bigger and the cache is
+    /// full. The key counts as freshly inserted for eviction, keeps its pin,
+    /// and is announced again; peers that already fetched the old content keep
+    /// their copy until they evict it. The hash of the old content, if
+    /// indexed, no longer resolves to the key.
+    pub async fn update(&self, key: &str, content: &[u8]) -> Result<()> {
+        let started = Instant::now();
+        namespace::check_key(key)?;
+        self.file_sharing.check_reachable()?;
+        if self.sign_published {
+            self.file_sharing.sign_file(key, content)?;
+        }
+        self.disk_cache.replace(key, content).await?;
+        self.unindex(|indexed| indexed == key).await?;
+        self.index(key, content).await?;
+        self.latencies.insert(started.elapsed());
+        Ok(())
+    }
+
+    /// Makes `content` available to peers as `key` without writing it to
+    /// disk, e.g. for data only distributed briefly. The content is held in
+    /// memory, where it counts towards [`CacheConfig::ephemeral_capacity`]
+    /// instead of the capacity of the cache: once that is full, the least
+    /// recently served ephemeral files are dropped and no longer announced.
+    /// Adding `key` again replaces its content.
+    ///
+    /// A [`Disca::get`] of `key` on this node moves it to the disk cache, as
+    /// content read locally is what the cache is for.
+    pub async fn add_ephemeral(&self, key: &str, content: &[u8]) -> Result<()> {
+        namespace::check_key(key)?;
+        self.file_sharing.check_reachable()?;
+        if self.sign_published {
+            self.file_sharing.sign_file(key, content)?;
+        }
+        for dropped in self.ephemeral.insert(key, content.to_vec())? {
+            if !self.disk_cache.contains_key(&dropped) {
+                self.file_sharing.remove_file(dropped).await?;
+            }
+        }
+        self.file_sharing.add_file(key).await
+    }
+
+    /// Stops serving the ephemeral file `key`. Returns whether there was one.
+    pub async fn remove_ephemeral(&self, key: &str) -> Result<bool> {
+        if self.ephemeral.remove(key).is_none() {
+            return Ok(false);
+        }
+        if !self.disk_cache.contains_key(key) {
+            self.file_sharing.remove_file(key).await?;
+        }
+        Ok(true)
+    }
+
+    /// Bytes of memory taken by ephemeral files.
+    pub fn ephemeral_size(&self) -> u64 {
+        self.ephemeral.size()
+    }
+
+    /// Like [`Disca::add`], but the file is pinned: it is never evicted and
+    /// does not count towards the capacity, so content fetched later cannot
+    /// push it out. Meant for seed nodes serving a dataset of their own.
+    pub async fn add_pinned(&self, key: &str, content: &[u8]) -> Result<()> {
+        self.publish(key, content, true).await
+    }
+
+    /// Counts as an access to `key` for eviction, like a get, without opening
+    /// its file, e.g. when it is known to be needed again soon. Returns
+    /// whether it was cached.
+    pub fn touch(&self, key: &str) -> bool {
+        self.disk_cache.touch(key)
+    }
+
+    /// Pins a cached file, see [`Disca::add_pinned`]. Returns whether it was
+    /// cached.
+    pub fn pin(&self, key: &str) -> bool {
+        self.disk_cache.pin(key)
+    }
+
+    /// Lets a pinned file be evicted again. Returns whether it was cached.
+    pub async fn unpin(&self, key: &str) -> Result<bool> {
+        self.disk_cache.unpin(key).await
+    }
+
+    /// Caches content published by this node, signing it first if enabled.
+    async fn insert(&self, key: &str, content: &[u8]) -> Result<()> {
+        self.publish(key, content, false).await
+    }
+
+    /// Like [`Disca::insert`], pinning the file from the start if `pinned`.
+    async fn publish(&self, key: &str, content: &[u8], pinned: bool) -> Result<()> {
+        let started = Instant::now();
+        namespace::check_key(key)?;
+        self.file_sharing.check_reachable()?;
+        if self.sign_published {
+            self.file_sharing.sign_file(key, content)?;
+        }
+        if pinned {
+            self.disk_cache.insert_pinned(key, content).await?;
         } else {
-            let file_content = self.file_sharing.get_file(path.clone()).await?;
-            if let Some(file_content) = file_content {
-                self.disk_cache.insert(&path, &file_content).await?;
-                let file = self.disk_cache.get(&path).await?;
-                return Ok(file);
+            self.disk_cache.insert(key, content).await?;
+        }
+        self.index(key, content).await?;
+        self.latencies.insert(started.elapsed());
+        Ok(())
+    }
+
+    /// Caches content fetched from the network, replacing any stale copy.
+    async fn cache_fetched(&self, key: &str, content: &[u8]) -> Result<()> {
+        namespace::check_key(key)?;
+        if self.disk_cache.contains_key(key) {
+            self.disk_cache.replace(key, content).await?;
+        } else {
+            self.disk_cache.insert(key, content).await?;
+        }
+        self.index(key, content).await
+    }
+
+    /// Announces `content` under its hash too, if indexing is enabled, and
+    /// records its chunks, if chunking is.
+    ///
+    /// Identical content cached under several keys is stored once per key
+    /// unless the cache deduplicates; its hash resolves to whichever of them
+    /// was cached last. Evicted keys stay indexed until they are cached
+    /// again or deleted, so peers asking this node for their hash in the
+    /// meantime get nothing back.
+    async fn index(&self, key: &str, content: &[u8]) -> Result<()> {
+        if let Some(chunks) = &self.chunks {
+            chunks.index(key, content);
+        }
+        if !self.index_by_hash {
+            return Ok(());
+        }
+        let content_key = self
+            .hash_algorithm
+            .content_key(&self.hash_algorithm.hash(content));
+        if self
+            .by_hash
+            .insert(content_key.clone(), key.to_owned())
+            .is_none()
+        {
+            let announced = self.file_sharing.add_file(content_key).await;
+            if !self.file_sharing.local_fallback() {
+                announced?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops announcing the hashes that resolve to keys matching `removed`,
+    /// and forgets the chunks of those keys.
+    async fn unindex(&self, removed: impl Fn(&str) -> bool) -> Result<()> {
+        if let Some(chunks) = &self.chunks {
+            chunks.forget_where(&removed);
+        }
+        let content_keys = self
+            .by_hash
+            .iter()
+            .filter(|entry| removed(entry.value()))
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        for content_key in content_keys {
+            self.by_hash.remove(&content_key);
+            self.file_sharing.remove_file(content_key).await?;
+        }
+        Ok(())
+    }
+
+    /// Gets a file by the hash of its content, as computed by the configured
+    /// [`HashAlgorithm`], when its key is unknown. Only finds files on peers
+    /// that index by hash; the fetched file is cached under its content key.
+    pub async fn get_by_hash(&mut self, hash: &[u8]) -> Result<Option<File>> {
+        let content_key = self.hash_algorithm.content_key(hash);
+        let cached_as = self
+            .by_hash
+            .get(&content_key)
+            .map(|key| key.value().clone());
+        if let Some(key) = cached_as {
+            if let Some(file) = self.disk_cache.get(&key).await? {
+                return Ok(Some(file));
+            }
+        }
+
+        // The event loop checks the content against the hash in the key.
+        match self.file_sharing.fetch_file(content_key.clone()).await? {
+            GetOutcome::Found(content) => {
+                self.cache_fetched(&content_key, &content).await?;
+                self.disk_cache.get(&content_key).await
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Streams `content` into the cache. See [`DiskCache::insert_from_reader`]
+    /// for why `len` has to be known up front.
+    pub async fn add_from_reader<R: tokio::io::AsyncRead + Unpin>(
+        &self,
+        key: &str,
+        content: R,
+        len: u64,
+    ) -> Result<()> {
+        let started = Instant::now();
+        namespace::check_key(key)?;
+        self.file_sharing.check_reachable()?;
+        self.disk_cache
+            .insert_from_reader(key, content, len)
+            .await?;
+        self.latencies.insert(started.elapsed());
+        Ok(())
+    }
+
+    /// Streams every cached key with its content, from the least to the most
+    /// recently used, e.g. to back the cache up or to copy it to another node
+    /// with [`Disca::import`]. Reading a file does not count as an access.
+    ///
+    /// The keys are listed once, along with their entries, when the stream is
+    /// created, and each file is read when its turn comes, one at a time. Keys evicted or deleted before that are
+    /// skipped, replaced ones yield their new content, and keys added in the
+    /// meantime are left out.
+    pub fn export(&self) -> impl futures::Stream<Item = Result<(String, Vec<u8>)>> + '_ {
+        futures::stream::iter(self.disk_cache.entries())
+            .then(move |(key, info)| async move {
+                let Some(mut file) = self.disk_cache.peek_listed(&key, info).await? else {
+                    return Ok(None);
+                };
+                let mut content = Vec::new();
+                file.read_to_end(&mut content)
+                    .await
+                    .with_context(|| format!("reading {} for export", key))?;
+                Ok(Some((key, content)))
+            })
+            .filter_map(|entry| async move { entry.transpose() })
+    }
+
+    /// Like [`Disca::export`], but the stream ends once `cancel` is
+    /// cancelled, after the file being read, if any.
+    pub fn export_cancellable(
+        &self,
+        cancel: CancellationToken,
+    ) -> impl futures::Stream<Item = Result<(String, Vec<u8>)>> + '_ {
+        self.export().take_until(cancel.cancelled_owned())
+    }
+
+    /// Adds every file of `entries`, e.g. from [`Disca::export`] on another
+    /// node, like [`Disca::add`] does. Keys already cached keep their content
+    /// and pins are not carried over. Stops at the first error of `entries`
+    /// or of adding a file. Returns how many files were added.
+    pub async fn import<S>(&self, entries: S) -> Result<usize>
+    where
+        S: futures::Stream<Item = Result<(String, Vec<u8>)>>,
+    {
+        futures::pin_mut!(entries);
+        let mut imported = 0;
+        while let Some(entry) = entries.next().await {
+            let (key, content) = entry?;
+            if self.disk_cache.contains_key(&key) {
+                continue;
             }
+            self.insert(&key, &content).await?;
+            imported += 1;
         }
-        Ok(None)
+        Ok(imported)
     }
 
-    pub async fn add(&mut self, key: &str, content: &[u8]) -> Result<()> {
-        self.disk_cache.insert(key, content).await?;
+    /// Writes every cached file to `writer` as a tar archive, e.g. to carry a
+    /// warm cache to a node without network access or to keep a backup,
+    /// which [`Disca::import_archive`] reads back. Files go in the order of
+    /// [`Disca::export`], from the least to the most recently used, and are
+    /// each named as [`SanitizedKeys`] names their file, so that extracting
+    /// the archive lays the files out as in a cache with the default
+    /// [`KeyMapper`]. Reading a file does not count as an access.
+    pub async fn export_archive<W: AsyncWrite + Unpin + Send>(&self, writer: &mut W) -> Result<()> {
+        let mut archive = tokio_tar::Builder::new_non_terminated(&mut *writer);
+        for (key, info) in self.disk_cache.entries() {
+            let Some(file) = self.disk_cache.peek_listed(&key, info).await? else {
+                continue;
+            };
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(file.metadata().await?.len());
+            header.set_mode(0o644);
+            header.set_mtime(
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            );
+            archive
+                .append_data(&mut header, SanitizedKeys.path(&key), file)
+                .await
+                .with_context(|| format!("archiving {}", key))?;
+        }
+        archive.finish().await?;
+        drop(archive);
+        writer.flush().await?;
         Ok(())
     }
 
+    /// Adds every file of a tar archive read from `reader`, as written by
+    /// [`Disca::export_archive`], like [`Disca::import`] does: keys already
+    /// cached keep their content, and the others are announced as if added
+    /// here. Added in the order of the archive, the files keep the order in
+    /// which they were used relative to each other, as more recently used
+    /// than anything cached here already. Entries other than files are
+    /// skipped. Returns how many files were added.
+    pub async fn import_archive<R: AsyncRead + Unpin>(&self, reader: R) -> Result<usize> {
+        let entries = tokio_tar::Archive::new(reader)
+            .entries()?
+            .map_err(anyhow::Error::from)
+            .try_filter_map(|mut entry| async move {
+                if !entry.header().entry_type().is_file() {
+                    return Ok(None);
+                }
+                let name = entry.path()?.to_string_lossy().into_owned();
+                let key = SanitizedKeys::key(&name)
+                    .with_context(|| format!("{:?} in the archive does not name a key", name))?;
+                let mut content = Vec::new();
+                entry
+                    .read_to_end(&mut content)
+                    .await
+                    .with_context(|| format!("reading {} from the archive", key))?;
+                Ok(Some((key, content)))
+            });
+        self.import(entries).await
+    }
+
+    /// Removes `key` from the local cache, and from the ephemeral files, and
+    /// stops providing it. Returns whether it was cached or ephemeral.
+    pub async fn delete(&mut self, key: &str) -> Result<bool> {
+        let ephemeral = self.ephemeral.remove(key).is_some();
+        let removed = self.disk_cache.remove(key).await?;
+        if ephemeral && !removed {
+            self.file_sharing.remove_file(key).await?;
+        }
+        self.unindex(|indexed| indexed == key).await?;
+        Ok(removed || ephemeral)
+    }
+
+    /// Removes every cached key starting with `prefix`, e.g. to invalidate all
+    /// the artifacts of a project at once. Returns how many were removed.
+    pub async fn delete_prefix(&mut self, prefix: &str) -> Result<usize> {
+        let removed = self.disk_cache.remove_prefix(prefix).await?;
+        self.unindex(|indexed| indexed.starts_with(prefix)).await?;
+        Ok(removed)
+    }
+
+    /// Maximum number of bytes the cache keeps on disk.
+    pub fn capacity(&self) -> u64 {
+        self.disk_cache.capacity()
+    }
+
+    /// Number of bytes currently cached.
+    pub fn size(&self) -> u64 {
+        self.disk_cache.size()
+    }
+
+    /// Applies `config` to the running node, e.g. a configuration file
+    /// loaded again. A smaller capacity evicts files until the cache fits,
+    /// and network settings are handed to the event loop, see
+    /// [`FileSharingP2P::reconfigure`]. Namespaces created from now on take
+    /// on the new cache settings; existing ones keep theirs.
+    ///
+    /// Settings the node is built around cannot change until it restarts:
+    /// the root and the partial directory, the listen addresses, peers
+    /// dialed or seeded on start-up, the identity and what files are stored,
+    /// signed, indexed, chunked and exchanged as. Nothing is applied if
+    /// `config` changes any of them, nor if it raises the request timeout, or
+    /// if the cache cannot shrink to the new capacity. The identity, key
+    /// mapper, provider selector, fetch interceptor, access log and token
+    /// validator, which configuration files cannot carry, stay as they are.
+    pub async fn reconfigure(&mut self, mut config: DiscaConfig) -> Result<()> {
+        self.config.check_reconfigure(&config, self.peer_id())?;
+        self.file_sharing.check_reconfigure(&config.network)?;
+        self.disk_cache.set_capacity(config.cache.capacity).await?;
+
+        let cache = &config.cache;
+        self.disk_cache.set_files_to_evict(cache.files_to_evict);
+        self.disk_cache.set_durable(cache.durable);
+        self.disk_cache.set_ttl(cache.ttl);
+        self.disk_cache.set_eviction_policy(cache.eviction_policy);
+        self.stale_while_revalidate = cache.stale_while_revalidate;
+        for dropped in self.ephemeral.set_capacity(cache.ephemeral_capacity) {
+            if !self.disk_cache.contains_key(&dropped) {
+                self.file_sharing.remove_file(dropped).await?;
+            }
+        }
+        config.cache.key_mapper = self.config.cache.key_mapper.clone();
+        let network = &mut config.network;
+        network.keypair = self.config.network.keypair.clone();
+        network.provider_selector = self.config.network.provider_selector.clone();
+        network.on_fetch = self.config.network.on_fetch.clone();
+        network.on_served = self.config.network.on_served.clone();
+        network.token_validator = self.config.network.token_validator.clone();
+        self.max_concurrent_fetches = network.max_concurrent_fetches.max(1);
+        self.file_sharing.reconfigure(network).await?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// Number of bytes the cached files actually take on disk, to compare
+    /// with [`Disca::size`]: a drift between the two means files were left
+    /// behind or went missing. See [`DiskCache::disk_usage`].
+    pub async fn disk_usage(&self) -> Result<u64> {
+        self.disk_cache.disk_usage().await
+    }
+
+    /// Number of cached entries.
+    pub fn len(&self) -> usize {
+        self.disk_cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.disk_cache.is_empty()
+    }
+
+    /// Size and timestamps of a cached entry, without counting as an access.
+    pub fn entry_info(&self, key: &str) -> Option<EntryInfo> {
+        self.disk_cache.entry_info(key)
+    }
+
+    pub fn list_keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.disk_cache.keys_with_prefix(prefix)
+    }
+
+    /// Makes sure every file added so far is on disk. Only needed when the
+    /// cache is not configured to be durable.
+    pub async fn flush(&mut self) -> Result<()> {
+        self.disk_cache.flush().await
+    }
+
+    /// Stops announcing the cached files, so that peers stop fetching from
+    /// this node without it losing its cache, e.g. to drain it before a
+    /// shutdown. Peers asking anyway are still served. See
+    /// [`FileSharingP2P::unannounce_all`].
+    pub async fn unannounce_all(&mut self) -> Result<()> {
+        self.file_sharing.unannounce_all().await
+    }
+
+    /// Announces the cached files again after [`Disca::unannounce_all`].
+    pub async fn announce_all(&mut self) -> Result<()> {
+        self.file_sharing.announce_all().await
+    }
+
     pub async fn add_peer(&mut self, addr: Multiaddr) -> Result<()> {
         self.file_sharing.add_peer(addr).await?;
         Ok(())
     }
 
+    /// Subscribes to the events of this node. Events sent before subscribing
+    /// are not delivered.
+    ///
+    /// The ids in [`DiscaEvent::ProvidersLookupStarted`] and
+    /// [`DiscaEvent::FileRequested`] are those of libp2p, to find the network
+    /// side of a slow or failed get in its logs.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<DiscaEvent> {
+        self.file_sharing.subscribe()
+    }
+
+    pub async fn listen_on(&mut self, addr: Multiaddr) -> Result<Multiaddr> {
+        self.file_sharing.listen_on(addr).await
+    }
+
+    pub async fn stop_listening(&mut self, addr: Multiaddr) -> Result<()> {
+        self.file_sharing.stop_listening(addr).await
+    }
+
+    /// Checks that `peer` can reach this node: it is asked to dial back the
+    /// address it sees us at, and pinged for the round-trip time. Helps
+    /// telling apart a node that nobody can connect to from one that simply
+    /// does not have what others ask for. `peer` must run disca too.
+    pub async fn self_test(&self, peer: libp2p::PeerId) -> Result<SelfTestReport> {
+        self.file_sharing.self_test(peer).await
+    }
+
+    /// Summarizes the routing table, e.g. to tell an empty table apart from
+    /// records that are actually missing when lookups fail.
+    pub async fn kad_stats(&self) -> Result<KadStats> {
+        self.file_sharing.kad_stats().await
+    }
+
+    /// Every peer in the routing table, to save before shutting down and
+    /// pass as [`NetworkConfig::seed_peers`] on the next start, so that the
+    /// node does not start from an empty table. Addresses are multiaddrs and
+    /// round-trip through their string form.
+    pub async fn known_peers(&self) -> Result<Vec<Multiaddr>> {
+        self.file_sharing.known_peers().await
+    }
+
     pub fn addr(&self) -> &Multiaddr {
         self.file_sharing.addr()
     }
 
+    pub fn listen_addrs(&self) -> Vec<Multiaddr> {
+        self.file_sharing.listen_addrs()
+    }
+
+    /// Whether other nodes can connect to this one. With [`KadMode::Auto`]
+    /// the node acts as a Kademlia server until it turns out to be private,
+    /// and then only as a client, so that it stays out of others' routing
+    /// tables.
+    pub fn reachability(&self) -> Reachability {
+        self.file_sharing.reachability()
+    }
+
+    /// Number of peers with at least one open connection.
+    pub fn connected_peers(&self) -> usize {
+        self.file_sharing.connected_peers()
+    }
+
+    /// Whether the node is listening and connected to peers right now. With
+    /// [`NetworkConfig::local_fallback`] set, an offline or isolated node is
+    /// still a working local cache whose gets miss whatever it does not have.
+    pub fn network_status(&self) -> NetworkStatus {
+        self.file_sharing.network_status()
+    }
+
+    /// How well `peer` served our fetches lately, see
+    /// [`FileSharingP2P::peer_score`].
+    pub fn peer_score(&self, peer: &libp2p::PeerId) -> f64 {
+        self.file_sharing.peer_score(peer)
+    }
+
+    /// How long local reads, network fetches, provider lookups and inserts
+    /// took so far.
+    pub fn latencies(&self) -> Latencies {
+        self.latencies.snapshot()
+    }
+
+    /// Waits until at least `min_peers` peers are connected and the routing
+    /// table is not empty, e.g. after startup, before relying on `get` to
+    /// find remote keys. Fails after `timeout`.
+    pub async fn wait_ready(&self, min_peers: usize, timeout: Duration) -> Result<()> {
+        self.file_sharing.wait_ready(min_peers, timeout).await
+    }
+
     pub fn peer_id(&self) -> &libp2p::PeerId {
         self.file_sharing.peer_id()
     }
@@ -0,0 +1,145 @@
+//! A [`FileProvider`] wrapper for nodes serving untrusted peers, bounding
+//! what they can ask for and recording what they got.
+
+use std::{ops::Range, sync::Arc};
+
+use libp2p::PeerId;
+
+use crate::{CompressionAlgorithm, FileProvider};
+
+/// A request served by a [`LimitedFileProvider`], or turned down for the
+/// size of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServedFile {
+    /// The peer that asked, unless the provider was called some other way
+    /// than for a peer's request.
+    pub peer: Option<PeerId>,
+    pub path: String,
+    /// The part of the file asked for, if not all of it.
+    pub range: Option<Range<u64>>,
+    /// Bytes read for the request, sent unless it was refused.
+    pub size: u64,
+    /// Whether nothing was sent because the file is larger than the limit.
+    pub refused: bool,
+}
+
+/// Called with every request a [`LimitedFileProvider`] served or refused.
+/// Clones share the function.
+#[derive(Clone)]
+pub struct AccessLog(Arc<dyn Fn(&ServedFile) + Send + Sync>);
+
+impl AccessLog {
+    pub fn new(f: impl Fn(&ServedFile) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AccessLog")
+    }
+}
+
+/// Serves the files of another provider, answering that a file is not there
+/// when it is larger than a limit, and reporting every file served to an
+/// [`AccessLog`], e.g. to audit who fetched what.
+///
+/// The limit is on the bytes sent: a file sent compressed, see
+/// [`FileProvider::get_file_encoded`], counts as its compressed size. Ranges
+/// reaching past the limit are refused too, so that a larger file cannot be
+/// had in parts either. Manifests, and chunks read for this node's own
+/// fetches, pass through unreported.
+#[derive(Clone)]
+pub struct LimitedFileProvider<T> {
+    inner: T,
+    max_size: Option<u64>,
+    access_log: Option<AccessLog>,
+    /// Set on the clone serving a request, see [`FileProvider::requested_by`].
+    peer: Option<PeerId>,
+}
+
+impl<T> LimitedFileProvider<T> {
+    /// Serves every file of `inner`, without reporting them, until told
+    /// otherwise.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            max_size: None,
+            access_log: None,
+            peer: None,
+        }
+    }
+
+    /// Refuses files of more than `max_size` bytes. Unlimited if unset.
+    pub fn with_max_size(mut self, max_size: Option<u64>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Reports every request to `access_log`. It is called while serving, off
+    /// the event loop unless [`crate::NetworkConfig::offload`] is off, so it
+    /// should be quick either way.
+    pub fn with_access_log(mut self, access_log: Option<AccessLog>) -> Self {
+        self.access_log = access_log;
+        self
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Hands `content` over if it fits, reporting it either way.
+    fn serve(&self, path: String, range: Option<Range<u64>>, content: Vec<u8>) -> Option<Vec<u8>> {
+        let offset = range.as_ref().map_or(0, |range| range.start);
+        let size = content.len() as u64;
+        let refused = self
+            .max_size
+            .is_some_and(|max_size| offset + size > max_size);
+        if let Some(access_log) = &self.access_log {
+            (access_log.0)(&ServedFile {
+                peer: self.peer,
+                path,
+                range,
+                size,
+                refused,
+            });
+        }
+        (!refused).then_some(content)
+    }
+}
+
+impl<T: FileProvider> FileProvider for LimitedFileProvider<T> {
+    fn requested_by(&mut self, peer: PeerId) {
+        self.peer = Some(peer);
+        self.inner.requested_by(peer);
+    }
+
+    fn get_file(&mut self, path: String) -> Option<Vec<u8>> {
+        let content = self.inner.get_file(path.clone())?;
+        self.serve(path, None, content)
+    }
+
+    fn get_file_encoded(
+        &mut self,
+        path: String,
+        accept_encodings: &[CompressionAlgorithm],
+    ) -> Option<(Vec<u8>, Option<CompressionAlgorithm>)> {
+        let (content, encoding) = self
+            .inner
+            .get_file_encoded(path.clone(), accept_encodings)?;
+        Some((self.serve(path, None, content)?, encoding))
+    }
+
+    fn get_file_range(&mut self, path: String, range: Range<u64>) -> Option<Vec<u8>> {
+        let content = self.inner.get_file_range(path.clone(), range.clone())?;
+        self.serve(path, Some(range), content)
+    }
+
+    fn get_manifest(&mut self, path: String) -> Option<Vec<u8>> {
+        self.inner.get_manifest(path)
+    }
+
+    fn get_chunk(&mut self, hash: &[u8]) -> Option<Vec<u8>> {
+        self.inner.get_chunk(hash)
+    }
+}
@@ -0,0 +1,170 @@
+//! Helpers building the addresses disca listens on and dials, so that callers
+//! do not have to format protocol strings by hand.
+//!
+//! Nodes only speak QUIC, so these are all of the form
+//! `/<ip>/<address>/udp/<port>/quic-v1`, possibly followed by the peer id.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{bail, Context, Result};
+use libp2p::{core::multiaddr::Protocol, Multiaddr};
+
+/// QUIC on `port` of `ip`. Port 0 lets the OS pick one when listening.
+pub fn quic(ip: impl Into<IpAddr>, port: u16) -> Multiaddr {
+    let ip = match ip.into() {
+        IpAddr::V4(ip) => Protocol::Ip4(ip),
+        IpAddr::V6(ip) => Protocol::Ip6(ip),
+    };
+    Multiaddr::empty()
+        .with(ip)
+        .with(Protocol::Udp(port))
+        .with(Protocol::QuicV1)
+}
+
+/// QUIC on `port` of `ip`.
+pub fn quic_ipv4(ip: Ipv4Addr, port: u16) -> Multiaddr {
+    quic(ip, port)
+}
+
+/// QUIC on `port` of every IPv6 interface, which is what nodes listen on by
+/// default.
+pub fn quic_ipv6(port: u16) -> Multiaddr {
+    quic(Ipv6Addr::UNSPECIFIED, port)
+}
+
+/// Parses `addr`, failing unless it is a QUIC address a node can listen on
+/// or dial: an IP address or DNS name, a UDP port, `quic-v1` and, optionally,
+/// the peer id, in that order.
+pub fn parse(addr: &str) -> Result<Multiaddr> {
+    let parsed = addr
+        .parse::<Multiaddr>()
+        .with_context(|| format!("{} is not a multiaddr", addr))?;
+    let mut protocols = parsed.iter();
+    match protocols.next() {
+        Some(
+            Protocol::Ip4(_)
+            | Protocol::Ip6(_)
+            | Protocol::Dns(_)
+            | Protocol::Dns4(_)
+            | Protocol::Dns6(_),
+        ) => {}
+        other => bail!(
+            "{} should start with an IP address or DNS name, not {}",
+            addr,
+            describe(other)
+        ),
+    }
+    match protocols.next() {
+        Some(Protocol::Udp(_)) => {}
+        other => bail!(
+            "{} should have a UDP port after the host, not {}",
+            addr,
+            describe(other)
+        ),
+    }
+    match protocols.next() {
+        Some(Protocol::QuicV1) => {}
+        other => bail!(
+            "{} should have quic-v1 after the port, not {}",
+            addr,
+            describe(other)
+        ),
+    }
+    match protocols.next() {
+        None | Some(Protocol::P2p(_)) => {}
+        Some(other) => bail!(
+            "{} should end after quic-v1 or the peer id, not go on with {}",
+            addr,
+            other
+        ),
+    }
+    if let Some(other) = protocols.next() {
+        bail!(
+            "{} should end with the peer id, not go on with {}",
+            addr,
+            other
+        );
+    }
+    Ok(parsed)
+}
+
+/// The addresses among `addrs`, those a peer listens on, that this node can
+/// reach it at: neither unspecified nor link-local, and loopback ones only
+/// if the peer is on the same host, as told by `observed`, the address it
+/// sees this node at, being a loopback one too. Addresses without an IP,
+/// e.g. DNS names, are kept.
+pub(crate) fn routable(addrs: &[Multiaddr], observed: &Multiaddr) -> Vec<Multiaddr> {
+    let same_host = ip(observed).is_some_and(|ip| ip.is_loopback());
+    addrs
+        .iter()
+        .filter(|addr| match ip(addr) {
+            Some(ip) if ip.is_loopback() => same_host,
+            Some(ip) => !ip.is_unspecified() && !is_link_local(ip),
+            None => true,
+        })
+        .cloned()
+        .collect()
+}
+
+/// The IP address `addr` starts with, if any.
+fn ip(addr: &Multiaddr) -> Option<IpAddr> {
+    match addr.iter().next()? {
+        Protocol::Ip4(ip) => Some(ip.into()),
+        Protocol::Ip6(ip) => Some(ip.into()),
+        _ => None,
+    }
+}
+
+fn is_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+fn describe(protocol: Option<Protocol>) -> String {
+    protocol.map_or_else(|| "nothing".to_owned(), |protocol| protocol.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<Multiaddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn a_peer_without_addresses_has_no_routable_one() {
+        let observed = "/ip4/203.0.113.7/udp/4001/quic-v1".parse().unwrap();
+
+        assert!(routable(&[], &observed).is_empty());
+    }
+
+    #[test]
+    fn loopback_addresses_are_only_routable_on_the_same_host() {
+        let loopback = addrs(&[
+            "/ip4/127.0.0.1/udp/4001/quic-v1",
+            "/ip6/::1/udp/4001/quic-v1",
+        ]);
+        let remote = "/ip4/203.0.113.7/udp/4001/quic-v1".parse().unwrap();
+        let local = "/ip4/127.0.0.1/udp/4001/quic-v1".parse().unwrap();
+
+        assert!(routable(&loopback, &remote).is_empty());
+        assert_eq!(routable(&loopback, &local), loopback);
+    }
+
+    #[test]
+    fn unspecified_and_link_local_addresses_are_dropped() {
+        let listened = addrs(&[
+            "/ip4/0.0.0.0/udp/4001/quic-v1",
+            "/ip4/169.254.1.2/udp/4001/quic-v1",
+            "/ip6/fe80::1/udp/4001/quic-v1",
+            "/ip4/192.168.1.2/udp/4001/quic-v1",
+            "/dns4/node.example/udp/4001/quic-v1",
+        ]);
+        let observed = "/ip4/203.0.113.7/udp/4001/quic-v1".parse().unwrap();
+
+        assert_eq!(routable(&listened, &observed), listened[3..]);
+    }
+}
@@ -0,0 +1,116 @@
+//! Latency distributions of a node's operations.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hdrhistogram::Histogram;
+
+/// Latencies above this are recorded as this, in microseconds.
+const MAX_LATENCY_MICROS: u64 = 10 * 60 * 1_000_000;
+/// Significant decimal digits kept for every recorded value.
+const SIGNIFICANT_DIGITS: u8 = 3;
+
+/// The distribution of the latencies of one kind of operation since the node
+/// started, with a precision of three significant digits.
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    histogram: Histogram<u64>,
+}
+
+impl LatencyHistogram {
+    fn new() -> Self {
+        Self {
+            histogram: Histogram::new_with_max(MAX_LATENCY_MICROS, SIGNIFICANT_DIGITS)
+                .expect("valid histogram bounds"),
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.histogram
+            .saturating_record(micros.min(MAX_LATENCY_MICROS));
+    }
+
+    /// Number of operations recorded.
+    pub fn count(&self) -> u64 {
+        self.histogram.len()
+    }
+
+    /// The latency below which the fraction `quantile` of the operations
+    /// completed, e.g. `0.99` for the 99th percentile. Zero when nothing was
+    /// recorded.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        Duration::from_micros(self.histogram.value_at_quantile(quantile))
+    }
+
+    pub fn mean(&self) -> Duration {
+        Duration::from_secs_f64(self.histogram.mean() / 1_000_000.0)
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.histogram.max())
+    }
+}
+
+/// A snapshot of the latencies of a node's operations, see
+/// [`crate::Disca::latencies`].
+#[derive(Debug, Clone)]
+pub struct Latencies {
+    /// Reads answered from the local cache.
+    pub local_get: LatencyHistogram,
+    /// Lookups on the network, from asking for providers to receiving the
+    /// file, whatever the outcome.
+    pub network_fetch: LatencyHistogram,
+    /// DHT lookups for the providers of a key, part of the network fetches
+    /// that missed the cached providers, whatever they found.
+    pub provider_lookup: LatencyHistogram,
+    /// Files added to the cache by this node.
+    pub insert: LatencyHistogram,
+}
+
+/// Where a node records latencies. Clones record into the same histograms.
+#[derive(Debug, Clone)]
+pub(crate) struct LatencyRecorder {
+    latencies: Arc<Mutex<Latencies>>,
+}
+
+impl Default for LatencyRecorder {
+    fn default() -> Self {
+        Self {
+            latencies: Arc::new(Mutex::new(Latencies {
+                local_get: LatencyHistogram::new(),
+                network_fetch: LatencyHistogram::new(),
+                provider_lookup: LatencyHistogram::new(),
+                insert: LatencyHistogram::new(),
+            })),
+        }
+    }
+}
+
+impl LatencyRecorder {
+    pub(crate) fn local_get(&self, latency: Duration) {
+        self.latencies().local_get.record(latency);
+    }
+
+    pub(crate) fn network_fetch(&self, latency: Duration) {
+        self.latencies().network_fetch.record(latency);
+    }
+
+    pub(crate) fn provider_lookup(&self, latency: Duration) {
+        self.latencies().provider_lookup.record(latency);
+    }
+
+    pub(crate) fn insert(&self, latency: Duration) {
+        self.latencies().insert.record(latency);
+    }
+
+    pub(crate) fn snapshot(&self) -> Latencies {
+        self.latencies().clone()
+    }
+
+    fn latencies(&self) -> std::sync::MutexGuard<'_, Latencies> {
+        self.latencies.lock().expect("latencies lock poisoned")
+    }
+}
@@ -0,0 +1,176 @@
+//! Independent caches sharing one node, e.g. one per tenant.
+//!
+//! A namespace caches its files in a directory of its own under the root,
+//! with a capacity of its own, so namespaces never evict each other's files
+//! nor those of the node's own cache. Its keys are announced and requested on
+//! the network as `<namespace>/<key>`. Keys cannot contain a `/`, so these
+//! never collide with the keys of the node's own cache, nor with those of
+//! another namespace, and a get in one namespace never returns the file of
+//! another. Nodes serve namespaced files to any peer asking for them by that
+//! name: namespaces keep keys apart, they do not restrict who can fetch them.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use tokio::fs::File;
+
+use crate::{metrics::LatencyRecorder, DiskCache, FileNotifier, FileSharingP2P, GetOutcome};
+
+/// The key a file of `namespace` goes by on the network.
+pub(crate) fn scoped(namespace: &str, key: &str) -> String {
+    format!("{}/{}", namespace, key)
+}
+
+/// Keys of a node cannot contain a `/`, which separates a namespace from the
+/// keys in it on the network.
+pub(crate) fn check_key(key: &str) -> Result<()> {
+    if key.contains('/') {
+        bail!("invalid key {}: keys cannot contain '/'", key);
+    }
+    Ok(())
+}
+
+/// Fails unless `name` can name a namespace, and its directory.
+pub(crate) fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains('/') || name.contains('\0') {
+        bail!(
+            "invalid namespace {:?}: names must be non-empty and contain neither '/' nor NUL",
+            name
+        );
+    }
+    // Directories starting with a dot belong to the cache, e.g. `.tmp`.
+    if name.starts_with('.') {
+        bail!("invalid namespace {:?}: names cannot start with '.'", name);
+    }
+    Ok(())
+}
+
+/// Announces the files of a namespace under their scoped keys.
+pub(crate) struct NamespaceNotifier {
+    pub(crate) namespace: String,
+    pub(crate) file_sharing: FileSharingP2P,
+}
+
+#[async_trait]
+impl FileNotifier for NamespaceNotifier {
+    async fn added(&self, path: String) -> Result<()> {
+        self.file_sharing
+            .added(scoped(&self.namespace, &path))
+            .await
+    }
+
+    async fn removed(&self, path: String) -> Result<()> {
+        self.file_sharing
+            .removed(scoped(&self.namespace, &path))
+            .await
+    }
+}
+
+/// A handle on one namespace of a node, see [`crate::Disca::add_namespace`].
+/// Clones share the namespace.
+#[derive(Clone)]
+pub struct Namespace {
+    pub(crate) name: String,
+    pub(crate) cache: Arc<DiskCache<NamespaceNotifier>>,
+    pub(crate) file_sharing: FileSharingP2P,
+    pub(crate) sign_published: bool,
+    pub(crate) latencies: LatencyRecorder,
+}
+
+impl Namespace {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the file for `key`, from this namespace's cache or fetched
+    /// from the peers caching it in a namespace of the same name.
+    pub async fn get(&self, key: &str) -> Result<Option<File>> {
+        Ok(self.get_outcome(key).await?.into_option())
+    }
+
+    /// Like [`Namespace::get`], but on a miss tells apart "nobody advertises
+    /// this key" from "the advertised providers did not have it".
+    pub async fn get_outcome(&self, key: &str) -> Result<GetOutcome<File>> {
+        // Checked before fetching, so that no key reaches into another
+        // namespace on the network.
+        check_key(key)?;
+        let started = std::time::Instant::now();
+        if let Some(file) = self.cache.get(key).await? {
+            self.latencies.local_get(started.elapsed());
+            return Ok(GetOutcome::Found(file));
+        }
+        let started = std::time::Instant::now();
+        let outcome = self
+            .file_sharing
+            .clone()
+            .fetch_file(scoped(&self.name, key))
+            .await;
+        self.latencies.network_fetch(started.elapsed());
+        match outcome? {
+            GetOutcome::Found(content) => {
+                self.cache.insert(key, &content).await?;
+                let file = self.cache.get(key).await?;
+                Ok(file.map_or(GetOutcome::NoProviders, GetOutcome::Found))
+            }
+            GetOutcome::NoProviders => Ok(GetOutcome::NoProviders),
+            GetOutcome::ProvidersHadNothing { peers } => {
+                Ok(GetOutcome::ProvidersHadNothing { peers })
+            }
+            // Never cached, as it would then be served as the whole file.
+            GetOutcome::Partial(partial) => Ok(GetOutcome::Partial(partial)),
+        }
+    }
+
+    /// Caches `content` under `key` in this namespace and announces it.
+    pub async fn add(&self, key: &str, content: &[u8]) -> Result<()> {
+        check_key(key)?;
+        let started = std::time::Instant::now();
+        self.file_sharing.check_reachable()?;
+        if self.sign_published {
+            self.file_sharing
+                .sign_file(&scoped(&self.name, key), content)?;
+        }
+        self.cache.insert(key, content).await?;
+        self.latencies.insert(started.elapsed());
+        Ok(())
+    }
+
+    /// Removes `key` from this namespace and stops announcing it. Returns
+    /// whether it was cached.
+    pub async fn delete(&self, key: &str) -> Result<bool> {
+        self.cache.remove(key).await
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.cache.contains_key(key)
+    }
+
+    pub fn list_keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.cache.keys_with_prefix(prefix)
+    }
+
+    /// Maximum number of bytes the namespace keeps on disk.
+    pub fn capacity(&self) -> u64 {
+        self.cache.capacity()
+    }
+
+    /// Number of bytes the namespace currently caches.
+    pub fn size(&self) -> u64 {
+        self.cache.size()
+    }
+
+    /// Number of bytes the files of the namespace actually take on disk, see
+    /// [`DiskCache::disk_usage`].
+    pub async fn disk_usage(&self) -> Result<u64> {
+        self.cache.disk_usage().await
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+}
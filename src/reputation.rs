@@ -0,0 +1,110 @@
+//! How well peers served our fetches lately.
+//!
+//! Every answer to a file request moves the provider's score: up for the
+//! file, down for nothing, a failed request or content that does not verify.
+//! Scores decay towards zero, so that old behaviour is forgotten and a peer
+//! that misbehaved once gets another chance later.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use libp2p::PeerId;
+
+use crate::ProviderInfo;
+
+/// Time for a score to decay to half its value.
+const HALF_LIFE: Duration = Duration::from_secs(10 * 60);
+/// Scores are capped, so that a long good record cannot hide a peer that
+/// just turned bad.
+const MAX_SCORE: f64 = 20.0;
+/// Peers scoring below this are only asked when no other provider is known.
+const BLACKLIST_BELOW: f64 = -10.0;
+/// Scores closer to zero than this are forgotten.
+const FORGOTTEN_BELOW: f64 = 0.01;
+
+/// What a provider did with a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Served,
+    /// It announced the key but had nothing.
+    HadNothing,
+    /// The request failed or timed out.
+    Failed,
+    /// It sent content that does not match its hash or signature.
+    Corrupt,
+}
+
+impl Outcome {
+    fn delta(self) -> f64 {
+        match self {
+            Outcome::Served => 1.0,
+            Outcome::HadNothing => -1.0,
+            Outcome::Failed => -2.0,
+            Outcome::Corrupt => -10.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Score {
+    value: f64,
+    updated_at: Instant,
+}
+
+impl Score {
+    fn decayed(self, now: Instant) -> f64 {
+        let half_lives =
+            now.duration_since(self.updated_at).as_secs_f64() / HALF_LIFE.as_secs_f64();
+        self.value * 0.5f64.powf(half_lives)
+    }
+}
+
+/// Scores of every peer we fetched from. Clones share the scores.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Reputation {
+    scores: Arc<DashMap<PeerId, Score>>,
+}
+
+impl Reputation {
+    /// Current score of `peer`: zero for peers never asked, positive for
+    /// peers that served us well lately.
+    pub(crate) fn score(&self, peer: &PeerId) -> f64 {
+        self.scores
+            .get(peer)
+            .map_or(0.0, |score| score.decayed(Instant::now()))
+    }
+
+    pub(crate) fn record(&self, peer: PeerId, outcome: Outcome) {
+        let now = Instant::now();
+        let mut score = self.scores.entry(peer).or_insert(Score {
+            value: 0.0,
+            updated_at: now,
+        });
+        score.value = (score.decayed(now) + outcome.delta()).min(MAX_SCORE);
+        score.updated_at = now;
+    }
+
+    /// Forgets peers whose score decayed to nothing, so that the scores of
+    /// everybody ever asked are not kept forever.
+    pub(crate) fn prune(&self) {
+        let now = Instant::now();
+        self.scores
+            .retain(|_, score| score.decayed(now).abs() >= FORGOTTEN_BELOW);
+    }
+
+    /// Moves providers with a negative score after the others, keeping the
+    /// order of the selector otherwise, and drops blacklisted ones unless
+    /// nobody else is left.
+    pub(crate) fn demote(&self, providers: &mut Vec<ProviderInfo>) {
+        providers.sort_by_key(|provider| provider.score < 0.0);
+        if providers
+            .iter()
+            .any(|provider| provider.score >= BLACKLIST_BELOW)
+        {
+            providers.retain(|provider| provider.score >= BLACKLIST_BELOW);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Files fetched in chunks are written to disk as the chunks come, so that a
+//! fetch broken off part way, by the provider going away or by the caller
+//! giving up, resumes from the chunks already in on the next try, from any
+//! provider, instead of starting over.
+//!
+//! The chunks go to a partial file at their offset in the file, and the index
+//! and hash of each one written are appended to a record, after the hash of
+//! the manifest they follow. A file fetched again under another manifest,
+//! e.g. because it changed, starts over. Once every chunk is in and checked,
+//! the partial file is renamed to the complete one, which the fetch hands
+//! over and removes along with the record.
+//!
+//! Every function here does blocking IO, so they run off the event loop.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::chunking::Manifest;
+
+/// Directory under the cache root where [`crate::Disca`] keeps files being
+/// fetched in chunks, see [`crate::NetworkConfig::partial_dir`].
+pub(crate) const PARTIAL_DIR: &str = ".partial";
+
+/// Where a file being fetched in chunks is kept until it is complete. Only
+/// names the files, which are created once opened.
+#[derive(Debug)]
+pub(crate) struct PartialFile {
+    dir: PathBuf,
+    /// The chunks written so far, at their offset in the file.
+    data: PathBuf,
+    /// The hash of the manifest, then the index and hash of every chunk
+    /// written to `data`, a line each.
+    record: PathBuf,
+    /// The file once complete.
+    complete: PathBuf,
+}
+
+impl PartialFile {
+    /// The partial file of `key` under `dir`.
+    pub(crate) fn new(dir: &Path, key: &str) -> Self {
+        let name = blake3::hash(key.as_bytes()).to_hex();
+        Self {
+            dir: dir.to_owned(),
+            data: dir.join(format!("{}.part", name)),
+            record: dir.join(format!("{}.chunks", name)),
+            complete: dir.join(name.as_str()),
+        }
+    }
+
+    /// Picks up what came on an earlier try of the file, returning which
+    /// chunks of `manifest` are in, each checked against its hash again as
+    /// it may not have reached the disk whole. Starts over if nothing came
+    /// or it followed another manifest.
+    pub(crate) fn open(&self, manifest: &Manifest, offsets: &[u64]) -> Result<Vec<bool>> {
+        let header = blake3::hash(&manifest.encode()).to_hex();
+        let mut received = vec![false; manifest.chunks.len()];
+        let recorded = match std::fs::read_to_string(&self.record) {
+            Ok(recorded) => recorded,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", self.record.display())),
+        };
+        let mut lines = recorded.lines();
+        if lines.next() == Some(header.as_str()) {
+            // Complete but never handed over, e.g. because the fetch was
+            // dropped while it was checked.
+            if !self.data.exists() {
+                let _ = std::fs::rename(&self.complete, &self.data);
+            }
+            if let Ok(mut data) = File::open(&self.data) {
+                for line in lines {
+                    let Some((index, hash)) = line.split_once(' ') else {
+                        continue;
+                    };
+                    let Some(index) = index.parse::<usize>().ok().filter(|i| *i < received.len())
+                    else {
+                        continue;
+                    };
+                    let chunk = &manifest.chunks[index];
+                    if received[index] || hash != to_hex(&chunk.hash) {
+                        continue;
+                    }
+                    let mut content = vec![0; chunk.len as usize];
+                    received[index] = data.seek(SeekFrom::Start(offsets[index])).is_ok()
+                        && data.read_exact(&mut content).is_ok()
+                        && manifest.algorithm.hash(&content) == chunk.hash;
+                }
+                return Ok(received);
+            }
+        }
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("creating {}", self.dir.display()))?;
+        let _ = std::fs::remove_file(&self.complete);
+        File::create(&self.data)
+            .and_then(|data| data.set_len(manifest.chunks.iter().map(|chunk| chunk.len).sum()))
+            .with_context(|| format!("creating {}", self.data.display()))?;
+        std::fs::write(&self.record, format!("{}\n", header))
+            .with_context(|| format!("creating {}", self.record.display()))?;
+        Ok(received)
+    }
+
+    /// Writes `chunks`, by index into `manifest`, and records them once
+    /// written. The chunks are expected to match the manifest already.
+    pub(crate) fn write<'a>(
+        &self,
+        manifest: &Manifest,
+        offsets: &[u64],
+        chunks: impl IntoIterator<Item = (usize, &'a [u8])>,
+    ) -> Result<()> {
+        let mut data = OpenOptions::new()
+            .write(true)
+            .open(&self.data)
+            .with_context(|| format!("opening {}", self.data.display()))?;
+        let mut record = String::new();
+        for (index, content) in chunks {
+            data.seek(SeekFrom::Start(offsets[index]))
+                .and_then(|_| data.write_all(content))
+                .with_context(|| format!("writing {}", self.data.display()))?;
+            record.push_str(&format!(
+                "{} {}\n",
+                index,
+                to_hex(&manifest.chunks[index].hash)
+            ));
+        }
+        OpenOptions::new()
+            .append(true)
+            .open(&self.record)
+            .and_then(|mut file| file.write_all(record.as_bytes()))
+            .with_context(|| format!("writing {}", self.record.display()))
+    }
+
+    /// Reads the chunk at `offset` of `len` bytes, as written.
+    pub(crate) fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let mut content = vec![0; len as usize];
+        File::open(&self.data)
+            .and_then(|mut data| {
+                data.seek(SeekFrom::Start(offset))?;
+                data.read_exact(&mut content)
+            })
+            .with_context(|| format!("reading {}", self.data.display()))?;
+        Ok(content)
+    }
+
+    /// Checks every chunk of the file against `manifest` once all came, and
+    /// renames it to the complete file if they all match, returning its
+    /// content. The complete file stays until [`PartialFile::remove`], so
+    /// that it is picked up again if the fetch is dropped in between.
+    pub(crate) fn commit(&self, manifest: &Manifest, offsets: &[u64]) -> Result<Vec<u8>> {
+        let content = std::fs::read(&self.data)
+            .with_context(|| format!("reading {}", self.data.display()))?;
+        let len: u64 = manifest.chunks.iter().map(|chunk| chunk.len).sum();
+        let matches = content.len() as u64 == len
+            && manifest.chunks.iter().zip(offsets).all(|(chunk, offset)| {
+                let range = *offset as usize..(offset + chunk.len) as usize;
+                manifest.algorithm.hash(&content[range]) == chunk.hash
+            });
+        if !matches {
+            // Overwritten meanwhile, e.g. by another fetch starting over, so
+            // the next try does too.
+            self.remove();
+            anyhow::bail!("{} does not match its manifest", self.data.display());
+        }
+        std::fs::rename(&self.data, &self.complete).with_context(|| {
+            format!(
+                "renaming {} to {}",
+                self.data.display(),
+                self.complete.display()
+            )
+        })?;
+        Ok(content)
+    }
+
+    /// Removes the file and its record, whatever came of them.
+    pub(crate) fn remove(&self) {
+        for path in [&self.data, &self.complete, &self.record] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,68 @@
+use std::{
+    fmt::Debug,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use libp2p::PeerId;
+use rand::seq::SliceRandom;
+
+/// A provider of a key, with what is known about it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProviderInfo {
+    pub peer_id: PeerId,
+    /// Last round-trip time measured by pinging the peer, if connected to it
+    /// long enough to have one.
+    pub rtt: Option<Duration>,
+    /// How well the peer served fetches lately, see
+    /// [`crate::FileSharingP2P::peer_score`]. Whatever the selector decides,
+    /// peers with a negative score are asked after the others.
+    pub score: f64,
+}
+
+/// Decides in which order the providers of a key are asked for it. The next
+/// one is only asked after the previous one failed or had nothing.
+pub trait ProviderSelector: Debug + Send + Sync {
+    /// Sorts `providers` so that the first one is asked first.
+    fn order(&self, key: &str, providers: &mut [ProviderInfo]);
+}
+
+/// Asks the closest providers first. Providers that were never pinged come
+/// last, in no particular order.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LowestRtt;
+
+impl ProviderSelector for LowestRtt {
+    fn order(&self, _: &str, providers: &mut [ProviderInfo]) {
+        providers.sort_by_key(|provider| provider.rtt.unwrap_or(Duration::MAX));
+    }
+}
+
+/// Spreads fetches evenly over the providers by starting each one with the
+/// provider after the one the previous fetch started with.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl ProviderSelector for RoundRobin {
+    fn order(&self, _: &str, providers: &mut [ProviderInfo]) {
+        if providers.is_empty() {
+            return;
+        }
+        // Sort first so that the rotation is meaningful across fetches.
+        providers.sort_by_key(|provider| provider.peer_id);
+        let offset = self.next.fetch_add(1, Ordering::Relaxed) % providers.len();
+        providers.rotate_left(offset);
+    }
+}
+
+/// Asks the providers in random order.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomOrder;
+
+impl ProviderSelector for RandomOrder {
+    fn order(&self, _: &str, providers: &mut [ProviderInfo]) {
+        providers.shuffle(&mut rand::thread_rng());
+    }
+}
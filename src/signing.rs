@@ -0,0 +1,178 @@
+//! Signed provider records.
+//!
+//! Anybody can announce themselves as a provider for a key, so on its own a
+//! fetched file proves nothing about where it came from. A publisher can
+//! instead sign `(key, content hash)` with its identity keypair; the signature
+//! travels with every copy of the file, and fetchers configured with a set of
+//! trusted publishers reject content that is unsigned, signed by someone else
+//! or does not match the signed hash, moving on to the next provider.
+//!
+//! This protects against spoofed or corrupted content served by malicious
+//! providers. It does not stop them from announcing keys they do not have
+//! (that only costs a failed attempt), does not hide content from anybody,
+//! and does not prevent replaying an older signed version of the same key.
+
+use std::collections::HashSet;
+
+use anyhow::Result;
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const SIGNING_DOMAIN: &[u8] = b"disca-content-signature:";
+const CONTENT_KEY_PREFIX: &str = "#";
+
+/// Digest used to refer to file content. The algorithm travels with every
+/// hash it produced, so nodes configured differently can still verify each
+/// other's content.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// Fast, and the default.
+    #[default]
+    Blake3,
+    /// For compatibility with existing manifests and tooling.
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn hash(self, content: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Blake3 => blake3::hash(content).as_bytes().to_vec(),
+            HashAlgorithm::Sha256 => Sha256::digest(content).to_vec(),
+        }
+    }
+
+    /// Key under which content with the given hash is announced, e.g.
+    /// `#blake3:af13…`.
+    pub fn content_key(self, hash: &[u8]) -> String {
+        let mut key = format!("{}{}:", CONTENT_KEY_PREFIX, self.name());
+        for byte in hash {
+            key.push_str(&format!("{:02x}", byte));
+        }
+        key
+    }
+
+    /// Splits a key made by [`HashAlgorithm::content_key`] back into the
+    /// algorithm and the hash, or returns `None` for any other key.
+    pub fn parse_content_key(key: &str) -> Option<(Self, Vec<u8>)> {
+        let (name, hex) = key.strip_prefix(CONTENT_KEY_PREFIX)?.split_once(':')?;
+        let algorithm = [HashAlgorithm::Blake3, HashAlgorithm::Sha256]
+            .into_iter()
+            .find(|algorithm| algorithm.name() == name)?;
+        if hex.len() % 2 != 0 {
+            return None;
+        }
+        let hash = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<_>>>()?;
+        Some((algorithm, hash))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+/// Checks that content fetched under a key made by
+/// [`HashAlgorithm::content_key`] actually has that hash. Such content is
+/// self-certifying, so it needs no signature.
+pub(crate) fn verify_content_key(key: &str, content: &[u8]) -> Option<Result<()>> {
+    let (algorithm, hash) = HashAlgorithm::parse_content_key(key)?;
+    if algorithm.hash(content) == hash {
+        Some(Ok(()))
+    } else {
+        Some(Err(anyhow::anyhow!(
+            "content of {} does not match its hash",
+            key
+        )))
+    }
+}
+
+/// Hashes file content with the default [`HashAlgorithm`].
+pub fn content_hash(content: &[u8]) -> Vec<u8> {
+    HashAlgorithm::default().hash(content)
+}
+
+/// A publisher's signature over a key and the hash of its content.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ContentSignature {
+    /// Protobuf encoding of the publisher's public key.
+    publisher: Vec<u8>,
+    /// Peers predating pluggable hashes only knew BLAKE3.
+    #[serde(default)]
+    algorithm: HashAlgorithm,
+    hash: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl ContentSignature {
+    pub fn sign(
+        keypair: &Keypair,
+        algorithm: HashAlgorithm,
+        key: &str,
+        content: &[u8],
+    ) -> Result<Self> {
+        let hash = algorithm.hash(content);
+        let signature = keypair.sign(&signed_message(key, algorithm, &hash))?;
+        Ok(Self {
+            publisher: keypair.public().encode_protobuf(),
+            algorithm,
+            hash,
+            signature,
+        })
+    }
+
+    pub fn publisher(&self) -> Result<PeerId> {
+        Ok(PublicKey::try_decode_protobuf(&self.publisher)?.to_peer_id())
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Checks that the signature is valid for `key` and `content`, and, unless
+    /// `trusted` is empty, that it was made by one of the trusted publishers.
+    pub fn verify(&self, key: &str, content: &[u8], trusted: &HashSet<PeerId>) -> Result<()> {
+        let publisher = PublicKey::try_decode_protobuf(&self.publisher)?;
+        if !trusted.is_empty() && !trusted.contains(&publisher.to_peer_id()) {
+            anyhow::bail!(
+                "{} is signed by untrusted publisher {}",
+                key,
+                publisher.to_peer_id()
+            );
+        }
+        if self.algorithm.hash(content) != self.hash {
+            anyhow::bail!("content of {} does not match its signed hash", key);
+        }
+        if !publisher.verify(
+            &signed_message(key, self.algorithm, &self.hash),
+            &self.signature,
+        ) {
+            anyhow::bail!("invalid signature for {}", key);
+        }
+        Ok(())
+    }
+}
+
+/// The algorithm is only part of the message when it is not the original
+/// BLAKE3, so that older signatures stay valid while a SHA-256 hash can never
+/// pass for a BLAKE3 one.
+fn signed_message(key: &str, algorithm: HashAlgorithm, hash: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(SIGNING_DOMAIN.len() + 16 + key.len() + hash.len());
+    message.extend_from_slice(SIGNING_DOMAIN);
+    if algorithm == HashAlgorithm::Sha256 {
+        message.extend_from_slice(b"sha256:");
+    }
+    message.extend_from_slice(&(key.len() as u64).to_be_bytes());
+    message.extend_from_slice(key.as_bytes());
+    message.extend_from_slice(hash);
+    message
+}
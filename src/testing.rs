@@ -0,0 +1,158 @@
+//! Helpers for exercising the fetch logic against misbehaving providers,
+//! available with the `testing` feature.
+//!
+//! A [`ScriptedFileProvider`] is handed to [`crate::FileSharingP2P::with_config`]
+//! in place of a real one, so that a node answers requests exactly as
+//! scripted: with the expected content, wrong content, nothing at all or
+//! after a delay.
+//!
+//! A [`Dataset`] provides files of realistic sizes to warm a node up with,
+//! e.g. before benchmarking it.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+
+use crate::{Disca, FileProvider};
+
+/// Smallest file of a generated [`Dataset`].
+const DATASET_MIN_SIZE: u64 = 512;
+/// Largest file of a generated [`Dataset`], well below what a response can
+/// carry.
+const DATASET_MAX_SIZE: u64 = 4 * 1024 * 1024;
+
+/// How a [`ScriptedFileProvider`] answers one request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptedResponse {
+    Content(Vec<u8>),
+    /// Answer that the file is not there.
+    Nothing,
+    /// Answer with the content after blocking for the given time, like a
+    /// slow disk would. With [`crate::NetworkConfig::offload`] off, providers
+    /// are called from the event loop, so the whole node stalls meanwhile.
+    Delayed(Duration, Vec<u8>),
+}
+
+/// A [`FileProvider`] answering from a script instead of a directory.
+///
+/// Responses for a key are used in the order they were scripted, and the
+/// last one keeps being used once the others ran out. Keys without a script
+/// are answered with nothing. Clones share the script, so it can still be
+/// changed after the provider was handed to a node.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedFileProvider {
+    scripts: Arc<Mutex<HashMap<String, VecDeque<ScriptedResponse>>>>,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl ScriptedFileProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a response to the script of `key`.
+    pub fn respond(&self, key: impl Into<String>, response: ScriptedResponse) -> &Self {
+        self.scripts
+            .lock()
+            .expect("scripts lock poisoned")
+            .entry(key.into())
+            .or_default()
+            .push_back(response);
+        self
+    }
+
+    /// Forgets the script of `key`, which is then answered with nothing.
+    pub fn clear(&self, key: &str) {
+        self.scripts
+            .lock()
+            .expect("scripts lock poisoned")
+            .remove(key);
+    }
+
+    /// Every key requested so far, in order.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests
+            .lock()
+            .expect("requests lock poisoned")
+            .clone()
+    }
+}
+
+impl FileProvider for ScriptedFileProvider {
+    fn get_file(&mut self, path: String) -> Option<Vec<u8>> {
+        self.requests
+            .lock()
+            .expect("requests lock poisoned")
+            .push(path.clone());
+        let response = {
+            let mut scripts = self.scripts.lock().expect("scripts lock poisoned");
+            let script = scripts.get_mut(&path)?;
+            if script.len() > 1 {
+                script.pop_front()
+            } else {
+                script.front().cloned()
+            }
+        };
+        match response? {
+            ScriptedResponse::Content(content) => Some(content),
+            ScriptedResponse::Nothing => None,
+            ScriptedResponse::Delayed(delay, content) => {
+                std::thread::sleep(delay);
+                Some(content)
+            }
+        }
+    }
+}
+
+/// Files with random content and sizes distributed like the artifacts of a
+/// build cache: mostly a few kilobytes, some hundreds of kilobytes and the odd
+/// one of megabytes.
+#[derive(Debug, Clone)]
+pub struct Dataset {
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl Dataset {
+    /// Generates `count` files named `file-0`, `file-1`, … The same `seed`
+    /// always gives the same files.
+    pub fn generate(count: usize, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let files = (0..count)
+            .map(|i| {
+                // Log-uniform sizes skewed towards the small end.
+                let position = rng.gen::<f64>().powi(2);
+                let ratio = (DATASET_MAX_SIZE / DATASET_MIN_SIZE) as f64;
+                let size = (DATASET_MIN_SIZE as f64 * ratio.powf(position)) as usize;
+                let mut content = vec![0; size];
+                rng.fill_bytes(&mut content);
+                (format!("file-{}", i), content)
+            })
+            .collect();
+        Self { files }
+    }
+
+    pub fn files(&self) -> &[(String, Vec<u8>)] {
+        &self.files
+    }
+
+    /// Total size of the files in bytes.
+    pub fn size(&self) -> u64 {
+        self.files
+            .iter()
+            .map(|(_, content)| content.len() as u64)
+            .sum()
+    }
+
+    /// Adds every file to `disca`, so that it serves them to its peers.
+    pub async fn warm_up(&self, disca: &Disca) -> Result<()> {
+        for (key, content) in &self.files {
+            disca.add(key, content).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+//! Whole nodes, each caching under a temporary directory of its own, talking
+//! over QUIC on the loopback interface.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use disca::{
+    ChunkingConfig, Disca, DiscaBuilder, FileProvider, FileSharingP2P, HashAlgorithm,
+    MemoryFileProvider, NetworkConfig,
+};
+use libp2p::Multiaddr;
+use tempfile::TempDir;
+use tokio::io::AsyncReadExt;
+
+const LOOPBACK: &str = "/ip4/127.0.0.1/udp/0/quic-v1";
+
+fn builder(root: &TempDir) -> DiscaBuilder {
+    Disca::builder(root.path()).listen_addr(LOOPBACK.parse().unwrap())
+}
+
+fn network_config() -> NetworkConfig {
+    NetworkConfig {
+        listen_addrs: vec![LOOPBACK.parse().unwrap()],
+        ..Default::default()
+    }
+}
+
+/// Adds the node listening on `addr` as a peer of `node`.
+async fn connect(node: &mut Disca, addr: &Multiaddr) {
+    node.add_peer(addr.clone()).await.unwrap();
+    node.wait_ready(1, Duration::from_secs(30)).await.unwrap();
+}
+
+async fn read(mut file: tokio::fs::File) -> Vec<u8> {
+    let mut content = Vec::new();
+    file.read_to_end(&mut content).await.unwrap();
+    content
+}
+
+/// Serves every key as its own bytes after a while, keeping track of how
+/// many requests it served at once at most.
+#[derive(Clone, Default)]
+struct SlowProvider {
+    in_flight: Arc<AtomicUsize>,
+    max_in_flight: Arc<AtomicUsize>,
+}
+
+impl FileProvider for SlowProvider {
+    fn get_file(&mut self, path: String) -> Option<Vec<u8>> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(50));
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        Some(path.into_bytes())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn prefetch_keeps_to_max_concurrent_fetches() {
+    let files = SlowProvider::default();
+    let provider = FileSharingP2P::with_config(network_config(), files.clone())
+        .await
+        .unwrap();
+    let root = TempDir::new().unwrap();
+    let mut node = builder(&root)
+        .max_concurrent_fetches(2)
+        .build()
+        .await
+        .unwrap();
+    node.add_peer(provider.addr().clone()).await.unwrap();
+    node.wait_ready(1, Duration::from_secs(30)).await.unwrap();
+    let keys = (0..8).map(|i| format!("key-{}", i)).collect::<Vec<_>>();
+    for key in &keys {
+        provider.add_file(key.clone()).await.unwrap();
+    }
+
+    assert_eq!(node.prefetch(keys.clone()).await.unwrap(), keys.len());
+    assert!(files.max_in_flight.load(Ordering::SeqCst) <= 2);
+    assert_eq!(node.len(), keys.len());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn nodes_agree_on_the_hash_of_content() {
+    for algorithm in [HashAlgorithm::Blake3, HashAlgorithm::Sha256] {
+        let (root, other_root) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let node = |root| {
+            builder(root)
+                .hash_algorithm(algorithm)
+                .index_by_hash(true)
+                .build()
+        };
+        let publisher = node(&root).await.unwrap();
+        let mut fetcher = node(&other_root).await.unwrap();
+        connect(&mut fetcher, publisher.addr()).await;
+        publisher.add("published", b"same content").await.unwrap();
+
+        // Only the content is shared, the fetcher hashes it on its own.
+        let file = fetcher
+            .get_by_hash(&algorithm.hash(b"same content"))
+            .await
+            .unwrap()
+            .expect("content found by its hash");
+        assert_eq!(read(file).await, b"same content");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_corrupted_file_is_fetched_again() {
+    let files = MemoryFileProvider::new();
+    files.insert("key", b"original".to_vec());
+    let provider = FileSharingP2P::with_config(network_config(), files)
+        .await
+        .unwrap();
+    let root = TempDir::new().unwrap();
+    let mut node = builder(&root)
+        .verify_local_checksums(true)
+        .build()
+        .await
+        .unwrap();
+    connect(&mut node, provider.addr()).await;
+    provider.add_file("key").await.unwrap();
+    node.get("key")
+        .await
+        .unwrap()
+        .expect("fetched from the provider");
+
+    std::fs::write(root.path().join("key"), b"corrupt!").unwrap();
+
+    let file = node.get("key").await.unwrap().expect("fetched again");
+    assert_eq!(read(file).await, b"original");
+    assert_eq!(std::fs::read(root.path().join("key")).unwrap(), b"original");
+}
+
+#[tokio::test]
+async fn a_root_that_cannot_be_created_is_an_error() {
+    let dir = TempDir::new().unwrap();
+    let file = dir.path().join("file");
+    std::fs::write(&file, b"").unwrap();
+
+    let root = file.join("cache");
+    let result = Disca::builder(&root)
+        .listen_addr(LOOPBACK.parse().unwrap())
+        .build()
+        .await;
+
+    let e = result.err().expect("a file cannot hold a directory");
+    // Says which directory, rather than panicking.
+    assert!(
+        format!("{:#}", e).contains(&root.display().to_string()),
+        "{:#}",
+        e
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_do_not_find_a_node_after_it_unannounced() {
+    let root = TempDir::new().unwrap();
+    let mut node = builder(&root).build().await.unwrap();
+    // Announced before any peer could store the announcement, so that only
+    // the node itself knows of it.
+    node.add("key", b"content").await.unwrap();
+    node.unannounce_all().await.unwrap();
+    let mut peer = FileSharingP2P::with_config(network_config(), MemoryFileProvider::new())
+        .await
+        .unwrap();
+    peer.add_peer(node.addr().clone()).await.unwrap();
+    peer.wait_ready(1, Duration::from_secs(30)).await.unwrap();
+
+    assert!(!peer.exists("key").await.unwrap());
+
+    node.announce_all().await.unwrap();
+    tokio::time::timeout(Duration::from_secs(30), async {
+        while !peer.exists("key").await.unwrap() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("found once announced again");
+}
+
+#[tokio::test]
+async fn a_read_only_node_is_never_returned_as_a_provider() {
+    let roots = [(); 3].map(|_| TempDir::new().unwrap());
+    let mut fetcher = builder(&roots[0]).build().await.unwrap();
+    let mut serving = builder(&roots[1]).build().await.unwrap();
+    let mut read_only = builder(&roots[2]).read_only(true).build().await.unwrap();
+    connect(&mut serving, fetcher.addr()).await;
+    connect(&mut read_only, fetcher.addr()).await;
+    serving.add("key", b"served").await.unwrap();
+    read_only.add("key", b"read-only").await.unwrap();
+    read_only.add("read-only-key", b"read-only").await.unwrap();
+
+    assert!(fetcher
+        .wait_for_timeout("key", Duration::from_secs(30))
+        .await
+        .unwrap());
+    assert!(!fetcher.exists("read-only-key").await.unwrap());
+    for _ in 0..5 {
+        let file = fetcher.get("key").await.unwrap().unwrap();
+        assert_eq!(read(file).await, b"served");
+        fetcher.delete("key").await.unwrap();
+    }
+    // Asked directly, the node still answers as if it had nothing.
+    let read_only_peer = *read_only.peer_id();
+    assert_eq!(
+        fetcher.get_from_peer(read_only_peer, "key").await.unwrap(),
+        None
+    );
+}
+
+#[tokio::test]
+async fn a_file_larger_than_the_cache_can_be_pinned() {
+    let root = TempDir::new().unwrap();
+    let node = builder(&root).capacity(30).build().await.unwrap();
+    for key in ["a", "b", "c"] {
+        node.add(key, &[0; 10]).await.unwrap();
+    }
+
+    node.add_pinned("dataset", &[0; 100]).await.unwrap();
+
+    assert!(node.entry_info("dataset").unwrap().pinned);
+    // It takes no room, so nothing was evicted for it.
+    assert_eq!(node.list_keys_with_prefix(""), ["a", "b", "c", "dataset"]);
+    assert_eq!(node.size(), 30);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_chunked_fetch_given_up_on_resumes_where_it_stopped() {
+    let served = Arc::new(Mutex::new(Vec::new()));
+    let stalled = Arc::new(AtomicBool::new(false));
+    let (root, other_root) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let publisher = builder(&root)
+        .chunking(ChunkingConfig::default())
+        .on_served({
+            let served = served.clone();
+            let stalled = stalled.clone();
+            move |file| {
+                let Some(range) = file.range.clone() else {
+                    return;
+                };
+                served.lock().unwrap().push(range.clone());
+                // Holds the second run of chunks back, once.
+                if range.start > 0 && !stalled.swap(true, Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_secs(3));
+                }
+            }
+        })
+        .build()
+        .await
+        .unwrap();
+    let mut fetcher = builder(&other_root)
+        .chunking(ChunkingConfig::default())
+        .build()
+        .await
+        .unwrap();
+    connect(&mut fetcher, publisher.addr()).await;
+    // More than one run of chunks, cut in different places.
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let content = (0..6 * 1024 * 1024)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect::<Vec<_>>();
+    publisher.add("large", &content).await.unwrap();
+
+    // Given up on once the first run of chunks came.
+    tokio::select! {
+        _ = fetcher.get("large") => panic!("fetched while the second run is held back"),
+        _ = async {
+            while !stalled.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        } => {}
+    }
+
+    let file = fetcher.get("large").await.unwrap().expect("fetched again");
+    assert_eq!(read(file).await, content);
+    let served = served.lock().unwrap().clone();
+    assert_eq!(
+        served.iter().filter(|range| range.start == 0).count(),
+        1,
+        "{:?}",
+        served
+    );
+    let partial = std::fs::read_dir(other_root.path().join(".partial")).unwrap();
+    assert_eq!(partial.count(), 0);
+}
@@ -0,0 +1,438 @@
+//! The local cache on its own, under a temporary directory, without any
+//! networking.
+
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use disca::{DiskCache, EvictionPolicy, FileNotifier, SharedCapacity};
+use tempfile::TempDir;
+use tokio::io::AsyncReadExt;
+
+/// Announces nothing.
+struct NoNotifier;
+
+#[async_trait]
+impl FileNotifier for NoNotifier {
+    async fn added(&self, _: String) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn removed(&self, _: String) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fails to announce anything.
+struct FailingNotifier;
+
+#[async_trait]
+impl FileNotifier for FailingNotifier {
+    async fn added(&self, path: String) -> anyhow::Result<()> {
+        anyhow::bail!("cannot announce {}", path)
+    }
+
+    async fn removed(&self, path: String) -> anyhow::Result<()> {
+        anyhow::bail!("cannot unannounce {}", path)
+    }
+}
+
+fn cache(root: &TempDir, capacity: u64, files_to_evict: u64) -> DiskCache<NoNotifier> {
+    DiskCache::new(root.path(), files_to_evict, capacity, NoNotifier).unwrap()
+}
+
+/// Files directly under `root`, leaving out temporary ones.
+fn files_on_disk(root: &TempDir) -> usize {
+    std::fs::read_dir(root.path())
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_type().unwrap().is_file())
+        .count()
+}
+
+#[tokio::test]
+async fn a_large_file_evicts_as_many_files_as_it_needs() {
+    let root = TempDir::new().unwrap();
+    let cache = cache(&root, 100, 1);
+    for i in 0..5 {
+        cache
+            .insert(format!("small-{}", i), &[0; 20])
+            .await
+            .unwrap();
+    }
+
+    cache.insert("large", &[0; 70]).await.unwrap();
+
+    assert!(cache.contains_key("large"));
+    assert_eq!(cache.keys_with_prefix("small-"), ["small-4"]);
+    assert_eq!(cache.size(), 90);
+    assert_eq!(files_on_disk(&root), 2);
+}
+
+#[tokio::test]
+async fn a_file_larger_than_the_cache_is_refused() {
+    let root = TempDir::new().unwrap();
+    let cache = cache(&root, 100, 1);
+    cache.insert("small", &[0; 20]).await.unwrap();
+
+    assert!(cache.insert("huge", &[0; 101]).await.is_err());
+    assert!(cache.contains_key("small"));
+    assert_eq!(files_on_disk(&root), 1);
+}
+
+#[tokio::test]
+async fn a_file_is_refused_when_nothing_can_be_evicted() {
+    let root = TempDir::new().unwrap();
+    let cache = cache(&root, 100, 1);
+    for i in 0..5 {
+        let key = format!("small-{}", i);
+        cache.insert(&key, &[0; 20]).await.unwrap();
+        // Directories cannot be removed as files, so evicting fails.
+        let path = root.path().join(&key);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+    }
+
+    assert!(cache.insert("large", &[0; 70]).await.is_err());
+    assert!(!cache.contains_key("large"));
+    assert_eq!(cache.len(), 5);
+    assert_eq!(cache.size(), 100);
+}
+
+#[tokio::test]
+async fn an_eviction_pass_evicts_files_to_evict_files() {
+    for (files_to_evict, left) in [(1, 10), (3, 8), (50, 1)] {
+        let root = TempDir::new().unwrap();
+        let cache = cache(&root, 100, files_to_evict);
+        for i in 0..10 {
+            cache.insert(format!("old-{}", i), &[0; 10]).await.unwrap();
+        }
+
+        cache.insert("new", &[0; 10]).await.unwrap();
+
+        assert_eq!(cache.len(), left, "evicting {} at once", files_to_evict);
+        assert_eq!(files_on_disk(&root), left);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_inserts_keep_to_the_capacity() {
+    let root = TempDir::new().unwrap();
+    let cache = Arc::new(cache(&root, 100, 1));
+
+    let inserts = (0..64).map(|i| {
+        let cache = cache.clone();
+        tokio::spawn(async move { cache.insert(format!("file-{}", i), &[0; 10]).await })
+    });
+    for inserted in futures::future::join_all(inserts).await {
+        inserted.unwrap().unwrap();
+    }
+
+    assert_eq!(cache.size(), 100);
+    assert_eq!(cache.len(), 10);
+    // Every file evicted is gone from the disk too.
+    assert_eq!(files_on_disk(&root), 10);
+}
+
+#[tokio::test]
+async fn notifier_errors_are_returned_by_the_cache() {
+    let root = TempDir::new().unwrap();
+    let cache = DiskCache::new(root.path(), 1, 20, FailingNotifier).unwrap();
+
+    let e = cache.insert("first", b"0123456789").await.unwrap_err();
+    assert_eq!(e.to_string(), "cannot announce first");
+    cache.insert("second", b"0123456789").await.unwrap_err();
+    assert_eq!(cache.len(), 2);
+    // Evicting the first file to make room fails to unannounce it, which
+    // fails the insert.
+    let e = cache.insert("third", b"0123456789").await.unwrap_err();
+    assert_eq!(e.to_string(), "cannot unannounce first");
+    assert!(!cache.contains_key("first"));
+    assert!(!cache.contains_key("third"));
+    let e = cache.remove("second").await.unwrap_err();
+    assert_eq!(e.to_string(), "cannot unannounce second");
+    assert_eq!(cache.len(), 0);
+    assert_eq!(files_on_disk(&root), 0);
+}
+
+#[tokio::test]
+async fn pinned_files_survive_eviction() {
+    let root = TempDir::new().unwrap();
+    let cache = cache(&root, 30, 1);
+    cache.insert("pinned", &[0; 10]).await.unwrap();
+    cache.pin("pinned");
+
+    for i in 0..6 {
+        cache
+            .insert(format!("small-{}", i), &[0; 10])
+            .await
+            .unwrap();
+    }
+
+    assert!(cache.contains_key("pinned"));
+    assert_eq!(cache.len(), 4);
+    // Unpinned, it counts again, so one more file goes to make room.
+    assert!(cache.unpin("pinned").await.unwrap());
+    assert!(cache.contains_key("pinned"));
+    assert!(!cache.entry_info("pinned").unwrap().pinned);
+    assert_eq!(cache.len(), 3);
+    assert_eq!(cache.size(), 30);
+    assert_eq!(files_on_disk(&root), 3);
+}
+
+#[tokio::test]
+async fn unpinning_fails_when_no_room_can_be_made() {
+    let root = TempDir::new().unwrap();
+    let cache = cache(&root, 10, 1);
+    cache.insert("pinned", &[0; 10]).await.unwrap();
+    cache.pin("pinned");
+    cache.insert("other", &[0; 10]).await.unwrap();
+    let path = root.path().join("other");
+    std::fs::remove_file(&path).unwrap();
+    std::fs::create_dir(&path).unwrap();
+
+    assert!(cache.unpin("pinned").await.is_err());
+    assert!(cache.entry_info("pinned").unwrap().pinned);
+    assert!(cache.contains_key("other"));
+    assert_eq!(cache.size(), 10);
+}
+
+/// The key evicted to make room for a fourth file once `a`, `b` and `c` were
+/// inserted in that order, `b` read twice, then `a` twice, then `c` once,
+/// and `wait` went by. `a` is the oldest, `b` the least recently used and
+/// `c` the least often used.
+async fn evicted_by(policy: EvictionPolicy, ttl: Option<Duration>, wait: Duration) -> String {
+    let root = TempDir::new().unwrap();
+    let cache = cache(&root, 30, 1)
+        .with_eviction_policy(policy)
+        .with_ttl(ttl);
+    for key in ["a", "b", "c"] {
+        cache.insert(key, &[0; 10]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    for key in ["b", "b", "a", "a", "c"] {
+        cache.get(key).await.unwrap().unwrap();
+    }
+    tokio::time::sleep(wait).await;
+
+    cache.insert("d", &[0; 10]).await.unwrap();
+
+    let evicted = ["a", "b", "c"]
+        .into_iter()
+        .filter(|key| !cache.contains_key(key))
+        .collect::<Vec<_>>();
+    assert_eq!(evicted.len(), 1, "{:?} evicted {:?}", policy, evicted);
+    evicted[0].to_owned()
+}
+
+#[tokio::test]
+async fn each_eviction_policy_evicts_in_its_own_order() {
+    let never = Some(Duration::from_secs(3600));
+    let soon = Some(Duration::from_millis(50));
+    for (policy, ttl, wait, evicted) in [
+        (EvictionPolicy::Lru, None, Duration::ZERO, "b"),
+        (EvictionPolicy::Lfu, None, Duration::ZERO, "c"),
+        (EvictionPolicy::Fifo, None, Duration::ZERO, "a"),
+        // Nothing expired, so least recently used first.
+        (EvictionPolicy::TtlFirst, never, Duration::ZERO, "b"),
+        // Everything expired, so oldest first.
+        (
+            EvictionPolicy::TtlFirst,
+            soon,
+            Duration::from_millis(100),
+            "a",
+        ),
+    ] {
+        assert_eq!(evicted_by(policy, ttl, wait).await, evicted, "{:?}", policy);
+    }
+}
+
+#[test]
+fn a_root_that_cannot_be_created_is_an_error() {
+    let dir = TempDir::new().unwrap();
+    let file = dir.path().join("file");
+    std::fs::write(&file, b"").unwrap();
+
+    let e = DiskCache::new(file.join("cache"), 1, 10, NoNotifier)
+        .err()
+        .expect("a file cannot hold a directory");
+    assert!(
+        e.to_string().starts_with("creating cache directory"),
+        "{:#}",
+        e
+    );
+}
+
+#[tokio::test]
+async fn a_large_eviction_batch_survives_failing_removals() {
+    let root = TempDir::new().unwrap();
+    let cache = cache(&root, 1000, 50);
+    for i in 0..100 {
+        cache.insert(format!("file-{}", i), &[0; 10]).await.unwrap();
+    }
+    // One in five of the files of the batch cannot be removed.
+    let failing = (0..50).step_by(5).map(|i| format!("file-{}", i));
+    for key in failing.clone() {
+        let path = root.path().join(&key);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+    }
+
+    cache.insert("new", &[0; 10]).await.unwrap();
+
+    assert!(failing.clone().all(|key| cache.contains_key(key)));
+    assert!((0..50)
+        .filter(|i| i % 5 != 0)
+        .all(|i| !cache.contains_key(format!("file-{}", i))));
+    assert_eq!(cache.len(), 61);
+    assert_eq!(cache.size(), 610);
+    assert_eq!(files_on_disk(&root), 51);
+}
+
+#[tokio::test]
+async fn a_file_that_could_not_be_evicted_stays_the_first_to_go() {
+    let root = TempDir::new().unwrap();
+    let cache = cache(&root, 30, 2);
+    for key in ["a", "b", "c"] {
+        cache.insert(key, &[0; 10]).await.unwrap();
+    }
+    let path = root.path().join("a");
+    std::fs::remove_file(&path).unwrap();
+    std::fs::create_dir(&path).unwrap();
+
+    // Evicting `a` fails, `b` makes room.
+    cache.insert("d", &[0; 10]).await.unwrap();
+    assert!(cache.contains_key("a"));
+    assert!(!cache.contains_key("b"));
+
+    std::fs::remove_dir(&path).unwrap();
+    std::fs::write(&path, [0; 10]).unwrap();
+    // `a` is still the least recently used, so it goes along with `c`
+    // rather than `d`.
+    cache.insert("e", &[0; 20]).await.unwrap();
+    assert!(!cache.contains_key("a"));
+    assert!(!cache.contains_key("c"));
+    assert!(cache.contains_key("d"));
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn a_reserved_floor_cannot_be_taken_by_other_caches() {
+    let shared = SharedCapacity::new(100);
+    let (critical_root, busy_root) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let critical = cache(&critical_root, 100, 1)
+        .with_shared_capacity(Some(shared.clone()))
+        .with_reserved_capacity(40);
+    let busy = cache(&busy_root, 100, 1).with_shared_capacity(Some(shared.clone()));
+
+    // The busy cache only gets what is not set aside, evicting its own
+    // files once it has taken that.
+    for i in 0..10 {
+        busy.insert(format!("busy-{}", i), &[0; 10]).await.unwrap();
+    }
+    assert_eq!(busy.size(), 60);
+    assert_eq!(files_on_disk(&busy_root), 6);
+
+    // The critical cache still has room for its floor without evicting
+    // anything of the busy one.
+    for i in 0..4 {
+        critical
+            .insert(format!("critical-{}", i), &[0; 10])
+            .await
+            .unwrap();
+    }
+    assert_eq!(critical.size(), 40);
+    assert_eq!(busy.size(), 60);
+    assert_eq!(files_on_disk(&busy_root), 6);
+    assert_eq!(shared.used(), 100);
+
+    // Not even evicting all of its own files makes room in the busy cache
+    // for more than it was left.
+    assert!(busy.insert("too-large", &[0; 70]).await.is_err());
+    assert_eq!(critical.size(), 40);
+}
+
+#[tokio::test]
+async fn a_failed_removal_leaves_the_entry_in_place() {
+    let root = TempDir::new().unwrap();
+    let cache = cache(&root, 30, 1);
+    for key in ["a", "b", "c"] {
+        cache.insert(key, &[0; 10]).await.unwrap();
+    }
+    let path = root.path().join("a");
+    std::fs::remove_file(&path).unwrap();
+    std::fs::create_dir(&path).unwrap();
+
+    assert!(cache.remove("a").await.is_err());
+    assert!(cache.contains_key("a"));
+    assert_eq!(cache.size(), 30);
+
+    std::fs::remove_dir(&path).unwrap();
+    std::fs::write(&path, [0; 10]).unwrap();
+    // `a` is still the least recently used.
+    cache.insert("d", &[0; 10]).await.unwrap();
+    assert!(!cache.contains_key("a"));
+    assert!(cache.contains_key("b"));
+    assert!(!path.exists());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_inserts_keep_to_the_capacity_when_removals_fail() {
+    let root = TempDir::new().unwrap();
+    let cache = Arc::new(cache(&root, 100, 5));
+    for i in 0..10 {
+        cache.insert(format!("old-{}", i), &[0; 10]).await.unwrap();
+    }
+    for i in [0, 3, 6] {
+        let path = root.path().join(format!("old-{}", i));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+    }
+
+    let inserts = (0..64).map(|i| {
+        let cache = cache.clone();
+        tokio::spawn(async move { cache.insert(format!("new-{}", i), &[0; 10]).await })
+    });
+    for inserted in futures::future::join_all(inserts).await {
+        inserted.unwrap().unwrap();
+    }
+
+    assert!(cache.size() <= 100);
+    assert_eq!(cache.size(), cache.len() as u64 * 10);
+    // The entries that could not be evicted are still there, and no file is
+    // left on disk that the cache lost track of.
+    assert!([0, 3, 6]
+        .iter()
+        .all(|i| cache.contains_key(format!("old-{}", i))));
+    assert_eq!(files_on_disk(&root), cache.len() - 3);
+}
+
+#[tokio::test]
+async fn listed_entries_read_as_their_current_content() {
+    let root = TempDir::new().unwrap();
+    let cache = cache(&root, 100, 1).with_verify_checksums(true);
+    for key in ["a", "b", "c"] {
+        cache.insert(key, key.as_bytes()).await.unwrap();
+    }
+    let entries = cache.entries();
+    assert_eq!(
+        entries
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>(),
+        ["a", "b", "c"]
+    );
+
+    cache.replace("a", b"new").await.unwrap();
+    cache.remove("b").await.unwrap();
+
+    let mut read = Vec::new();
+    for (key, info) in entries {
+        if let Some(mut file) = cache.peek_listed(&key, info).await.unwrap() {
+            let mut content = String::new();
+            file.read_to_string(&mut content).await.unwrap();
+            read.push(content);
+        }
+    }
+    assert_eq!(read, ["new", "c"]);
+    // Replaced rather than corrupt, so it is still cached.
+    assert!(cache.contains_key("a"));
+}
@@ -0,0 +1,195 @@
+//! Nodes talking to each other over QUIC on the loopback interface.
+
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use disca::{FileProvider, FileSharingP2P, HashAlgorithm, MemoryFileProvider, NetworkConfig};
+use libp2p::multiaddr::Protocol;
+
+fn config() -> NetworkConfig {
+    NetworkConfig {
+        listen_addrs: vec!["/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap()],
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn listening_on_port_0_reports_the_bound_port() {
+    let node = FileSharingP2P::with_config(config(), MemoryFileProvider::new())
+        .await
+        .unwrap();
+
+    let port = node.addr().iter().find_map(|protocol| match protocol {
+        Protocol::Udp(port) => Some(port),
+        _ => None,
+    });
+    assert!(port.is_some_and(|port| port != 0), "{}", node.addr());
+    assert!(node.listen_addrs().contains(node.addr()));
+}
+
+/// Serves one file, only in ranges starting before `serves_until`, and keeps
+/// track of where the ranges it was asked for start.
+#[derive(Clone)]
+struct RangeProvider {
+    content: Arc<Vec<u8>>,
+    serves_until: u64,
+    asked_from: Arc<Mutex<Vec<u64>>>,
+}
+
+impl RangeProvider {
+    fn new(content: Arc<Vec<u8>>, serves_until: u64) -> Self {
+        Self {
+            content,
+            serves_until,
+            asked_from: Arc::default(),
+        }
+    }
+}
+
+impl FileProvider for RangeProvider {
+    fn get_file(&mut self, _: String) -> Option<Vec<u8>> {
+        Some(self.content.to_vec())
+    }
+
+    fn get_file_range(&mut self, _: String, range: Range<u64>) -> Option<Vec<u8>> {
+        self.asked_from.lock().unwrap().push(range.start);
+        if range.start >= self.serves_until {
+            return None;
+        }
+        let end = range.end.min(self.content.len() as u64);
+        Some(self.content[range.start as usize..end as usize].to_vec())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_ranged_fetch_resumes_from_the_next_provider() {
+    // Too large for one response, so it comes in ranges of 2 MiB.
+    let content = Arc::new(
+        (0..11 * 1024 * 1024)
+            .map(|i: u32| (i % 251) as u8)
+            .collect::<Vec<_>>(),
+    );
+    let key = HashAlgorithm::Blake3.content_key(&HashAlgorithm::Blake3.hash(&content));
+    let first = RangeProvider::new(content.clone(), 4 * 1024 * 1024);
+    let second = RangeProvider::new(content.clone(), u64::MAX);
+    let first_node = FileSharingP2P::with_config(config(), first.clone())
+        .await
+        .unwrap();
+    // A parent is only asked once the provider found on the DHT gave up.
+    let second_node = FileSharingP2P::with_config(config(), second.clone())
+        .await
+        .unwrap();
+    let parent = second_node
+        .addr()
+        .clone()
+        .with(Protocol::P2p(*second_node.peer_id()));
+    let mut node = FileSharingP2P::with_config(
+        NetworkConfig {
+            parent_peers: vec![parent],
+            ..config()
+        },
+        MemoryFileProvider::new(),
+    )
+    .await
+    .unwrap();
+    node.add_peer(first_node.addr().clone()).await.unwrap();
+    node.wait_ready(1, Duration::from_secs(30)).await.unwrap();
+    first_node.add_file(key.clone()).await.unwrap();
+
+    let fetched = node.get_file(key).await.unwrap();
+
+    assert!(fetched.is_some_and(|fetched| fetched == *content));
+    assert_eq!(
+        *first.asked_from.lock().unwrap(),
+        [0, 2 * 1024 * 1024, 4 * 1024 * 1024]
+    );
+    assert_eq!(
+        *second.asked_from.lock().unwrap(),
+        [
+            4 * 1024 * 1024,
+            6 * 1024 * 1024,
+            8 * 1024 * 1024,
+            10 * 1024 * 1024
+        ]
+    );
+}
+
+/// Serves every key as `content`, after `delay`.
+#[derive(Clone)]
+struct DelayedProvider {
+    content: &'static [u8],
+    delay: Duration,
+}
+
+impl FileProvider for DelayedProvider {
+    fn get_file(&mut self, _: String) -> Option<Vec<u8>> {
+        std::thread::sleep(self.delay);
+        Some(self.content.to_vec())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_fastest_of_the_hedged_providers_wins() {
+    let slow = DelayedProvider {
+        content: b"slow",
+        delay: Duration::from_secs(5),
+    };
+    let fast = DelayedProvider {
+        content: b"fast",
+        delay: Duration::ZERO,
+    };
+    let slow_node = FileSharingP2P::with_config(config(), slow).await.unwrap();
+    let fast_node = FileSharingP2P::with_config(config(), fast).await.unwrap();
+    let mut node = FileSharingP2P::with_config(
+        NetworkConfig {
+            hedge_count: 2,
+            ..config()
+        },
+        MemoryFileProvider::new(),
+    )
+    .await
+    .unwrap();
+    node.add_peer(slow_node.addr().clone()).await.unwrap();
+    node.add_peer(fast_node.addr().clone()).await.unwrap();
+    node.wait_ready(2, Duration::from_secs(30)).await.unwrap();
+    slow_node.add_file("key").await.unwrap();
+    fast_node.add_file("key").await.unwrap();
+
+    let started = Instant::now();
+    let fetched = node.get_file("key").await.unwrap();
+
+    assert_eq!(fetched.as_deref(), Some(&b"fast"[..]));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_file_just_over_the_response_limit_is_reported_too_large() {
+    // Responses carry at most 10 MiB, including their encoding.
+    const LIMIT: usize = 10 * 1024 * 1024;
+    let files = MemoryFileProvider::new();
+    files.insert("over", vec![1; LIMIT]);
+    let provider = FileSharingP2P::with_config(config(), files).await.unwrap();
+    let mut node = FileSharingP2P::with_config(
+        NetworkConfig {
+            fetch_in_ranges: false,
+            ..config()
+        },
+        MemoryFileProvider::new(),
+    )
+    .await
+    .unwrap();
+    node.add_peer(provider.addr().clone()).await.unwrap();
+    node.wait_ready(1, Duration::from_secs(30)).await.unwrap();
+    provider.add_file("over").await.unwrap();
+
+    // Rather than a transport failure, or the request timing out.
+    let e = node.get_file("over").await.unwrap_err();
+    assert!(
+        e.to_string().contains("does not fit in a response"),
+        "{:#}",
+        e
+    );
+}